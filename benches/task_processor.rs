@@ -47,7 +47,7 @@ pub async fn run_benchmark_suite(
             tx.send((duration, None::<anyhow::Error>)).await.unwrap();
         });
 
-        let queue_len = pool.task_store.queue_size("default").await?;
+        let queue_len = pool.task_store.queue_size("default").await? as usize;
         max_queue_len = max_queue_len.max(queue_len);
 
         let current_memory = get_memory_usage()?;
@@ -123,6 +123,7 @@ async fn main() -> anyhow::Result<()> {
 	const STORAGE: &str = "/mnt/storage/users/dev/databases/backie";
 	let database_url = format!("sqlite://{}/backie_tasks.db", STORAGE);
 	let task_store = SqliteTaskStore::create(database_url.as_str()).await?;
+	task_store.migrate().await?;
 	let pool = task_store.pool.clone();
 
 	log::info!("Pool created ...");
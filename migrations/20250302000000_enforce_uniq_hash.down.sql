@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS idx_backie_tasks_uniq_hash;
+
+ALTER TABLE backie_tasks DROP COLUMN uniq_scope;
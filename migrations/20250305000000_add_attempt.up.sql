@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE backie_tasks ADD COLUMN attempt INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Finished tasks are pruned oldest first, see `RetentionMode::KeepFor`
+CREATE INDEX idx_backie_tasks_queue_done ON backie_tasks (queue_name, done_at) WHERE done_at IS NOT NULL;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS backie_dead_tasks;
+
+ALTER TABLE backie_tasks DROP COLUMN error_history;
@@ -0,0 +1,26 @@
+-- Add up migration script here
+-- Errors of every failed attempt, see `Task::error_history`
+ALTER TABLE backie_tasks ADD COLUMN error_history TEXT NOT NULL DEFAULT '[]';
+
+-- Tasks that exhausted their retries, see `TaskStore::dead_letter_task`
+CREATE TABLE backie_dead_tasks (
+  id TEXT PRIMARY KEY NOT NULL,
+  task_name TEXT NOT NULL,
+  queue_name TEXT NOT NULL,
+  uniq_hash TEXT,
+  uniq_scope TEXT NOT NULL,
+  payload TEXT NOT NULL,
+  timeout_msecs INTEGER NOT NULL,
+  created_at INTEGER NOT NULL,
+  max_retries INTEGER NOT NULL,
+  backoff_mode TEXT NOT NULL,
+  retries INTEGER NOT NULL,
+  attempt INTEGER NOT NULL,
+  tags TEXT NOT NULL,
+  error_history TEXT NOT NULL,
+  dead_at INTEGER NOT NULL
+);
+
+CREATE INDEX idx_backie_dead_tasks_created ON backie_dead_tasks (created_at, id);
+
+CREATE INDEX idx_backie_dead_tasks_queue_dead ON backie_dead_tasks (queue_name, dead_at);
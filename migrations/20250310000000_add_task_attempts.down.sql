@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS backie_dead_tasks_remove_attempts;
+
+DROP TRIGGER IF EXISTS backie_tasks_remove_attempts;
+
+DROP TABLE IF EXISTS backie_task_attempts;
@@ -0,0 +1,27 @@
+-- Add up migration script here
+-- Executions of the tasks by the workers, see `TaskStore::task_attempts`
+CREATE TABLE backie_task_attempts (
+  task_id TEXT NOT NULL,
+  attempt INTEGER NOT NULL,
+  worker_id TEXT NOT NULL,
+  started_at INTEGER NOT NULL,
+  finished_at INTEGER NOT NULL,
+  outcome TEXT NOT NULL,
+  error TEXT,
+  PRIMARY KEY (task_id, attempt)
+);
+
+-- Attempts are removed along with their task, unless it only moved to or from the dead-letter queue
+CREATE TRIGGER backie_tasks_remove_attempts AFTER DELETE ON backie_tasks
+WHEN NOT EXISTS (SELECT 1 FROM backie_tasks WHERE id = OLD.id)
+AND NOT EXISTS (SELECT 1 FROM backie_dead_tasks WHERE id = OLD.id)
+BEGIN
+  DELETE FROM backie_task_attempts WHERE task_id = OLD.id;
+END;
+
+CREATE TRIGGER backie_dead_tasks_remove_attempts AFTER DELETE ON backie_dead_tasks
+WHEN NOT EXISTS (SELECT 1 FROM backie_tasks WHERE id = OLD.id)
+AND NOT EXISTS (SELECT 1 FROM backie_dead_tasks WHERE id = OLD.id)
+BEGIN
+  DELETE FROM backie_task_attempts WHERE task_id = OLD.id;
+END;
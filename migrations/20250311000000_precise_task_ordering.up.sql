@@ -0,0 +1,17 @@
+-- Add up migration script here
+-- Timestamps are kept in milliseconds since the epoch instead of seconds
+UPDATE backie_tasks SET
+  created_at = created_at * 1000,
+  scheduled_at = scheduled_at * 1000,
+  running_at = running_at * 1000,
+  lease_expires_at = lease_expires_at * 1000,
+  done_at = done_at * 1000,
+  cancelled_at = cancelled_at * 1000;
+
+UPDATE backie_dead_tasks SET
+  created_at = created_at * 1000,
+  dead_at = dead_at * 1000;
+
+UPDATE backie_task_attempts SET
+  started_at = started_at * 1000,
+  finished_at = finished_at * 1000;
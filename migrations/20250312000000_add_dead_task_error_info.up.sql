@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Error the dead tasks failed with for the last time, see `DeadTask::error_info`
+ALTER TABLE backie_dead_tasks ADD COLUMN error_info TEXT;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS idx_backie_tasks_enqueue_seq;
+
+ALTER TABLE backie_tasks DROP COLUMN enqueue_seq;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- Tasks created in the same millisecond are claimed in the order they were enqueued in, every task
+-- is numbered after the tasks already in the table, see `Task::enqueue_seq`
+ALTER TABLE backie_tasks ADD COLUMN enqueue_seq INTEGER NOT NULL DEFAULT 0;
+
+UPDATE backie_tasks SET enqueue_seq = rowid;
+
+CREATE INDEX idx_backie_tasks_enqueue_seq ON backie_tasks (enqueue_seq);
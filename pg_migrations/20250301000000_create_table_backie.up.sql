@@ -0,0 +1,25 @@
+-- Add up migration script here
+CREATE TABLE backie_tasks (
+  id UUID PRIMARY KEY NOT NULL,
+  task_name VARCHAR NOT NULL,
+  queue_name VARCHAR NOT NULL,
+  uniq_hash VARCHAR,
+  payload JSONB NOT NULL,
+  timeout_msecs BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  scheduled_at TIMESTAMPTZ NOT NULL,
+  running_at TIMESTAMPTZ,
+  done_at TIMESTAMPTZ,
+  error_info JSONB,
+  retries INTEGER NOT NULL DEFAULT 0,
+  max_retries INTEGER NOT NULL,
+  backoff_mode JSONB NOT NULL
+);
+
+CREATE INDEX idx_backie_tasks_scheduled ON backie_tasks (scheduled_at);
+
+CREATE INDEX idx_backie_tasks_task_name ON backie_tasks (task_name);
+
+CREATE INDEX idx_backie_tasks_queue_name ON backie_tasks (queue_name);
+
+CREATE INDEX idx_backie_tasks_task_queue ON backie_tasks (task_name, queue_name);
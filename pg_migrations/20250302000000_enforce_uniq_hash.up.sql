@@ -0,0 +1,20 @@
+-- Add up migration script here
+ALTER TABLE backie_tasks ADD COLUMN uniq_scope VARCHAR NOT NULL DEFAULT 'PendingOrRunning';
+
+-- Nothing enforced uniqueness before, keep the hash only on the oldest unfinished duplicate
+UPDATE backie_tasks
+SET uniq_hash = NULL
+WHERE uniq_hash IS NOT NULL
+AND done_at IS NULL
+AND EXISTS (
+  SELECT 1 FROM backie_tasks older
+  WHERE older.uniq_hash = backie_tasks.uniq_hash
+  AND older.done_at IS NULL
+  AND older.ctid < backie_tasks.ctid
+);
+
+-- Tasks hold their hash according to their scope, see `UniqueScope`
+CREATE UNIQUE INDEX idx_backie_tasks_uniq_hash ON backie_tasks (uniq_hash)
+WHERE uniq_hash IS NOT NULL
+AND (done_at IS NULL OR uniq_scope = 'Forever')
+AND (running_at IS NULL OR done_at IS NOT NULL OR uniq_scope != 'Pending');
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS idx_backie_tasks_created;
+
+ALTER TABLE backie_tasks DROP COLUMN tags;
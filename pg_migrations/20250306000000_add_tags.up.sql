@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE backie_tasks ADD COLUMN tags JSONB NOT NULL DEFAULT '[]';
+
+-- Tasks are listed by creation time, see `TaskStore::list_tasks`
+CREATE INDEX idx_backie_tasks_created ON backie_tasks (created_at, id);
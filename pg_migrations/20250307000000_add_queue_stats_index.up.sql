@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- Queue statistics aggregate the tasks of a queue by task name, see `TaskStore::queue_stats`
+CREATE INDEX idx_backie_tasks_queue_task ON backie_tasks (queue_name, task_name);
@@ -0,0 +1,26 @@
+-- Add up migration script here
+-- Errors of every failed attempt, see `Task::error_history`
+ALTER TABLE backie_tasks ADD COLUMN error_history JSONB NOT NULL DEFAULT '[]';
+
+-- Tasks that exhausted their retries, see `TaskStore::dead_letter_task`
+CREATE TABLE backie_dead_tasks (
+  id UUID PRIMARY KEY NOT NULL,
+  task_name VARCHAR NOT NULL,
+  queue_name VARCHAR NOT NULL,
+  uniq_hash VARCHAR,
+  uniq_scope VARCHAR NOT NULL,
+  payload JSONB NOT NULL,
+  timeout_msecs BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL,
+  max_retries INTEGER NOT NULL,
+  backoff_mode JSONB NOT NULL,
+  retries INTEGER NOT NULL,
+  attempt INTEGER NOT NULL,
+  tags JSONB NOT NULL,
+  error_history JSONB NOT NULL,
+  dead_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX idx_backie_dead_tasks_created ON backie_dead_tasks (created_at, id);
+
+CREATE INDEX idx_backie_dead_tasks_queue_dead ON backie_dead_tasks (queue_name, dead_at);
@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS backie_dead_tasks_remove_attempts ON backie_dead_tasks;
+
+DROP TRIGGER IF EXISTS backie_tasks_remove_attempts ON backie_tasks;
+
+DROP FUNCTION IF EXISTS backie_remove_task_attempts();
+
+DROP TABLE IF EXISTS backie_task_attempts;
@@ -0,0 +1,29 @@
+-- Add up migration script here
+-- Executions of the tasks by the workers, see `TaskStore::task_attempts`
+CREATE TABLE backie_task_attempts (
+  task_id UUID NOT NULL,
+  attempt INTEGER NOT NULL,
+  worker_id VARCHAR NOT NULL,
+  started_at TIMESTAMPTZ NOT NULL,
+  finished_at TIMESTAMPTZ NOT NULL,
+  outcome VARCHAR NOT NULL,
+  error VARCHAR,
+  PRIMARY KEY (task_id, attempt)
+);
+
+-- Attempts are removed along with their task, unless it only moved to or from the dead-letter queue
+CREATE FUNCTION backie_remove_task_attempts() RETURNS TRIGGER AS $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM backie_tasks WHERE id = OLD.id)
+  AND NOT EXISTS (SELECT 1 FROM backie_dead_tasks WHERE id = OLD.id) THEN
+    DELETE FROM backie_task_attempts WHERE task_id = OLD.id;
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER backie_tasks_remove_attempts AFTER DELETE ON backie_tasks
+FOR EACH ROW EXECUTE FUNCTION backie_remove_task_attempts();
+
+CREATE TRIGGER backie_dead_tasks_remove_attempts AFTER DELETE ON backie_dead_tasks
+FOR EACH ROW EXECUTE FUNCTION backie_remove_task_attempts();
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE backie_tasks DROP COLUMN enqueue_seq;
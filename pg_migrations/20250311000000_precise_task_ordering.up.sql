@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Timestamps already have a microsecond precision, tasks created at the same time are claimed in
+-- the order they were inserted in
+ALTER TABLE backie_tasks ADD COLUMN enqueue_seq BIGSERIAL NOT NULL;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE backie_dead_tasks DROP COLUMN error_info;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS idx_backie_tasks_queue_unsucceeded;
+DROP INDEX IF EXISTS idx_backie_tasks_queue_succeeded;
+CREATE INDEX idx_backie_tasks_queue_done ON backie_tasks (queue_name, done_at) WHERE done_at IS NOT NULL;
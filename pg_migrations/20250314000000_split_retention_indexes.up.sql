@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- Done tasks and failed or cancelled ones are kept for different times, see `RetentionMode::KeepFor`,
+-- so each outcome gets its own index and a batch only reads the tasks it removes
+DROP INDEX IF EXISTS idx_backie_tasks_queue_done;
+CREATE INDEX idx_backie_tasks_queue_succeeded ON backie_tasks (queue_name, done_at)
+    WHERE done_at IS NOT NULL AND error_info IS NULL AND cancelled_at IS NULL;
+CREATE INDEX idx_backie_tasks_queue_unsucceeded ON backie_tasks (queue_name, done_at)
+    WHERE done_at IS NOT NULL AND (error_info IS NOT NULL OR cancelled_at IS NOT NULL);
@@ -0,0 +1,291 @@
+use crate::sqlite_helpers::SqliteValidate;
+use crate::sqlite_task::Task;
+use sqlite_macros::SqliteType;
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Backoff strategy registered under a name, see [`BackoffMode::Custom`].
+pub(crate) type BackoffFn = Arc<dyn Fn(&Task) -> Duration + Send + Sync>;
+
+/// Longest delay of [`BackoffMode::ExponentialBackoff`], reached after 16 retries.
+const MAX_EXPONENTIAL_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// All possible options for backoff between task retries.
+///
+/// The backoff mode is stored along with each task, durations are stored in milliseconds.
+///
+/// # Examples
+///
+/// Wait a second before the first retry, then twice as long before each following one, up to a
+/// minute, at a random time so failing tasks do not all come back at once:
+/// ```
+/// use foo::{BackoffMode, Jitter};
+/// use std::time::Duration;
+///
+/// const BACKOFF_MODE: BackoffMode = BackoffMode::Exponential {
+///     base: Duration::from_secs(1),
+///     multiplier: 2,
+///     max: Duration::from_secs(60),
+///     jitter: Jitter::Full,
+/// };
+/// ```
+#[derive(Clone, Eq, PartialEq, Debug, Hash, serde::Serialize, serde::Deserialize, SqliteType)]
+pub enum BackoffMode {
+	/// No backoff, retry immediately
+	NoBackoff,
+
+	/// Exponential backoff, waits 2, 4, 8... seconds up to a day
+	ExponentialBackoff,
+
+	/// Wait the same delay before every retry
+	Fixed {
+		#[serde(with = "duration_millis")]
+		delay: Duration,
+	},
+
+	/// Wait `initial` before the first retry, then `increment` more before each following one, up
+	/// to `max`
+	Linear {
+		#[serde(with = "duration_millis")]
+		initial: Duration,
+		#[serde(with = "duration_millis")]
+		increment: Duration,
+		#[serde(with = "duration_millis")]
+		max: Duration,
+	},
+
+	/// Wait `base` before the first retry, then `multiplier` times longer before each following
+	/// one, up to `max`
+	Exponential {
+		#[serde(with = "duration_millis")]
+		base: Duration,
+		multiplier: u32,
+		#[serde(with = "duration_millis")]
+		max: Duration,
+		jitter: Jitter,
+	},
+
+	/// Wait the delay computed by the strategy registered under this name with
+	/// [`crate::WorkerPool::register_backoff`]
+	///
+	/// Tasks whose strategy is not registered by the worker pool executing them fall back to
+	/// [`BackoffMode::ExponentialBackoff`].
+	Custom { name: Cow<'static, str> },
+}
+
+/// Randomization of the delays of [`BackoffMode::Exponential`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Jitter {
+	/// Wait exactly the computed delay
+	None,
+
+	/// Wait a random delay between zero and the computed delay
+	Full,
+
+	/// Wait a random delay between `base` and three times the previous delay, up to `max`
+	Decorrelated,
+}
+
+impl fmt::Display for BackoffMode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NoBackoff => write!(f, "NoBackoff"),
+			Self::ExponentialBackoff => write!(f, "ExponentialBackoff"),
+			// Modes with parameters are kept as JSON, the way the Postgres store keeps all of them
+			_ => write!(f, "{}", serde_json::to_string(self).map_err(|_| fmt::Error)?),
+		}
+	}
+}
+
+impl Default for BackoffMode {
+	fn default() -> Self {
+		Self::ExponentialBackoff
+	}
+}
+
+impl BackoffMode {
+	/// Delay before retrying a task that failed after the given number of retries.
+	///
+	/// `previous` is the delay the task waited before its last retry, if it was retried.
+	pub(crate) fn next_attempt(&self, retries: i64, previous: Option<Duration>) -> Duration {
+		let retries = u32::try_from(retries.max(0)).unwrap_or(u32::MAX);
+		match self {
+			Self::NoBackoff => Duration::ZERO,
+			Self::ExponentialBackoff | Self::Custom { .. } => Duration::from_secs(2u64.saturating_pow(retries.saturating_add(1))).min(MAX_EXPONENTIAL_BACKOFF),
+			Self::Fixed { delay } => *delay,
+			Self::Linear { initial, increment, max } => initial.saturating_add(increment.saturating_mul(retries)).min(*max),
+			Self::Exponential { base, multiplier, max, jitter } => match jitter {
+				Jitter::None => base.saturating_mul(multiplier.saturating_pow(retries)).min(*max),
+				Jitter::Full => random_between(Duration::ZERO, base.saturating_mul(multiplier.saturating_pow(retries)).min(*max)),
+				Jitter::Decorrelated => {
+					let previous = previous.unwrap_or(*base).max(*base);
+					random_between(*base, previous.saturating_mul(3)).min(*max)
+				}
+			},
+		}
+	}
+}
+
+/// A random duration between the given bounds, both included, to the millisecond.
+fn random_between(low: Duration, high: Duration) -> Duration {
+	let (low, high) = (millis(low), millis(high));
+	if high <= low {
+		return Duration::from_millis(low);
+	}
+	Duration::from_millis(fastrand::u64(low..=high))
+}
+
+fn millis(duration: Duration) -> u64 {
+	u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+mod duration_millis {
+	use serde::{Deserialize, Deserializer, Serializer};
+	use std::time::Duration;
+
+	pub(super) fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_u64(super::millis(*duration))
+	}
+
+	pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+		u64::deserialize(deserializer).map(Duration::from_millis)
+	}
+}
+
+impl FromStr for BackoffMode {
+	type Err = sqlx::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.starts_with('{') {
+			return serde_json::from_str(s).map_err(|_| sqlx::Error::Protocol("Invalid backoff mode".into()));
+		}
+		match s.to_lowercase().as_str() {
+			"nobackoff" => Ok(Self::NoBackoff),
+			"exponentialbackoff" => Ok(Self::ExponentialBackoff),
+			_ => Err(sqlx::Error::Protocol("Invalid backoff mode".into())),
+		}
+	}
+}
+
+impl From<String> for BackoffMode {
+	fn from(s: String) -> Self {
+		Self::from_str(s.as_str()).unwrap_or(Self::NoBackoff)
+	}
+}
+
+impl SqliteValidate for BackoffMode {
+	type Error = sqlx::Error;
+
+	fn validate(s: &str) -> Result<(), Self::Error> {
+		Self::from_str(s).map(|_| ())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SECOND: Duration = Duration::from_secs(1);
+
+	#[test]
+	fn delays_grow_with_the_retries_up_to_their_max() {
+		let delays = |mode: BackoffMode| (0..5).map(|retries| mode.next_attempt(retries, None).as_secs()).collect::<Vec<_>>();
+
+		assert_eq!(delays(BackoffMode::NoBackoff), [0, 0, 0, 0, 0]);
+		assert_eq!(delays(BackoffMode::ExponentialBackoff), [2, 4, 8, 16, 32]);
+		assert_eq!(delays(BackoffMode::Fixed { delay: 5 * SECOND }), [5, 5, 5, 5, 5]);
+		assert_eq!(
+			delays(BackoffMode::Linear {
+				initial: SECOND,
+				increment: 3 * SECOND,
+				max: 8 * SECOND
+			}),
+			[1, 4, 7, 8, 8]
+		);
+		assert_eq!(
+			delays(BackoffMode::Exponential {
+				base: SECOND,
+				multiplier: 3,
+				max: 60 * SECOND,
+				jitter: Jitter::None
+			}),
+			[1, 3, 9, 27, 60]
+		);
+	}
+
+	#[test]
+	fn huge_retry_counts_are_capped() {
+		let mode = BackoffMode::Exponential {
+			base: SECOND,
+			multiplier: 10,
+			max: 3600 * SECOND,
+			jitter: Jitter::None,
+		};
+		assert_eq!(mode.next_attempt(i64::MAX, None), 3600 * SECOND);
+		assert_eq!(BackoffMode::ExponentialBackoff.next_attempt(15, None), Duration::from_secs(65536));
+		assert_eq!(BackoffMode::ExponentialBackoff.next_attempt(16, None), 24 * 3600 * SECOND);
+		assert_eq!(BackoffMode::ExponentialBackoff.next_attempt(i64::MAX, None), 24 * 3600 * SECOND);
+		assert_eq!(BackoffMode::Custom { name: "unknown".into() }.next_attempt(i64::MAX, None), 24 * 3600 * SECOND);
+	}
+
+	#[test]
+	fn jittered_delays_stay_within_their_bounds() {
+		let full = BackoffMode::Exponential {
+			base: SECOND,
+			multiplier: 2,
+			max: 10 * SECOND,
+			jitter: Jitter::Full,
+		};
+		let decorrelated = BackoffMode::Exponential {
+			base: SECOND,
+			multiplier: 2,
+			max: 10 * SECOND,
+			jitter: Jitter::Decorrelated,
+		};
+
+		for _ in 0..100 {
+			assert!(full.next_attempt(2, None) <= 4 * SECOND);
+			assert!(full.next_attempt(10, None) <= 10 * SECOND);
+
+			let delay = decorrelated.next_attempt(0, None);
+			assert!((SECOND..=3 * SECOND).contains(&delay), "unexpected delay {delay:?}");
+			let delay = decorrelated.next_attempt(1, Some(2 * SECOND));
+			assert!((SECOND..=6 * SECOND).contains(&delay), "unexpected delay {delay:?}");
+			assert!(decorrelated.next_attempt(5, Some(9 * SECOND)) <= 10 * SECOND);
+		}
+	}
+
+	#[test]
+	fn backoff_modes_round_trip_through_their_stored_form() {
+		let modes = [
+			BackoffMode::NoBackoff,
+			BackoffMode::ExponentialBackoff,
+			BackoffMode::Fixed {
+				delay: Duration::from_millis(1500),
+			},
+			BackoffMode::Linear {
+				initial: SECOND,
+				increment: 2 * SECOND,
+				max: 30 * SECOND,
+			},
+			BackoffMode::Exponential {
+				base: SECOND,
+				multiplier: 2,
+				max: 60 * SECOND,
+				jitter: Jitter::Decorrelated,
+			},
+			BackoffMode::Custom { name: "rate_limited".into() },
+		];
+
+		for mode in modes {
+			assert_eq!(mode.to_string().parse::<BackoffMode>().unwrap(), mode);
+			assert_eq!(serde_json::from_value::<BackoffMode>(serde_json::to_value(&mode).unwrap()).unwrap(), mode);
+		}
+		assert_eq!(BackoffMode::Fixed { delay: SECOND }.to_string(), r#"{"Fixed":{"delay":1000}}"#);
+		assert_eq!("exponentialbackoff".parse::<BackoffMode>().unwrap(), BackoffMode::ExponentialBackoff);
+		assert!(r#"{"Fixed":{}}"#.parse::<BackoffMode>().is_err());
+	}
+}
@@ -1,11 +1,55 @@
 use crate::worker::TaskExecError;
 use futures::future::BoxFuture;
 use futures::FutureExt;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Once;
 use std::task::Context;
 use std::task::Poll;
 
+/// Where a panic happened and its backtrace, which are only known to the panic hook, see
+/// [`install_panic_hook`].
+#[derive(Default)]
+struct PanicDetails {
+	location: Option<String>,
+	backtrace: Option<String>,
+}
+
+thread_local! {
+	/// Details of the last panic of the thread, taken once it is caught.
+	static LAST_PANIC: RefCell<Option<PanicDetails>> = RefCell::new(None);
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Record where tasks panic, and their backtrace, along with the errors of their panics.
+///
+/// The panic payload only carries its message, the location and backtrace of a panic are only
+/// known to the panic hook. This chains a hook recording them before the current hook of the
+/// process runs, so it should be called after any custom hook is set. Only the first call installs
+/// it, without it [`crate::TaskError::location`] and [`crate::TaskError::backtrace`] are never
+/// set.
+///
+/// ```
+/// foo::install_panic_hook();
+/// ```
+pub fn install_panic_hook() {
+	PANIC_HOOK.call_once(|| {
+		let previous = std::panic::take_hook();
+		std::panic::set_hook(Box::new(move |info| {
+			let backtrace = Backtrace::capture();
+			let details = PanicDetails {
+				location: info.location().map(ToString::to_string),
+				backtrace: (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string()),
+			};
+			LAST_PANIC.with(|last| *last.borrow_mut() = Some(details));
+			previous(info);
+		}));
+	});
+}
+
 pub struct CatchUnwindFuture<F: Future + Send + 'static> {
 	inner: BoxFuture<'static, F::Output>,
 }
@@ -33,12 +77,20 @@ impl<F: Future + Send + 'static> Future for CatchUnwindFuture<F> {
 fn catch_unwind<F: FnOnce() -> R, R>(f: F) -> Result<R, TaskExecError> {
 	match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
 		Ok(res) => Ok(res),
-		Err(cause) => match cause.downcast_ref::<&'static str>() {
-			Some(message) => Err(TaskExecError::Panicked((*message).to_string())),
-			None => match cause.downcast_ref::<String>() {
-				Some(message) => Err(TaskExecError::Panicked(message.to_string())),
-				None => Err(TaskExecError::Panicked("Sorry, unknown panic message".to_string())),
-			},
-		},
+		Err(cause) => {
+			let message = match cause.downcast_ref::<&'static str>() {
+				Some(message) => (*message).to_string(),
+				None => match cause.downcast_ref::<String>() {
+					Some(message) => message.to_string(),
+					None => "Sorry, unknown panic message".to_string(),
+				},
+			};
+			let details = LAST_PANIC.with(|last| last.borrow_mut().take()).unwrap_or_default();
+			Err(TaskExecError::Panicked {
+				message,
+				location: details.location,
+				backtrace: details.backtrace,
+			})
+		}
 	}
 }
@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Source of the current time of a store and of the workers pulling from it.
+///
+/// The time tells when scheduled tasks and retries are due, when leases and execution timeouts
+/// expire and when finished tasks are old enough to be pruned. How often workers poll the store
+/// and how long a task may run are still measured in real time.
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+	/// The current time.
+	fn now(&self) -> DateTime<Utc>;
+}
+
+/// The clock of the system, the default clock of the stores.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> DateTime<Utc> {
+		Utc::now()
+	}
+}
+
+/// A clock that only moves when told to, so tests can travel in time instead of waiting.
+///
+/// Clones share the same time, a clone can be handed to a store and advanced by the test.
+///
+/// ```
+/// use foo::{Clock, ManualClock};
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::default();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(8));
+/// assert_eq!(clock.now() - start, chrono::Duration::seconds(8));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+	now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+	/// A clock stopped at the given time.
+	#[must_use]
+	pub fn new(now: DateTime<Utc>) -> Self {
+		Self { now: Arc::new(Mutex::new(now)) }
+	}
+
+	/// Move the clock forward by the given duration.
+	pub fn advance(&self, duration: Duration) {
+		let duration = chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::max_value());
+		let mut now = self.lock();
+		*now = now.checked_add_signed(duration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+	}
+
+	/// Set the clock to the given time, which may be in its past.
+	pub fn set(&self, now: DateTime<Utc>) {
+		*self.lock() = now;
+	}
+
+	fn lock(&self) -> MutexGuard<'_, DateTime<Utc>> {
+		// the time is always valid, even if a thread panicked while holding it
+		self.now.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+impl Default for ManualClock {
+	/// A clock stopped at the current time of the system.
+	fn default() -> Self {
+		Self::new(Utc::now())
+	}
+}
+
+impl Clock for ManualClock {
+	fn now(&self) -> DateTime<Utc> {
+		*self.lock()
+	}
+}
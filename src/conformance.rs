@@ -0,0 +1,659 @@
+//! A test suite checking that a [`TaskStore`] honours the contract the [`crate::Worker`] relies on.
+//!
+//! Every check is a function taking a freshly created store along with its clock, and the
+//! [`task_store_conformance_tests!`](crate::task_store_conformance_tests) macro turns all of them
+//! into tests for a store factory:
+//!
+//! ```no_run
+//! use foo::{ManualClock, SqliteTaskStore};
+//!
+//! async fn my_store(clock: ManualClock) -> SqliteTaskStore {
+//!     SqliteTaskStore::in_memory().await.unwrap().with_clock(clock)
+//! }
+//!
+//! foo::task_store_conformance_tests!(my_store);
+//! # fn main() {}
+//! ```
+//!
+//! The factory hands the [`ManualClock`] it is given to the store, the checks advance it instead
+//! of waiting for backoffs, timeouts and leases to be over. Stores are expected to tell the time
+//! with it, to keep timestamps with at least a millisecond precision, and to claim the tasks
+//! created in the same millisecond in the order they were enqueued in.
+use crate::errors::AsyncQueueError;
+use crate::sqlite_helpers::SqliteDateTime;
+use crate::sqlite_task::{AttemptOutcome, CurrentTask, EnqueueOptions, Task, TaskAttempt, TaskError, TaskErrorKind, TaskFilter, TaskHash, TaskId, TaskPage, TaskState};
+use crate::{BackgroundTask, BackoffMode, Clock, Jitter, ManualClock, TaskCounts, TaskStore, UniqueScope};
+use async_trait::async_trait;
+use chrono::SubsecRound;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::time::Duration;
+
+/// A store that can be checked by the conformance suite, its clones sharing the same tasks.
+pub trait ConformanceStore: TaskStore + Clone {}
+
+impl<S: TaskStore + Clone> ConformanceStore for S {}
+
+/// The task enqueued by the checks, all of them in the [`ConformanceTask::QUEUE`] queue.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ConformanceTask {
+	pub number: u16,
+}
+
+#[async_trait]
+impl BackgroundTask for ConformanceTask {
+	const TASK_NAME: &'static str = "conformance_task";
+	const QUEUE: &'static str = "conformance";
+	type AppData = ();
+	type Error = ();
+
+	async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+		Ok(())
+	}
+}
+
+/// Run a check to completion, used by the tests generated by
+/// [`task_store_conformance_tests!`](crate::task_store_conformance_tests).
+///
+/// The runtime has several worker threads, so the tasks spawned by the checks run in parallel.
+pub fn block_on<F: Future>(check: F) -> F::Output {
+	tokio::runtime::Builder::new_multi_thread()
+		.worker_threads(4)
+		.enable_all()
+		.build()
+		.expect("failed to build the runtime of the conformance suite")
+		.block_on(check)
+}
+
+/// Generate a test for every check of the conformance suite.
+///
+/// Takes the path of an async function creating an empty store implementing
+/// [`ConformanceStore`](crate::conformance::ConformanceStore), which tells the time with the
+/// [`ManualClock`](crate::ManualClock) it is given. Every test gets its own store and clock.
+/// Attributes given before the path are applied to every test, e.g. `#[ignore]` for stores
+/// needing an external database.
+#[macro_export]
+macro_rules! task_store_conformance_tests {
+	(@tests [$($attr:tt)*] $factory:path; $($check:ident),* $(,)?) => {
+		$(
+			#[test]
+			$($attr)*
+			fn $check() {
+				let clock = $crate::ManualClock::default();
+				$crate::conformance::block_on(async { $crate::conformance::$check($factory(clock.clone()).await, clock).await });
+			}
+		)*
+	};
+	($(#[$meta:meta])* $factory:path) => {
+		$crate::task_store_conformance_tests!(@tests [$(#[$meta])*] $factory;
+			enqueued_task_is_claimed_once,
+			tasks_are_claimed_in_enqueue_order,
+			claims_are_limited_to_the_queue_and_task_names,
+			scheduled_task_is_claimed_once_due,
+			retried_task_is_claimed_again_after_its_backoff,
+			finished_task_is_never_claimed_again,
+			finished_task_can_be_removed,
+			timed_out_task_is_claimed_again,
+			task_with_expired_lease_is_claimed_again,
+			stale_attempt_cannot_change_the_task,
+			cancel_task_finishes_pending_tasks_and_flags_running_ones,
+			cancelled_task_is_not_retried,
+			uniq_task_is_enqueued_once_until_finished,
+			concurrent_claims_never_share_an_attempt,
+			tasks_can_be_fetched_and_listed,
+			queue_stats_count_tasks_by_state,
+			finished_tasks_are_pruned_once_expired,
+			exhausted_task_is_dead_lettered_and_can_be_requeued,
+			failed_tasks_can_be_retried,
+			task_attempts_are_kept_along_with_the_task,
+			backoff_modes_are_kept_with_their_parameters,
+		);
+	};
+}
+
+fn task_names() -> Vec<String> {
+	vec![ConformanceTask::TASK_NAME.to_string()]
+}
+
+/// Options of a task that is due right away.
+fn due(clock: &ManualClock) -> EnqueueOptions {
+	EnqueueOptions::new().scheduled_at(clock.now() - chrono::Duration::seconds(1))
+}
+
+/// Move the clock just past the given duration, for a backoff, timeout or lease that long to be over.
+fn advance_past(clock: &ManualClock, duration: Duration) {
+	clock.advance(duration + Duration::from_millis(1));
+}
+
+async fn claim<S: ConformanceStore>(store: &S, execution_timeout: Option<Duration>, lease_duration: Option<Duration>) -> Option<Task> {
+	store
+		.pull_next_task(ConformanceTask::QUEUE, execution_timeout, lease_duration, &task_names())
+		.await
+		.expect("failed to pull the next task")
+}
+
+/// Enqueue a task and run it to the given outcome.
+async fn finish<S: ConformanceStore>(store: &S, clock: &ManualClock, number: u16, state: TaskState) -> TaskId {
+	let id = store.enqueue_task(ConformanceTask { number }, due(clock)).await.unwrap();
+	let task = claim(store, None, None).await.unwrap();
+	assert_eq!(task.id, id);
+	store.set_task_state(id, task.attempt, state).await.unwrap();
+	id
+}
+
+fn error(message: &str) -> TaskError {
+	TaskError::new(TaskErrorKind::Execution, message, 0)
+}
+
+fn assert_stale<T: std::fmt::Debug>(result: Result<T, AsyncQueueError>, id: TaskId, attempt: i64) {
+	match result {
+		Err(AsyncQueueError::StaleAttempt(stale_id, stale_attempt)) => assert_eq!((stale_id, stale_attempt), (id, attempt)),
+		other => panic!("expected attempt {attempt} of task {id} to be stale, got {other:?}"),
+	}
+}
+
+pub async fn enqueued_task_is_claimed_once<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+
+	let task = claim(&store, None, None).await.expect("the enqueued task was not claimed");
+	assert_eq!(task.id, id);
+	assert_eq!(task.state(), TaskState::Running);
+	assert_eq!(task.attempt, 1);
+	assert_eq!(task.retries, 0);
+	assert_eq!(task.payload.0, serde_json::json!({"number": 1}));
+
+	assert!(claim(&store, None, None).await.is_none(), "a running task was claimed twice");
+}
+
+pub async fn tasks_are_claimed_in_enqueue_order<S: ConformanceStore>(store: S, clock: ManualClock) {
+	// The clock stands still, so all of them share their creation time
+	let options = due(&clock);
+	let mut enqueued = Vec::new();
+	for number in 0..20 {
+		enqueued.push(store.enqueue_task(ConformanceTask { number }, options.clone()).await.unwrap());
+	}
+
+	let mut claimed = Vec::new();
+	while let Some(task) = claim(&store, None, None).await {
+		claimed.push(task.id);
+	}
+	assert_eq!(claimed, enqueued, "the tasks were not claimed in the order they were enqueued in");
+}
+
+pub async fn claims_are_limited_to_the_queue_and_task_names<S: ConformanceStore>(store: S, clock: ManualClock) {
+	store.enqueue_task(ConformanceTask { number: 1 }, due(&clock).queue("elsewhere")).await.unwrap();
+	let id = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+
+	let other_names = store.pull_next_task(ConformanceTask::QUEUE, None, None, &["other_task".to_string()]).await.unwrap();
+	assert!(other_names.is_none(), "a task was claimed by a worker not running its task type");
+
+	assert_eq!(claim(&store, None, None).await.map(|task| task.id), Some(id));
+	assert!(claim(&store, None, None).await.is_none(), "a task of another queue was claimed");
+}
+
+pub async fn scheduled_task_is_claimed_once_due<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let scheduled_at = clock.now() + chrono::Duration::seconds(1);
+	let id = store
+		.enqueue_task(ConformanceTask { number: 1 }, EnqueueOptions::new().scheduled_at(scheduled_at))
+		.await
+		.unwrap();
+
+	assert!(claim(&store, None, None).await.is_none(), "a task was claimed before its scheduled time");
+
+	advance_past(&clock, Duration::from_secs(1));
+	assert_eq!(claim(&store, None, None).await.map(|task| task.id), Some(id));
+}
+
+pub async fn retried_task_is_claimed_again_after_its_backoff<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+
+	let retried = store.schedule_task_retry(id, task.attempt, Duration::from_secs(1), &error("try again")).await.unwrap();
+	assert_eq!(retried.retries, 1);
+	assert_eq!(retried.state(), TaskState::Ready);
+	assert!(retried.error_info.0.is_some(), "the error of the failed attempt was not kept");
+	assert!(claim(&store, None, None).await.is_none(), "a task was claimed before its backoff was over");
+
+	advance_past(&clock, Duration::from_secs(1));
+	let task = claim(&store, None, None).await.expect("the retried task was not claimed again");
+	assert_eq!((task.id, task.retries, task.attempt), (id, 1, 2));
+
+	// Backoffs beyond the times the store can keep are clamped to the latest one
+	let retried = store.schedule_task_retry(id, task.attempt, Duration::MAX, &error("never again")).await.unwrap();
+	assert!(retried.scheduled_at.0 > clock.now() + chrono::Duration::days(36500), "a huge backoff wrapped around");
+	assert!(claim(&store, None, None).await.is_none(), "a task was claimed before its backoff was over");
+}
+
+pub async fn finished_task_is_never_claimed_again<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let done = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let failed = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+
+	for _ in 0..2 {
+		let task = claim(&store, None, None).await.unwrap();
+		let state = if task.id == done { TaskState::Done } else { TaskState::Failed(error("boom")) };
+		store.set_task_state(task.id, task.attempt, state).await.unwrap();
+	}
+
+	// Neither an execution timeout nor an expired lease resurrects finished tasks
+	assert!(claim(&store, Some(Duration::ZERO), None).await.is_none());
+	assert!(claim(&store, None, Some(Duration::ZERO)).await.is_none());
+
+	assert!(store.cancel_task(done).await.unwrap().is_none(), "a done task could be cancelled");
+	assert!(store.cancel_task(failed).await.unwrap().is_none(), "a failed task could be cancelled");
+}
+
+pub async fn finished_task_can_be_removed<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+
+	assert_eq!(store.remove_task(id, task.attempt).await.unwrap(), 1);
+	assert!(claim(&store, Some(Duration::ZERO), None).await.is_none(), "a removed task was claimed");
+	assert!(store.cancel_task(id).await.unwrap().is_none(), "a removed task could be cancelled");
+	assert_stale(store.remove_task(id, task.attempt).await, id, task.attempt);
+}
+
+pub async fn timed_out_task_is_claimed_again<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let timeout = Some(Duration::from_secs(1));
+
+	let first = claim(&store, timeout, None).await.unwrap();
+	assert!(first.lease_expires_at.0.is_none(), "a lease was taken without a lease duration");
+	assert!(claim(&store, timeout, None).await.is_none(), "a task was claimed again before timing out");
+
+	advance_past(&clock, Duration::from_secs(1));
+	let second = claim(&store, timeout, None).await.expect("the timed out task was not claimed again");
+	assert_eq!((second.id, second.attempt), (id, first.attempt + 1));
+}
+
+pub async fn task_with_expired_lease_is_claimed_again<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let lease = Duration::from_secs(1);
+
+	// The lease takes precedence over the execution timeout
+	let first = claim(&store, Some(Duration::ZERO), Some(lease)).await.unwrap();
+	assert!(first.lease_expires_at.0.is_some(), "no lease was taken");
+	assert!(store.renew_task_lease(id, first.attempt, lease).await.unwrap());
+	assert!(claim(&store, Some(Duration::ZERO), Some(lease)).await.is_none(), "a task was claimed while holding a lease");
+
+	advance_past(&clock, lease);
+	let second = claim(&store, None, Some(lease)).await.expect("the task was not claimed again once its lease expired");
+	assert_eq!((second.id, second.attempt), (id, first.attempt + 1));
+	assert!(!store.renew_task_lease(id, first.attempt, lease).await.unwrap(), "a stale attempt renewed the lease");
+	assert!(store.renew_task_lease(id, second.attempt, lease).await.unwrap());
+}
+
+pub async fn stale_attempt_cannot_change_the_task<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let first = claim(&store, None, Some(Duration::ZERO)).await.unwrap();
+
+	advance_past(&clock, Duration::ZERO);
+	let second = claim(&store, None, Some(Duration::from_secs(60))).await.unwrap();
+	assert_eq!(second.id, id);
+
+	assert_stale(store.set_task_state(id, first.attempt, TaskState::Done).await, id, first.attempt);
+	assert_stale(store.schedule_task_retry(id, first.attempt, Duration::ZERO, &error("late")).await, id, first.attempt);
+	assert_stale(store.remove_task(id, first.attempt).await, id, first.attempt);
+
+	// The current attempt is untouched and can still finish the task
+	assert!(claim(&store, None, None).await.is_none());
+	store.set_task_state(id, second.attempt, TaskState::Done).await.unwrap();
+}
+
+pub async fn cancel_task_finishes_pending_tasks_and_flags_running_ones<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let running = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, running);
+
+	let pending = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+	let cancelled = store.cancel_task(pending).await.unwrap().expect("the pending task was not cancelled");
+	assert_eq!(cancelled.state(), TaskState::Cancelled);
+	assert!(claim(&store, None, None).await.is_none(), "a cancelled task was claimed");
+
+	assert!(!store.is_cancellation_requested(running).await.unwrap());
+	let flagged = store.cancel_task(running).await.unwrap().expect("the running task was not flagged");
+	assert_eq!(flagged.state(), TaskState::Running);
+	assert!(store.is_cancellation_requested(running).await.unwrap());
+
+	store.set_task_state(running, task.attempt, TaskState::Cancelled).await.unwrap();
+	assert!(store.cancel_task(running).await.unwrap().is_none(), "a finished task could be cancelled");
+}
+
+pub async fn cancelled_task_is_not_retried<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	store.cancel_task(id).await.unwrap().expect("the running task was not flagged");
+
+	// The attempt fails after the cancellation was requested
+	let finished = store.schedule_task_retry(id, task.attempt, Duration::ZERO, &error("cancelled")).await.unwrap();
+	assert_eq!(finished.state(), TaskState::Cancelled);
+	assert_eq!(finished.retries, task.retries, "a cancelled task was retried");
+
+	advance_past(&clock, Duration::ZERO);
+	assert!(claim(&store, None, None).await.is_none(), "a cancelled task was claimed again");
+	assert_eq!(store.get_task(id).await.unwrap().unwrap().state(), TaskState::Cancelled);
+}
+
+pub async fn uniq_task_is_enqueued_once_until_finished<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let options = || due(&clock).uniq(TaskHash::new("conformance")).unique_scope(UniqueScope::PendingOrRunning);
+
+	let first = store.enqueue_task(ConformanceTask { number: 1 }, options()).await.unwrap();
+	let duplicate = store.enqueue_task(ConformanceTask { number: 2 }, options()).await.unwrap();
+	assert_eq!(first, duplicate, "a duplicate of a pending unique task was enqueued");
+
+	let task = claim(&store, None, None).await.unwrap();
+	let duplicate = store.enqueue_task(ConformanceTask { number: 3 }, options()).await.unwrap();
+	assert_eq!(first, duplicate, "a duplicate of a running unique task was enqueued");
+
+	store.set_task_state(task.id, task.attempt, TaskState::Done).await.unwrap();
+	let next = store.enqueue_task(ConformanceTask { number: 4 }, options()).await.unwrap();
+	assert_ne!(first, next, "the unique hash was not released once the task finished");
+}
+
+pub async fn concurrent_claims_never_share_an_attempt<S: ConformanceStore>(store: S, clock: ManualClock) {
+	for number in 0..40 {
+		store.enqueue_task(ConformanceTask { number }, due(&clock)).await.unwrap();
+	}
+
+	let workers = (0..4)
+		.map(|_| {
+			let store = store.clone();
+			tokio::spawn(async move {
+				let mut claimed = Vec::new();
+				while let Some(task) = claim(&store, None, None).await {
+					claimed.push(task.id);
+					tokio::task::yield_now().await;
+				}
+				claimed
+			})
+		})
+		.collect::<Vec<_>>();
+
+	let mut claimed = Vec::new();
+	for worker in workers {
+		claimed.extend(worker.await.unwrap());
+	}
+
+	assert_eq!(claimed.len(), 40);
+	assert_eq!(claimed.iter().collect::<BTreeSet<_>>().len(), 40, "a task was claimed by two workers");
+}
+
+pub async fn tasks_can_be_fetched_and_listed<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let later = || EnqueueOptions::new().scheduled_at(clock.now() + chrono::Duration::hours(1));
+	let tagged = store.enqueue_task(ConformanceTask { number: 1 }, later().tag("vip")).await.unwrap();
+	let running = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+	let other = store.enqueue_task(ConformanceTask { number: 3 }, later().tag("other")).await.unwrap();
+	assert_eq!(claim(&store, None, None).await.map(|task| task.id), Some(running));
+
+	let task = store.get_task(tagged).await.unwrap().expect("the enqueued task was not found");
+	assert_eq!(task.state(), TaskState::Ready);
+	assert!(task.tags.contains("vip"));
+	let (task, payload) = store.get::<ConformanceTask>(tagged).await.unwrap().unwrap();
+	assert_eq!((task.id, payload.number), (tagged, 1));
+	assert!(store.get_task(TaskId::from(uuid::Uuid::new_v4())).await.unwrap().is_none());
+
+	let filter = TaskFilter::new().queue(ConformanceTask::QUEUE);
+	let ids = |page: &TaskPage| page.tasks.iter().map(|task| task.id).collect::<BTreeSet<_>>();
+
+	let first_page = store.list_tasks(&filter.clone().limit(2)).await.unwrap();
+	assert_eq!(first_page.tasks.len(), 2);
+	let cursor = first_page.next_cursor.expect("the first page has no cursor to the next one");
+	let last_page = store.list_tasks(&filter.clone().limit(2).after(cursor)).await.unwrap();
+	assert_eq!(last_page.tasks.len(), 1);
+	assert!(last_page.next_cursor.is_none());
+	let listed = ids(&first_page).union(&ids(&last_page)).copied().collect::<BTreeSet<_>>();
+	assert_eq!(listed, BTreeSet::from([tagged, running, other]), "pages overlapped or missed tasks");
+
+	let page = store.list_tasks(&filter.clone().tag("vip")).await.unwrap();
+	assert_eq!(ids(&page), BTreeSet::from([tagged]));
+	let page = store.list_tasks(&filter.clone().state(TaskState::Running)).await.unwrap();
+	assert_eq!(ids(&page), BTreeSet::from([running]));
+	let page = store.list_tasks(&filter.clone().task_name("other_task")).await.unwrap();
+	assert!(page.tasks.is_empty());
+	let page = store.list_tasks(&filter.created_after(clock.now() + chrono::Duration::minutes(1))).await.unwrap();
+	assert!(page.tasks.is_empty(), "the time range applies to the creation time, not the scheduled time");
+}
+
+pub async fn queue_stats_count_tasks_by_state<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let done = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, done);
+	store.set_task_state(done, task.attempt, TaskState::Done).await.unwrap();
+
+	let running = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+	assert_eq!(claim(&store, None, None).await.map(|task| task.id), Some(running));
+
+	store.enqueue_task(ConformanceTask { number: 3 }, due(&clock)).await.unwrap();
+	store
+		.enqueue_task(ConformanceTask { number: 4 }, EnqueueOptions::new().scheduled_at(clock.now() + chrono::Duration::hours(1)))
+		.await
+		.unwrap();
+
+	let stats = store.queue_stats(ConformanceTask::QUEUE).await.unwrap();
+	let counts = TaskCounts {
+		ready: 1,
+		scheduled: 1,
+		running: 1,
+		done: 1,
+		..TaskCounts::default()
+	};
+	assert_eq!(stats.total.counts, counts);
+	assert_eq!(stats.by_task_name.keys().collect::<Vec<_>>(), vec![ConformanceTask::TASK_NAME]);
+	assert_eq!(stats.by_task_name[ConformanceTask::TASK_NAME], stats.total);
+	assert!(stats.total.oldest_pending_age.is_some(), "the ready task has no pending age");
+	assert!(stats.total.average_wait.is_some(), "the started tasks have no average wait");
+	assert_eq!(store.queue_size(ConformanceTask::QUEUE).await.unwrap(), 3);
+
+	let empty = store.queue_stats("empty").await.unwrap();
+	assert_eq!(empty.total.counts.total(), 0);
+	assert!(empty.by_task_name.is_empty());
+	assert!(empty.total.oldest_pending_age.is_none());
+}
+
+pub async fn finished_tasks_are_pruned_once_expired<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let first_done = finish(&store, &clock, 1, TaskState::Done).await;
+	let second_done = finish(&store, &clock, 2, TaskState::Done).await;
+	let failed = finish(&store, &clock, 3, TaskState::Failed(error("boom"))).await;
+	let cancelled = store.enqueue_task(ConformanceTask { number: 4 }, due(&clock)).await.unwrap();
+	store.cancel_task(cancelled).await.unwrap().unwrap();
+	let running = store.enqueue_task(ConformanceTask { number: 5 }, due(&clock)).await.unwrap();
+	assert_eq!(claim(&store, None, None).await.map(|task| task.id), Some(running));
+
+	let (past, future) = (clock.now() - chrono::Duration::hours(1), clock.now() + chrono::Duration::hours(1));
+
+	// Done tasks expired, failed ones did not
+	assert_eq!(store.prune_finished_tasks(ConformanceTask::QUEUE, future, past, 1).await.unwrap(), 1);
+	assert_eq!(store.prune_finished_tasks(ConformanceTask::QUEUE, future, past, 10).await.unwrap(), 1);
+	for id in [first_done, second_done] {
+		assert!(store.get_task(id).await.unwrap().is_none(), "an expired done task was kept");
+	}
+	assert!(store.get_task(failed).await.unwrap().is_some(), "a failed task was removed before it expired");
+
+	assert_eq!(store.prune_finished_tasks("elsewhere", future, future, 10).await.unwrap(), 0);
+	assert_eq!(store.prune_finished_tasks(ConformanceTask::QUEUE, future, future, 10).await.unwrap(), 2);
+	for id in [failed, cancelled] {
+		assert!(store.get_task(id).await.unwrap().is_none(), "an expired failed or cancelled task was kept");
+	}
+	assert!(store.get_task(running).await.unwrap().is_some(), "an unfinished task was pruned");
+}
+
+pub async fn exhausted_task_is_dead_lettered_and_can_be_requeued<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock).tag("vip")).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	store.schedule_task_retry(id, task.attempt, Duration::ZERO, &error("first")).await.unwrap();
+	advance_past(&clock, Duration::ZERO);
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.error_history.0.len(), 1, "the error of the retried attempt was not kept");
+
+	let dead = store.dead_letter_task(id, task.attempt, &error("second")).await.unwrap();
+	assert_eq!((dead.id, dead.retries, dead.attempt), (id, 1, task.attempt));
+	let errors = dead.error_history.0.iter().map(|failed| (failed.attempt, failed.error.as_str())).collect::<Vec<_>>();
+	assert_eq!(errors, vec![(task.attempt - 1, "first"), (task.attempt, "second")]);
+	assert_eq!(
+		dead.error().map(|error| error.message),
+		Some("second".to_string()),
+		"the error of the dead task was not kept"
+	);
+	assert!(store.get_task(id).await.unwrap().is_none(), "a dead task was kept in its queue");
+	assert_eq!(store.get_dead_task(id).await.unwrap(), Some(dead.clone()));
+	assert!(claim(&store, Some(Duration::ZERO), None).await.is_none(), "a dead task was claimed");
+	assert_stale(store.dead_letter_task(id, task.attempt, &error("again")).await, id, task.attempt);
+
+	let page = store.list_dead_tasks(&TaskFilter::new().queue(ConformanceTask::QUEUE).tag("vip")).await.unwrap();
+	assert_eq!(page.tasks, vec![dead.clone()]);
+	assert!(page.next_cursor.is_none());
+	assert!(store.list_dead_tasks(&TaskFilter::new().queue("elsewhere")).await.unwrap().tasks.is_empty());
+
+	let requeued = store.requeue_dead_task(id).await.unwrap().unwrap();
+	assert_eq!((requeued.id, requeued.retries, requeued.state()), (id, 0, TaskState::Ready));
+	assert_eq!(requeued.error_history, dead.error_history);
+	assert!(store.get_dead_task(id).await.unwrap().is_none());
+	assert!(store.requeue_dead_task(id).await.unwrap().is_none(), "a dead task was requeued twice");
+
+	advance_past(&clock, Duration::ZERO);
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, id);
+	assert!(task.attempt > dead.attempt, "the requeued task reused the attempts of the dead one");
+	let dead = store.dead_letter_task(id, task.attempt, &error("third")).await.unwrap();
+	assert_eq!(dead.error_history.0.len(), 3);
+
+	let (past, future) = (clock.now() - chrono::Duration::hours(1), clock.now() + chrono::Duration::hours(1));
+	assert_eq!(store.purge_dead_tasks(ConformanceTask::QUEUE, past).await.unwrap(), 0);
+	assert!(store.purge_dead_task(id).await.unwrap());
+	assert!(!store.purge_dead_task(id).await.unwrap());
+
+	let other = store.enqueue_task(ConformanceTask { number: 2 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, other);
+	store.dead_letter_task(other, task.attempt, &error("boom")).await.unwrap();
+	assert_eq!(store.purge_dead_tasks("elsewhere", future).await.unwrap(), 0);
+	assert_eq!(store.purge_dead_tasks(ConformanceTask::QUEUE, future).await.unwrap(), 1);
+	assert!(store.get_dead_task(other).await.unwrap().is_none());
+}
+
+pub async fn failed_tasks_can_be_retried<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let boom = TaskError {
+		location: Some("src/task.rs:1:1".to_string()),
+		..TaskError::new(TaskErrorKind::Panic, "boom", 1)
+	};
+	let first = finish(&store, &clock, 1, TaskState::Failed(boom.clone())).await;
+	assert_eq!(
+		store.get_task(first).await.unwrap().unwrap().state(),
+		TaskState::Failed(TaskError {
+			failed_at: Some(clock.now().trunc_subsecs(3)),
+			..boom
+		}),
+		"the error of the failed task was not kept as is, timed by the clock of the store"
+	);
+	let second = finish(&store, &clock, 2, TaskState::Failed(error("boom"))).await;
+	let done = finish(&store, &clock, 3, TaskState::Done).await;
+	assert!(store.retry(done, true).await.unwrap().is_none(), "a done task was retried");
+
+	let flaky = store.enqueue_task(ConformanceTask { number: 4 }, due(&clock)).await.unwrap();
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, flaky);
+	store.schedule_task_retry(flaky, task.attempt, Duration::ZERO, &error("first")).await.unwrap();
+	advance_past(&clock, Duration::ZERO);
+	let task = claim(&store, None, None).await.unwrap();
+	store.set_task_state(flaky, task.attempt, TaskState::Failed(error("second"))).await.unwrap();
+
+	let retried = store.retry(flaky, false).await.unwrap().unwrap();
+	assert_eq!((retried.state(), retried.retries), (TaskState::Ready, 1));
+	assert!(retried.error_info.0.is_none());
+	assert_eq!(retried.error_history.0.len(), 2, "the errors of the failed attempts were not kept");
+	assert!(store.retry(flaky, false).await.unwrap().is_none(), "a pending task was retried");
+
+	advance_past(&clock, Duration::ZERO);
+	let task = claim(&store, None, None).await.unwrap();
+	assert_eq!(task.id, flaky);
+	store.set_task_state(flaky, task.attempt, TaskState::Failed(error("third"))).await.unwrap();
+	assert_eq!(store.retry(flaky, true).await.unwrap().map(|task| task.retries), Some(0));
+
+	let filter = TaskFilter::new().queue(ConformanceTask::QUEUE);
+	assert_eq!(store.retry_where(&TaskFilter::new().queue("elsewhere"), false).await.unwrap(), 0);
+	assert_eq!(store.retry_where(&filter.clone().limit(1), false).await.unwrap(), 1);
+	assert_eq!(store.retry_where(&filter, false).await.unwrap(), 1);
+	assert_eq!(store.retry_where(&filter, false).await.unwrap(), 0);
+	for id in [first, second] {
+		assert_eq!(store.get_task(id).await.unwrap().unwrap().state(), TaskState::Ready);
+	}
+	assert_eq!(store.get_task(done).await.unwrap().unwrap().state(), TaskState::Done);
+}
+
+pub async fn task_attempts_are_kept_along_with_the_task<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let record = |task: &Task, outcome: AttemptOutcome, error: Option<&str>| TaskAttempt {
+		task_id: task.id,
+		attempt: task.attempt,
+		worker_id: "worker-conformance-0".to_string(),
+		started_at: task.running_at.0.unwrap_or_else(|| SqliteDateTime::at(clock.now())),
+		finished_at: SqliteDateTime::at(clock.now()),
+		outcome,
+		error: error.map(str::to_string),
+	};
+	let summary = |attempts: Vec<TaskAttempt>| {
+		attempts
+			.into_iter()
+			.map(|attempt| (attempt.task_id, attempt.attempt, attempt.worker_id, attempt.outcome, attempt.error))
+			.collect::<Vec<_>>()
+	};
+
+	let id = store.enqueue_task(ConformanceTask { number: 1 }, due(&clock)).await.unwrap();
+	assert!(store.task_attempts(id).await.unwrap().is_empty());
+	let first = claim(&store, None, None).await.unwrap();
+	store.record_task_attempt(&record(&first, AttemptOutcome::Failed, Some("boom"))).await.unwrap();
+	store.schedule_task_retry(id, first.attempt, Duration::ZERO, &error("boom")).await.unwrap();
+	advance_past(&clock, Duration::ZERO);
+	let second = claim(&store, None, None).await.unwrap();
+	store.record_task_attempt(&record(&second, AttemptOutcome::TimedOut, Some("too slow"))).await.unwrap();
+
+	let attempts = store.task_attempts(id).await.unwrap();
+	assert!(attempts.iter().all(|attempt| attempt.started_at <= attempt.finished_at));
+	assert_eq!(
+		summary(attempts),
+		vec![
+			(id, first.attempt, "worker-conformance-0".to_string(), AttemptOutcome::Failed, Some("boom".to_string())),
+			(
+				id,
+				second.attempt,
+				"worker-conformance-0".to_string(),
+				AttemptOutcome::TimedOut,
+				Some("too slow".to_string())
+			),
+		]
+	);
+
+	store.dead_letter_task(id, second.attempt, &error("too slow")).await.unwrap();
+	assert_eq!(store.task_attempts(id).await.unwrap().len(), 2, "the attempts of a dead task were removed");
+	store.requeue_dead_task(id).await.unwrap().unwrap();
+	assert_eq!(store.task_attempts(id).await.unwrap().len(), 2, "the attempts of a requeued task were removed");
+
+	advance_past(&clock, Duration::ZERO);
+	let third = claim(&store, None, None).await.unwrap();
+	store.record_task_attempt(&record(&third, AttemptOutcome::Done, None)).await.unwrap();
+	store.remove_task(id, third.attempt).await.unwrap();
+	assert!(store.task_attempts(id).await.unwrap().is_empty(), "the attempts of a removed task were kept");
+}
+
+pub async fn backoff_modes_are_kept_with_their_parameters<S: ConformanceStore>(store: S, clock: ManualClock) {
+	let modes = [
+		BackoffMode::NoBackoff,
+		BackoffMode::Linear {
+			initial: Duration::from_millis(1500),
+			increment: Duration::from_secs(2),
+			max: Duration::from_secs(30),
+		},
+		BackoffMode::Exponential {
+			base: Duration::from_secs(1),
+			multiplier: 3,
+			max: Duration::from_secs(600),
+			jitter: Jitter::Decorrelated,
+		},
+		BackoffMode::Custom { name: "rate_limited".into() },
+	];
+
+	for (number, mode) in (1..).zip(modes) {
+		let id = store.enqueue_task(ConformanceTask { number }, due(&clock).backoff_mode(mode.clone())).await.unwrap();
+		assert_eq!(store.get_task(id).await.unwrap().unwrap().backoff_mode, mode);
+		let task = claim(&store, None, None).await.unwrap();
+		assert_eq!((task.id, task.backoff_mode), (id, mode));
+	}
+}
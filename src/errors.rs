@@ -2,6 +2,8 @@ use std::error::Error;
 
 use thiserror::Error;
 
+use crate::TaskId;
+
 /// Library errors
 #[derive(Debug, Error)]
 pub enum BackieError {
@@ -29,6 +31,15 @@ pub enum AsyncQueueError {
 	#[error("Task with name {0} is not registered")]
 	TaskNotRegistered(String),
 
+	#[error("Task {0} was claimed again, attempt {1} cannot change it anymore")]
+	StaleAttempt(TaskId, i64),
+
+	#[error("Task {0} could not be enqueued, it kept conflicting with a task holding its unique hash that could not be found")]
+	UniqHashConflict(String),
+
+	#[error("Task {0} is a {1} task, not a {2} task")]
+	TaskTypeMismatch(TaskId, String, &'static str),
+
 	#[error("Task with name {0} is not serializable to JSON")]
 	JsonError(#[from] serde_json::Error),
 
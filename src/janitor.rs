@@ -0,0 +1,88 @@
+use crate::clock::Clock;
+use crate::errors::AsyncQueueError;
+use crate::sqlite_helpers::time_before;
+use crate::store::TaskStore;
+use crate::{QueueConfig, RetentionMode};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch::Receiver;
+
+/// Removes the finished tasks of a queue once their retention period is over, see
+/// [`RetentionMode::KeepFor`].
+pub(crate) struct Janitor<S: TaskStore> {
+	store: S,
+
+	/// Tells when the finished tasks expire, the clock of the store.
+	clock: Arc<dyn Clock>,
+
+	queue_name: String,
+
+	/// How long successfully finished tasks are kept.
+	done_retention: Duration,
+
+	/// How long failed and cancelled tasks are kept.
+	failed_retention: Duration,
+
+	interval: Duration,
+
+	batch_size: u32,
+
+	/// Notification for the janitor to stop.
+	shutdown: Receiver<()>,
+}
+
+impl<S: TaskStore> Janitor<S> {
+	/// Create the janitor of a queue, if its retention mode needs one.
+	pub(crate) fn new(store: S, config: &QueueConfig, shutdown: Receiver<()>) -> Option<Self> {
+		let RetentionMode::KeepFor { done, failed } = config.retention_mode else {
+			return None;
+		};
+
+		Some(Self {
+			clock: store.clock(),
+			store,
+			queue_name: config.name.clone(),
+			done_retention: done,
+			failed_retention: failed,
+			interval: config.janitor_interval,
+			batch_size: config.janitor_batch_size,
+			shutdown,
+		})
+	}
+
+	pub(crate) async fn run(mut self) {
+		loop {
+			match self.prune().await {
+				Ok(0) => {}
+				Ok(pruned) => log::debug!("Pruned {pruned} expired tasks of queue {}", self.queue_name),
+				Err(error) => log::warn!("Failed to prune the expired tasks of queue {}: {error}", self.queue_name),
+			}
+
+			tokio::select! {
+				_ = self.shutdown.changed() => return,
+				() = tokio::time::sleep(self.interval) => {}
+			}
+		}
+	}
+
+	/// Removes the expired tasks a batch at a time, so the store is never held for long and workers
+	/// can keep claiming tasks in between.
+	async fn prune(&self) -> Result<u64, AsyncQueueError> {
+		let now = self.clock.now();
+		let (done_before, failed_before) = (time_before(now, self.done_retention), time_before(now, self.failed_retention));
+
+		let mut pruned = 0;
+		loop {
+			if self.shutdown.has_changed().unwrap_or(true) {
+				return Ok(pruned);
+			}
+
+			let removed = self.store.prune_finished_tasks(&self.queue_name, done_before, failed_before, self.batch_size).await?;
+			pruned += removed;
+			if removed < u64::from(self.batch_size) {
+				return Ok(pruned);
+			}
+			tokio::task::yield_now().await;
+		}
+	}
+}
@@ -20,6 +20,13 @@ pub enum RetentionMode {
 
 	/// Remove only successfully finished tasks
 	RemoveDone,
+
+	/// Keep finished tasks for a while, then remove them.
+	///
+	/// Successfully finished tasks are kept for `done`, failed and cancelled ones for `failed`,
+	/// both counted from the time the task finished. Expired tasks are removed in the background,
+	/// see [`QueueConfig::janitor_interval`].
+	KeepFor { done: Duration, failed: Duration },
 }
 
 impl Default for RetentionMode {
@@ -28,59 +35,57 @@ impl Default for RetentionMode {
 	}
 }
 
-/// All possible options for backoff between task retries.
+/// How long a task's unique hash prevents duplicates from being enqueued.
+///
+/// The default scope is [`UniqueScope::PendingOrRunning`]
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, serde::Serialize, serde::Deserialize, SqliteType)]
-pub enum BackoffMode {
-	/// No backoff, retry immediately
-	NoBackoff,
+pub enum UniqueScope {
+	/// Unique while the task waits to be executed, a duplicate can be enqueued once it is running
+	Pending,
 
-	/// Exponential backoff
-	ExponentialBackoff,
+	/// Unique until the task is finished
+	PendingOrRunning,
+
+	/// Unique for as long as the task is kept in the database
+	Forever,
 }
 
-impl fmt::Display for BackoffMode {
+impl fmt::Display for UniqueScope {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
-			Self::NoBackoff => write!(f, "NoBackoff"),
-			Self::ExponentialBackoff => write!(f, "ExponentialBackoff"),
+			Self::Pending => write!(f, "Pending"),
+			Self::PendingOrRunning => write!(f, "PendingOrRunning"),
+			Self::Forever => write!(f, "Forever"),
 		}
 	}
 }
 
-impl Default for BackoffMode {
+impl Default for UniqueScope {
 	fn default() -> Self {
-		Self::ExponentialBackoff
-	}
-}
-
-impl BackoffMode {
-	const fn next_attempt(&self, attempt: i32) -> Duration {
-		match self {
-			Self::NoBackoff => Duration::from_secs(0),
-			Self::ExponentialBackoff => Duration::from_secs(2u64.saturating_pow(attempt.saturating_add(1) as u32)),
-		}
+		Self::PendingOrRunning
 	}
 }
 
-impl FromStr for BackoffMode {
+impl FromStr for UniqueScope {
 	type Err = sqlx::Error;
 
 	fn from_str(s: &str) -> Result<Self, Self::Err> {
 		match s.to_lowercase().as_str() {
-			"nobackoff" => Ok(Self::NoBackoff),
-			"exponentialbackoff" => Ok(Self::ExponentialBackoff),
-			_ => Err(sqlx::Error::Protocol("Invalid backoff mode".into())),
+			"pending" => Ok(Self::Pending),
+			"pendingorrunning" => Ok(Self::PendingOrRunning),
+			"forever" => Ok(Self::Forever),
+			_ => Err(sqlx::Error::Protocol("Invalid unique scope".into())),
 		}
 	}
 }
 
-impl From<String> for BackoffMode {
+impl From<String> for UniqueScope {
 	fn from(s: String) -> Self {
-		Self::from_str(s.as_str()).unwrap_or(Self::NoBackoff)
+		Self::from_str(s.as_str()).unwrap_or_default()
 	}
 }
 
-impl SqliteValidate for BackoffMode {
+impl SqliteValidate for UniqueScope {
 	type Error = sqlx::Error;
 
 	fn validate(s: &str) -> Result<(), Self::Error> {
@@ -88,23 +93,38 @@ impl SqliteValidate for BackoffMode {
 	}
 }
 
-pub use runnable::BackgroundTask;
-pub use sqlite_task::{CurrentTask, NewTask, Task, TaskHash, TaskId, TaskState};
+pub use backoff::{BackoffMode, Jitter};
+pub use catch_unwind::install_panic_hook;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use runnable::{BackgroundTask, ErrorClass};
+pub use sqlite_task::{
+	AttemptOutcome, CurrentTask, DeadTask, DeadTaskPage, EnqueueOptions, ErrorHistory, FailedAttempt, NewTask, Task, TaskAttempt, TaskCursor, TaskError, TaskErrorKind, TaskFilter,
+	TaskHash, TaskId, TaskPage, TaskState, TaskTags,
+};
+pub use stats::{QueueStats, TaskCounts, TaskStats};
 pub use store::{BackgroundTaskExt, TaskStore};
 pub use worker::Worker;
 pub use worker_pool::{QueueConfig, WorkerPool};
 
-// #[cfg(feature = "async_postgres")]
-// pub use store::PgTaskStore;
+#[cfg(feature = "memory_store")]
+pub use store::MemoryTaskStore;
+#[cfg(feature = "async_postgres")]
+pub use store::PgTaskStore;
 pub use store::SqliteTaskStore;
 
+mod backoff;
 mod catch_unwind;
+mod clock;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
 pub mod errors;
+mod janitor;
 mod queries;
 mod runnable;
 // mod schema;
 mod sqlite_helpers;
 mod sqlite_task;
+mod stats;
 mod store;
 // mod task;
 mod worker;
@@ -1,281 +1,746 @@
 use crate::errors::AsyncQueueError;
-use crate::sqlite_helpers::SqliteDateTime;
-use crate::sqlite_task::{NewTask, Task, TaskId};
-use chrono::Utc;
-use sqlx::SqliteConnection;
+use crate::sqlite_helpers::{clamped_millis, time_after, time_before, SqliteDateTime};
+use crate::sqlite_task::{DeadTask, DeadTaskPage, NewTask, Task, TaskAttempt, TaskError, TaskFilter, TaskHash, TaskId, TaskPage, TaskState};
+use crate::stats::{QueueStats, TaskCounts, TaskNameTally};
+use chrono::{DateTime, Utc};
+use sqlx::{Connection, QueryBuilder, Sqlite, SqliteConnection};
 use std::time::Duration;
 
+#[cfg(feature = "async_postgres")]
+pub(crate) mod pg;
+
+/// How many times enqueueing a unique task is tried when the task holding its hash cannot be
+/// found, because it was finished in the meantime.
+const INSERT_ATTEMPTS: u32 = 3;
+
+fn lease_expiration(now: SqliteDateTime, lease_duration: Duration) -> SqliteDateTime {
+	SqliteDateTime(time_after(now.0, lease_duration))
+}
+
+/// The current time according to the database, see [`crate::SqliteTaskStore::database_time`].
+pub(crate) async fn database_now(connection: &mut SqliteConnection) -> Result<SqliteDateTime, AsyncQueueError> {
+	let millis = sqlx::query_scalar!(r#"SELECT CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER) AS "millis!: i64""#)
+		.fetch_one(connection)
+		.await?;
+
+	Ok(SqliteDateTime::from(millis))
+}
+
+/// Condition matching the tasks in the given state, the error of [`TaskState::Failed`] is ignored.
+const fn state_condition(state: &TaskState) -> &'static str {
+	match state {
+		TaskState::Ready => " AND done_at IS NULL AND running_at IS NULL",
+		TaskState::Running => " AND done_at IS NULL AND running_at IS NOT NULL",
+		TaskState::Failed(_) => " AND done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NOT NULL",
+		TaskState::Done => " AND done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NULL",
+		TaskState::Cancelled => " AND done_at IS NOT NULL AND cancelled_at IS NOT NULL",
+	}
+}
+
+/// Pushes the criteria of the filter shared by tasks and dead tasks and its cursor, then orders
+/// the matching rows and keeps the first `limit` of them.
+fn push_filter<'a>(query: &mut QueryBuilder<'a, Sqlite>, filter: &'a TaskFilter, limit: i64) {
+	if let Some(queue) = &filter.queue {
+		query.push(" AND queue_name = ").push_bind(queue);
+	}
+	if let Some(task_name) = &filter.task_name {
+		query.push(" AND task_name = ").push_bind(task_name);
+	}
+	if let Some(created_after) = filter.created_after {
+		query.push(" AND created_at >= ").push_bind(SqliteDateTime(created_after));
+	}
+	if let Some(created_before) = filter.created_before {
+		query.push(" AND created_at < ").push_bind(SqliteDateTime(created_before));
+	}
+	if let Some(tag) = &filter.tag {
+		query.push(" AND EXISTS (SELECT 1 FROM json_each(tags) WHERE value = ").push_bind(tag).push(")");
+	}
+	if let Some(cursor) = filter.cursor {
+		query
+			.push(" AND (created_at > ")
+			.push_bind(SqliteDateTime(cursor.created_at))
+			.push(" OR (created_at = ")
+			.push_bind(SqliteDateTime(cursor.created_at))
+			.push(" AND id > ")
+			.push_bind(cursor.id)
+			.push("))");
+	}
+	query.push(" ORDER BY created_at ASC, id ASC LIMIT ").push_bind(limit);
+}
+
+/// Aggregates of the tasks of a queue with the same name, see [`Task::queue_stats`].
+#[derive(Debug, sqlx::FromRow)]
+struct StatsRow {
+	task_name: String,
+	ready: i64,
+	scheduled: i64,
+	running: i64,
+	failed: i64,
+	done: i64,
+	cancelled: i64,
+	oldest_ready_at: Option<SqliteDateTime>,
+	waited_msecs: i64,
+	started: i64,
+}
+
+impl StatsRow {
+	fn into_tally(self) -> (String, TaskNameTally) {
+		let count = |count: i64| u64::try_from(count).unwrap_or_default();
+		let tally = TaskNameTally {
+			counts: TaskCounts {
+				ready: count(self.ready),
+				scheduled: count(self.scheduled),
+				running: count(self.running),
+				failed: count(self.failed),
+				done: count(self.done),
+				cancelled: count(self.cancelled),
+			},
+			oldest_ready_at: self.oldest_ready_at.map(|oldest_ready_at| oldest_ready_at.0),
+			waited_msecs: self.waited_msecs,
+			started: count(self.started),
+		};
+		(self.task_name, tally)
+	}
+}
+
 impl Task {
 	#[allow(dead_code)]
-	pub(crate) async fn remove(connection: &mut SqliteConnection, id: TaskId) -> Result<u64, AsyncQueueError> {
-		let result = sqlx::query!("DELETE FROM backie_tasks WHERE id = ?", id).execute(connection).await?;
+	pub(crate) async fn remove(connection: &mut SqliteConnection, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError> {
+		let result = sqlx::query!("DELETE FROM backie_tasks WHERE id = ? AND attempt = ?", id, attempt)
+			.execute(connection)
+			.await?;
 
-		Ok(result.rows_affected())
+		match result.rows_affected() {
+			0 => Err(AsyncQueueError::StaleAttempt(id, attempt)),
+			removed => Ok(removed),
+		}
 	}
 
 	#[allow(dead_code)]
-	pub(crate) async fn fail_with_message(connection: &mut SqliteConnection, id: TaskId, error_message: &str) -> Result<Self, AsyncQueueError> {
-		let error = serde_json::json!({
-				"error": error_message,
-		});
-		let now = SqliteDateTime::now().timestamp();
+	pub(crate) async fn fail_with_error(connection: &mut SqliteConnection, id: TaskId, attempt: i64, error: &TaskError, now: SqliteDateTime) -> Result<Self, AsyncQueueError> {
+		let error_info = error.to_error_info(now.0)?;
 
 		let task = sqlx::query_as!(
 			Self,
 			r#"UPDATE backie_tasks 
-            SET error_info = ?, done_at = ?
-            WHERE id = ?
+            SET error_info = ?,
+                error_history = json_insert(error_history, '$[#]', json_object('attempt', attempt, 'error', ?)),
+                done_at = ?
+            WHERE id = ? AND attempt = ?
             RETURNING *"#,
-			error,
+			error_info,
+			error.message,
 			now,
-			id
+			id,
+			attempt
 		)
-		.fetch_one(connection)
+		.fetch_optional(connection)
 		.await?;
 
-		Ok(task)
+		task.ok_or(AsyncQueueError::StaleAttempt(id, attempt))
 	}
 
+	/// Records the failure of an attempt and schedules the task to run again after the backoff.
+	///
+	/// Tasks whose cancellation was requested while they were running are finished as cancelled instead.
 	#[allow(dead_code)]
-	pub(crate) async fn schedule_retry(connection: &mut SqliteConnection, id: TaskId, backoff: Duration, error_message: &str) -> Result<Self, AsyncQueueError> {
-		let error = serde_json::json!({
-				"error": error_message,
-		});
-		let scheduled_at = SqliteDateTime(Utc::now() + chrono::Duration::from_std(backoff).unwrap_or_else(|_| chrono::Duration::max_value()));
+	pub(crate) async fn schedule_retry(
+		connection: &mut SqliteConnection,
+		id: TaskId,
+		attempt: i64,
+		backoff: Duration,
+		error: &TaskError,
+		now: SqliteDateTime,
+	) -> Result<Self, AsyncQueueError> {
+		let error_info = error.to_error_info(now.0)?;
+		let scheduled_at = SqliteDateTime(time_after(now.0, backoff));
 
 		let task = sqlx::query_as!(
 			Self,
 			r#"UPDATE backie_tasks 
             SET error_info = ?,
-                retries = retries + 1,
-                scheduled_at = ?,
-                running_at = NULL
-            WHERE id = ?
+                error_history = json_insert(error_history, '$[#]', json_object('attempt', attempt, 'error', ?)),
+                done_at = CASE WHEN cancelled_at IS NULL THEN done_at ELSE ? END,
+                retries = CASE WHEN cancelled_at IS NULL THEN retries + 1 ELSE retries END,
+                scheduled_at = CASE WHEN cancelled_at IS NULL THEN ? ELSE scheduled_at END,
+                running_at = CASE WHEN cancelled_at IS NULL THEN NULL ELSE running_at END,
+                lease_expires_at = CASE WHEN cancelled_at IS NULL THEN NULL ELSE lease_expires_at END,
+                uniq_hash = CASE
+                    WHEN cancelled_at IS NULL AND uniq_scope = 'Pending' AND EXISTS (
+                        SELECT 1 FROM backie_tasks duplicate
+                        WHERE duplicate.uniq_hash = backie_tasks.uniq_hash
+                        AND duplicate.id != backie_tasks.id
+                        AND duplicate.running_at IS NULL
+                        AND duplicate.done_at IS NULL
+                    ) THEN NULL
+                    ELSE uniq_hash
+                END
+            WHERE id = ? AND attempt = ?
             RETURNING *"#,
-			error,
+			error_info,
+			error.message,
+			now,
 			scheduled_at,
-			id
+			id,
+			attempt
 		)
-		.fetch_one(connection)
+		.fetch_optional(connection)
+		.await?;
+
+		task.ok_or(AsyncQueueError::StaleAttempt(id, attempt))
+	}
+
+	/// Claims the oldest pending task of the queue in a single statement.
+	///
+	/// The lookup and the update run as one write, so two processes sharing the same database file
+	/// can never both mark the same row as running for the same attempt.
+	///
+	/// Tasks created in the same millisecond are claimed in the order they were inserted in, see
+	/// [`Task::enqueue_seq`].
+	///
+	/// Running tasks are claimed again once their lease expired, or when they hold no lease, once
+	/// they have been running for longer than the execution timeout.
+	#[allow(dead_code)]
+	pub(crate) async fn claim_next_pending(
+		connection: &mut SqliteConnection,
+		queue_name: &str,
+		execution_timeout: Option<Duration>,
+		lease_duration: Option<Duration>,
+		task_names: &[String],
+		now: SqliteDateTime,
+	) -> Result<Option<Self>, AsyncQueueError> {
+		let task_names_json = serde_json::to_value(task_names)?;
+		let timeout_threshold = execution_timeout.map(|timeout| SqliteDateTime(time_before(now.0, timeout)));
+		let lease_expires_at = lease_duration.map(|lease_duration| lease_expiration(now, lease_duration));
+
+		let task = sqlx::query_as!(
+			Self,
+			r#"UPDATE backie_tasks
+            SET running_at = ?, lease_expires_at = ?, attempt = attempt + 1
+            WHERE id = (
+                SELECT id FROM backie_tasks
+                WHERE task_name IN (SELECT value FROM json_each(?))
+                AND scheduled_at < ?
+                AND done_at IS NULL
+                AND queue_name = ?
+                AND (running_at IS NULL OR lease_expires_at < ? OR (lease_expires_at IS NULL AND running_at < ?))
+                ORDER BY created_at ASC, enqueue_seq ASC
+                LIMIT 1
+            )
+            AND done_at IS NULL
+            AND (running_at IS NULL OR lease_expires_at < ? OR (lease_expires_at IS NULL AND running_at < ?))
+            RETURNING *"#,
+			now,
+			lease_expires_at,
+			task_names_json,
+			now,
+			queue_name,
+			now,
+			timeout_threshold,
+			now,
+			timeout_threshold
+		)
+		.fetch_optional(connection)
 		.await?;
 
 		Ok(task)
 	}
 
+	/// Extends the lease of a running task, returns `false` if the attempt does not hold the task anymore.
 	#[allow(dead_code)]
-	pub(crate) async fn fetch_next_pending(connection: &mut SqliteConnection, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Option<Self> {
-		let now = SqliteDateTime(Utc::now());
-		let task_names_json = serde_json::to_value(task_names).unwrap();
-
-		match execution_timeout {
-			Some(timeout) => {
-				let timeout_threshold = SqliteDateTime(Utc::now() - chrono::Duration::from_std(timeout).unwrap_or_else(|_| chrono::Duration::max_value()));
-
-				sqlx::query_as!(
-					Self,
-					r#"SELECT * FROM backie_tasks
-                    WHERE task_name IN (SELECT value FROM json_each(?))
-                    AND scheduled_at < ?
-                    AND done_at IS NULL
-                    AND queue_name = ?
-                    AND (running_at IS NULL OR running_at < ?)
-                    ORDER BY created_at ASC
-                    LIMIT 1"#,
-					task_names_json,
-					now,
-					queue_name,
-					timeout_threshold
-				)
-				.fetch_optional(connection)
-				.await
-				.ok()
-				.flatten()
-			}
-			None => sqlx::query_as!(
-				Self,
-				r#"SELECT * FROM backie_tasks
-                    WHERE task_name IN (SELECT value FROM json_each(?))
-                    AND scheduled_at < ?
-                    AND done_at IS NULL
-                    AND queue_name = ?
-                    AND running_at IS NULL
-                    ORDER BY created_at ASC
-                    LIMIT 1"#,
-				task_names_json,
-				now,
-				queue_name
-			)
-			.fetch_optional(connection)
-			.await
-			.ok()
-			.flatten(),
-		}
+	pub(crate) async fn renew_lease(connection: &mut SqliteConnection, id: TaskId, attempt: i64, lease_duration: Duration, now: SqliteDateTime) -> Result<bool, AsyncQueueError> {
+		let lease_expires_at = lease_expiration(now, lease_duration);
+		let result = sqlx::query!(
+			r#"UPDATE backie_tasks
+            SET lease_expires_at = ?
+            WHERE id = ?
+            AND attempt = ?
+            AND running_at IS NOT NULL
+            AND done_at IS NULL"#,
+			lease_expires_at,
+			id,
+			attempt
+		)
+		.execute(connection)
+		.await?;
+
+		Ok(result.rows_affected() > 0)
 	}
 
 	#[allow(dead_code)]
-	pub(crate) async fn set_running(connection: &mut SqliteConnection, task: Self) -> Result<Self, AsyncQueueError> {
-		let now = SqliteDateTime(Utc::now());
+	pub(crate) async fn set_done(connection: &mut SqliteConnection, id: TaskId, attempt: i64, now: SqliteDateTime) -> Result<Self, AsyncQueueError> {
 		let task = sqlx::query_as!(
 			Self,
 			r#"UPDATE backie_tasks 
-            SET running_at = ?
-            WHERE id = ?
+            SET done_at = ?
+            WHERE id = ? AND attempt = ?
             RETURNING *"#,
 			now,
-			task.id
+			id,
+			attempt
 		)
-		.fetch_one(connection)
+		.fetch_optional(connection)
 		.await?;
 
-		Ok(task)
+		task.ok_or(AsyncQueueError::StaleAttempt(id, attempt))
 	}
 
+	/// Requests the cancellation of an unfinished task.
+	///
+	/// Pending tasks are finished right away, running tasks are only flagged so the worker executing
+	/// them can stop them.
 	#[allow(dead_code)]
-	pub(crate) async fn set_done(connection: &mut SqliteConnection, id: TaskId) -> Result<Self, AsyncQueueError> {
-		let now = SqliteDateTime(Utc::now());
+	pub(crate) async fn cancel(connection: &mut SqliteConnection, id: TaskId, now: SqliteDateTime) -> Result<Option<Self>, AsyncQueueError> {
 		let task = sqlx::query_as!(
 			Self,
-			r#"UPDATE backie_tasks 
-            SET done_at = ?
+			r#"UPDATE backie_tasks
+            SET cancelled_at = COALESCE(cancelled_at, ?),
+                done_at = CASE WHEN running_at IS NULL THEN ? ELSE done_at END
             WHERE id = ?
+            AND done_at IS NULL
             RETURNING *"#,
+			now,
 			now,
 			id
 		)
-		.fetch_one(connection)
+		.fetch_optional(connection)
 		.await?;
 
 		Ok(task)
 	}
 
 	#[allow(dead_code)]
-	pub(crate) async fn insert(connection: &mut SqliteConnection, new_task: NewTask) -> Result<Self, AsyncQueueError> {
-		let (task_name, queue_name, uniq_hash, payload, timeout_msecs, max_retries, backoff_mode) = new_task.into_values();
-		let id = TaskId::from(uuid::Uuid::new_v4());
-		let now = SqliteDateTime(Utc::now());
+	pub(crate) async fn is_cancellation_requested(connection: &mut SqliteConnection, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let cancelled = sqlx::query_scalar!(r#"SELECT cancelled_at IS NOT NULL AS "cancelled!: bool" FROM backie_tasks WHERE id = ?"#, id)
+			.fetch_optional(connection)
+			.await?;
+
+		Ok(cancelled.unwrap_or(false))
+	}
 
+	#[allow(dead_code)]
+	pub(crate) async fn set_cancelled(connection: &mut SqliteConnection, id: TaskId, attempt: i64, now: SqliteDateTime) -> Result<Self, AsyncQueueError> {
 		let task = sqlx::query_as!(
 			Self,
-			r#"INSERT INTO backie_tasks (
-                id, task_name, queue_name, uniq_hash, payload, 
-                timeout_msecs, created_at, scheduled_at, 
-                max_retries, backoff_mode, retries
-            )
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 0)
+			r#"UPDATE backie_tasks
+            SET cancelled_at = COALESCE(cancelled_at, ?), done_at = ?
+            WHERE id = ? AND attempt = ?
             RETURNING *"#,
+			now,
+			now,
 			id,
-			task_name,
+			attempt
+		)
+		.fetch_optional(connection)
+		.await?;
+
+		task.ok_or(AsyncQueueError::StaleAttempt(id, attempt))
+	}
+
+	/// Finds the task currently holding the given unique hash, if any.
+	#[allow(dead_code)]
+	pub(crate) async fn find_by_uniq_hash(connection: &mut SqliteConnection, uniq_hash: &TaskHash) -> Result<Option<Self>, AsyncQueueError> {
+		let task = sqlx::query_as!(
+			Self,
+			r#"SELECT * FROM backie_tasks
+            WHERE uniq_hash = ?
+            AND (done_at IS NULL OR uniq_scope = 'Forever')
+            AND (running_at IS NULL OR done_at IS NOT NULL OR uniq_scope != 'Pending')
+            LIMIT 1"#,
+			uniq_hash
+		)
+		.fetch_optional(connection)
+		.await?;
+
+		Ok(task)
+	}
+
+	/// Inserts a new task created at the given time, or at the time of the database when none is
+	/// given, unless another task holds the same unique hash.
+	///
+	/// When the unique index rejects the row, the task holding the hash is returned instead.
+	#[allow(dead_code)]
+	pub(crate) async fn insert(connection: &mut SqliteConnection, new_task: NewTask, now: Option<SqliteDateTime>) -> Result<Self, AsyncQueueError> {
+		let delay_msecs = new_task.scheduled_in.map(clamped_millis);
+		let max_msecs = DateTime::<Utc>::MAX_UTC.timestamp_millis();
+		let (task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, max_retries, backoff_mode, scheduled_at, tags) = new_task.into_values();
+		let scheduled_at = scheduled_at.map(SqliteDateTime);
+
+		for _ in 0..INSERT_ATTEMPTS {
+			let id = TaskId::from(uuid::Uuid::new_v4());
+
+			// The creation time is read once, the delay of the task is counted from it
+			let inserted = sqlx::query_as!(
+				Self,
+				r#"INSERT INTO backie_tasks (
+                    id, task_name, queue_name, uniq_hash, uniq_scope, payload,
+                    timeout_msecs, created_at, scheduled_at,
+                    max_retries, backoff_mode, retries, tags, enqueue_seq
+                )
+                SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, enqueued.created_at,
+                    COALESCE(?9, MIN(enqueued.created_at + ?10, ?11), enqueued.created_at),
+                    ?12, ?13, 0, ?14, (SELECT COALESCE(MAX(enqueue_seq), 0) + 1 FROM backie_tasks)
+                FROM (SELECT COALESCE(?8, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)) AS created_at) AS enqueued
+                WHERE true
+                ON CONFLICT (uniq_hash) WHERE uniq_hash IS NOT NULL
+                    AND (done_at IS NULL OR uniq_scope = 'Forever')
+                    AND (running_at IS NULL OR done_at IS NOT NULL OR uniq_scope != 'Pending')
+                DO NOTHING
+                RETURNING *"#,
+				id,
+				task_name,
+				queue_name,
+				uniq_hash,
+				uniq_scope,
+				payload,
+				timeout_msecs,
+				now,
+				scheduled_at,
+				delay_msecs,
+				max_msecs,
+				max_retries,
+				backoff_mode,
+				tags
+			)
+			.fetch_optional(&mut *connection)
+			.await?;
+
+			if let Some(task) = inserted {
+				return Ok(task);
+			}
+
+			// Only the unique hash index is a conflict target, a task without hash is always inserted
+			let Some(uniq_hash) = &uniq_hash else { break };
+			if let Some(existing) = Self::find_by_uniq_hash(&mut *connection, uniq_hash).await? {
+				log::debug!("Task {} already holds unique hash {}, not enqueueing a duplicate", existing.id, uniq_hash);
+				return Ok(existing);
+			}
+			// The conflicting task was finished in the meantime, try again
+		}
+
+		Err(AsyncQueueError::UniqHashConflict(task_name))
+	}
+
+	#[allow(dead_code)]
+	pub(crate) async fn find(connection: &mut SqliteConnection, id: TaskId) -> Result<Option<Self>, AsyncQueueError> {
+		let task = sqlx::query_as!(Self, "SELECT * FROM backie_tasks WHERE id = ?", id).fetch_optional(connection).await?;
+
+		Ok(task)
+	}
+
+	/// Lists a page of the tasks matching the filter, ordered by creation time then id.
+	#[allow(dead_code)]
+	pub(crate) async fn list(connection: &mut SqliteConnection, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError> {
+		let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM backie_tasks WHERE 1 = 1");
+		if let Some(state) = &filter.state {
+			query.push(state_condition(state));
+		}
+		push_filter(&mut query, filter, i64::from(filter.limit) + 1);
+
+		let tasks = query.build_query_as::<Self>().fetch_all(connection).await?;
+
+		Ok(TaskPage::from_overfetched(tasks, filter.limit))
+	}
+
+	/// Aggregates the tasks of a queue by task name, in a single pass over the queue index.
+	#[allow(dead_code)]
+	pub(crate) async fn queue_stats(connection: &mut SqliteConnection, queue_name: &str, now: SqliteDateTime) -> Result<QueueStats, AsyncQueueError> {
+		let rows = sqlx::query_as::<_, StatsRow>(
+			r#"SELECT task_name,
+                SUM(done_at IS NULL AND running_at IS NULL AND scheduled_at < ?1) AS ready,
+                SUM(done_at IS NULL AND running_at IS NULL AND scheduled_at >= ?1) AS scheduled,
+                SUM(done_at IS NULL AND running_at IS NOT NULL) AS running,
+                SUM(done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NOT NULL) AS failed,
+                SUM(done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NULL) AS done,
+                SUM(done_at IS NOT NULL AND cancelled_at IS NOT NULL) AS cancelled,
+                MIN(CASE WHEN done_at IS NULL AND running_at IS NULL AND scheduled_at < ?1 THEN scheduled_at END) AS oldest_ready_at,
+                COALESCE(SUM(running_at - scheduled_at), 0) AS waited_msecs,
+                COUNT(running_at) AS started
+            FROM backie_tasks
+            WHERE queue_name = ?2
+            GROUP BY task_name"#,
+		)
+		.bind(now)
+		.bind(queue_name)
+		.fetch_all(connection)
+		.await?;
+
+		Ok(QueueStats::new(queue_name, rows.into_iter().map(StatsRow::into_tally), now.0))
+	}
+
+	/// Removes a batch of tasks that finished before the cutoff of their outcome, done ones first and
+	/// each outcome through its own index, so the tasks that are still retained are never read.
+	#[allow(dead_code)]
+	pub(crate) async fn prune_finished(
+		connection: &mut SqliteConnection,
+		queue_name: &str,
+		done_before: DateTime<Utc>,
+		failed_before: DateTime<Utc>,
+		limit: u32,
+	) -> Result<u64, AsyncQueueError> {
+		let done_before = SqliteDateTime(done_before);
+		let failed_before = SqliteDateTime(failed_before);
+		let done = sqlx::query!(
+			r#"DELETE FROM backie_tasks
+            WHERE id IN (
+                SELECT id FROM backie_tasks
+                WHERE queue_name = ?
+                AND done_at IS NOT NULL AND error_info IS NULL AND cancelled_at IS NULL
+                AND done_at < ?
+                ORDER BY done_at ASC
+                LIMIT ?
+            )"#,
+			queue_name,
+			done_before,
+			limit
+		)
+		.execute(&mut *connection)
+		.await?
+		.rows_affected();
+
+		let remaining = u64::from(limit).saturating_sub(done) as i64;
+		if remaining == 0 {
+			return Ok(done);
+		}
+		let failed = sqlx::query!(
+			r#"DELETE FROM backie_tasks
+            WHERE id IN (
+                SELECT id FROM backie_tasks
+                WHERE queue_name = ?
+                AND done_at IS NOT NULL AND (error_info IS NOT NULL OR cancelled_at IS NOT NULL)
+                AND done_at < ?
+                ORDER BY done_at ASC
+                LIMIT ?
+            )"#,
 			queue_name,
-			uniq_hash,
-			payload,
-			timeout_msecs,
+			failed_before,
+			remaining
+		)
+		.execute(connection)
+		.await?
+		.rows_affected();
+
+		Ok(done + failed)
+	}
+
+	/// Puts a failed task back in its queue, to be executed right away.
+	///
+	/// The task keeps its error history and its attempt counter. It keeps its unique hash unless
+	/// another task holds it in the meantime.
+	#[allow(dead_code)]
+	pub(crate) async fn retry(connection: &mut SqliteConnection, id: TaskId, reset_retries: bool, now: SqliteDateTime) -> Result<Option<Self>, AsyncQueueError> {
+		let task = sqlx::query_as!(
+			Self,
+			r#"UPDATE backie_tasks
+            SET done_at = NULL,
+                running_at = NULL,
+                lease_expires_at = NULL,
+                error_info = NULL,
+                scheduled_at = ?,
+                retries = CASE WHEN ? THEN 0 ELSE retries END,
+                uniq_hash = CASE
+                    WHEN EXISTS (
+                        SELECT 1 FROM backie_tasks holder
+                        WHERE holder.uniq_hash = backie_tasks.uniq_hash
+                        AND holder.id != backie_tasks.id
+                        AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                        AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                    ) THEN NULL
+                    ELSE uniq_hash
+                END
+            WHERE id = ?
+            AND done_at IS NOT NULL
+            AND cancelled_at IS NULL
+            AND error_info IS NOT NULL
+            RETURNING *"#,
+			now,
+			reset_retries,
+			id
+		)
+		.fetch_optional(connection)
+		.await?;
+
+		Ok(task)
+	}
+
+	/// Puts the failed tasks matching the filter back in their queue, see [`Task::retry`].
+	#[allow(dead_code)]
+	pub(crate) async fn retry_where(connection: &mut SqliteConnection, filter: &TaskFilter, reset_retries: bool, now: SqliteDateTime) -> Result<u64, AsyncQueueError> {
+		let mut query = QueryBuilder::<Sqlite>::new(
+			r#"UPDATE backie_tasks
+            SET done_at = NULL,
+                running_at = NULL,
+                lease_expires_at = NULL,
+                error_info = NULL,
+                scheduled_at = "#,
+		);
+		query.push_bind(now).push(", retries = CASE WHEN ").push_bind(reset_retries).push(
+			r#" THEN 0 ELSE retries END,
+                uniq_hash = CASE
+                    WHEN EXISTS (
+                        SELECT 1 FROM backie_tasks holder
+                        WHERE holder.uniq_hash = backie_tasks.uniq_hash
+                        AND holder.id != backie_tasks.id
+                        AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                        AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                    ) THEN NULL
+                    ELSE uniq_hash
+                END
+            WHERE id IN (SELECT id FROM backie_tasks WHERE 1 = 1"#,
+		);
+		query.push(state_condition(&TaskState::Failed(TaskError::default())));
+		push_filter(&mut query, filter, i64::from(filter.limit));
+		query.push(")");
+
+		let result = query.build().execute(connection).await?;
+
+		Ok(result.rows_affected())
+	}
+}
+
+impl DeadTask {
+	/// Moves the given attempt of a task to the dead-letter queue, along with the error it failed with.
+	#[allow(dead_code)]
+	pub(crate) async fn dead_letter(connection: &mut SqliteConnection, id: TaskId, attempt: i64, error: &TaskError, now: SqliteDateTime) -> Result<Self, AsyncQueueError> {
+		let error_info = error.to_error_info(now.0)?;
+		let mut transaction = connection.begin().await?;
+
+		let dead_task = sqlx::query_as!(
+			Self,
+			r#"INSERT INTO backie_dead_tasks (
+                id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at,
+                max_retries, backoff_mode, retries, attempt, tags, error_history, dead_at, error_info
+            )
+            SELECT id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at,
+                max_retries, backoff_mode, retries, attempt, tags,
+                json_insert(error_history, '$[#]', json_object('attempt', attempt, 'error', ?)), ?, ?
+            FROM backie_tasks
+            WHERE id = ? AND attempt = ?
+            RETURNING *"#,
+			error.message,
 			now,
+			error_info,
+			id,
+			attempt
+		)
+		.fetch_optional(&mut *transaction)
+		.await?
+		.ok_or(AsyncQueueError::StaleAttempt(id, attempt))?;
+
+		sqlx::query!("DELETE FROM backie_tasks WHERE id = ?", id).execute(&mut *transaction).await?;
+		transaction.commit().await?;
+
+		Ok(dead_task)
+	}
+
+	#[allow(dead_code)]
+	pub(crate) async fn find(connection: &mut SqliteConnection, id: TaskId) -> Result<Option<Self>, AsyncQueueError> {
+		let dead_task = sqlx::query_as!(Self, "SELECT * FROM backie_dead_tasks WHERE id = ?", id).fetch_optional(connection).await?;
+
+		Ok(dead_task)
+	}
+
+	/// Lists a page of the dead tasks matching the filter, ordered by creation time then id.
+	#[allow(dead_code)]
+	pub(crate) async fn list(connection: &mut SqliteConnection, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError> {
+		let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM backie_dead_tasks WHERE 1 = 1");
+		push_filter(&mut query, filter, i64::from(filter.limit) + 1);
+
+		let dead_tasks = query.build_query_as::<Self>().fetch_all(connection).await?;
+
+		Ok(DeadTaskPage::from_overfetched(dead_tasks, filter.limit))
+	}
+
+	/// Puts a dead task back in its queue with its retries reset, to be executed right away.
+	///
+	/// The task keeps its error history and its attempt counter, so workers that lost the task
+	/// before it was dead-lettered still cannot finalize it. It keeps its unique hash unless another
+	/// task holds it in the meantime.
+	#[allow(dead_code)]
+	pub(crate) async fn requeue(connection: &mut SqliteConnection, id: TaskId, now: SqliteDateTime) -> Result<Option<Task>, AsyncQueueError> {
+		let mut transaction = connection.begin().await?;
+
+		let task = sqlx::query_as!(
+			Task,
+			r#"INSERT INTO backie_tasks (
+                id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at, scheduled_at,
+                max_retries, backoff_mode, retries, attempt, tags, error_history, enqueue_seq
+            )
+            SELECT id, task_name, queue_name,
+                CASE
+                    WHEN EXISTS (
+                        SELECT 1 FROM backie_tasks holder
+                        WHERE holder.uniq_hash = backie_dead_tasks.uniq_hash
+                        AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                        AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                    ) THEN NULL
+                    ELSE uniq_hash
+                END,
+                uniq_scope, payload, timeout_msecs, created_at, ?,
+                max_retries, backoff_mode, 0, attempt, tags, error_history,
+                (SELECT COALESCE(MAX(enqueue_seq), 0) + 1 FROM backie_tasks)
+            FROM backie_dead_tasks
+            WHERE id = ?
+            RETURNING *"#,
 			now,
-			max_retries,
-			backoff_mode
+			id
 		)
-		.fetch_one(connection)
+		.fetch_optional(&mut *transaction)
 		.await?;
 
+		if task.is_some() {
+			sqlx::query!("DELETE FROM backie_dead_tasks WHERE id = ?", id).execute(&mut *transaction).await?;
+			transaction.commit().await?;
+		}
+
 		Ok(task)
 	}
+
+	#[allow(dead_code)]
+	pub(crate) async fn purge(connection: &mut SqliteConnection, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let result = sqlx::query!("DELETE FROM backie_dead_tasks WHERE id = ?", id).execute(connection).await?;
+
+		Ok(result.rows_affected() > 0)
+	}
+
+	/// Removes the dead tasks of the queue that were dead-lettered before the cutoff.
+	#[allow(dead_code)]
+	pub(crate) async fn purge_expired(connection: &mut SqliteConnection, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+		let dead_before = SqliteDateTime(dead_before);
+		let result = sqlx::query!("DELETE FROM backie_dead_tasks WHERE queue_name = ? AND dead_at < ?", queue_name, dead_before)
+			.execute(connection)
+			.await?;
+
+		Ok(result.rows_affected())
+	}
 }
 
-// use diesel::prelude::*;
-// use diesel::ExpressionMethods;
-// use diesel_async::{pg::AsyncPgConnection, RunQueryDsl};
-
-// impl Task {
-// 	pub(crate) async fn remove(connection: &mut AsyncPgConnection, id: TaskId) -> Result<u64, AsyncQueueError> {
-// 		let query = backie_tasks::table.filter(backie_tasks::id.eq(id));
-// 		Ok(diesel::delete(query).execute(connection).await? as u64)
-// 	}
-//
-// 	pub(crate) async fn fail_with_message(connection: &mut AsyncPgConnection, id: TaskId, error_message: &str) -> Result<Task, AsyncQueueError> {
-// 		let error = serde_json::json!({
-// 				"error": error_message,
-// 		});
-// 		let query = backie_tasks::table.filter(backie_tasks::id.eq(id));
-// 		Ok(
-// 			diesel::update(query)
-// 				.set((backie_tasks::error_info.eq(Some(error)), backie_tasks::done_at.eq(Utc::now())))
-// 				.get_result::<Task>(connection)
-// 				.await?,
-// 		)
-// 	}
-//
-// 	pub(crate) async fn schedule_retry(connection: &mut AsyncPgConnection, id: TaskId, backoff: Duration, error_message: &str) -> Result<Task, AsyncQueueError> {
-// 		use crate::schema::backie_tasks::dsl;
-//
-// 		let error = serde_json::json!({
-// 				"error": error_message,
-// 		});
-//
-// 		let task = diesel::update(backie_tasks::table.filter(backie_tasks::id.eq(id)))
-// 			.set((
-// 				backie_tasks::error_info.eq(Some(error)),
-// 				backie_tasks::retries.eq(dsl::retries + 1),
-// 				backie_tasks::scheduled_at.eq(Utc::now() + chrono::Duration::from_std(backoff).unwrap_or(chrono::Duration::max_value())),
-// 				backie_tasks::running_at.eq::<Option<DateTime<Utc>>>(None),
-// 			))
-// 			.get_result::<Task>(connection)
-// 			.await?;
-//
-// 		Ok(task)
-// 	}
-//
-// 	pub(crate) async fn fetch_next_pending(connection: &mut AsyncPgConnection, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Option<Task> {
-// 		if let Some(execution_timeout) = execution_timeout {
-// 			backie_tasks::table
-// 				.filter(backie_tasks::task_name.eq_any(task_names))
-// 				.filter(backie_tasks::scheduled_at.lt(Utc::now())) // skip tasks scheduled for the future
-// 				.order(backie_tasks::created_at.asc()) // get the oldest task first
-// 				.filter(backie_tasks::done_at.is_null()) // and not marked as done
-// 				.filter(backie_tasks::queue_name.eq(queue_name))
-// 				.filter(
-// 					backie_tasks::running_at
-// 						.is_null()
-// 						.or(backie_tasks::running_at.lt(Utc::now() - chrono::Duration::from_std(execution_timeout).unwrap_or(chrono::Duration::max_value()))),
-// 				) // that is not marked as running already or expired
-// 				.for_update()
-// 				.skip_locked()
-// 				.limit(1)
-// 				.get_result::<Task>(connection)
-// 				.await
-// 				.ok()
-// 		} else {
-// 			backie_tasks::table
-// 				.filter(backie_tasks::task_name.eq_any(task_names))
-// 				.filter(backie_tasks::scheduled_at.lt(Utc::now())) // skip tasks scheduled for the future
-// 				.order(backie_tasks::created_at.asc()) // get the oldest task first
-// 				.filter(backie_tasks::done_at.is_null()) // and not marked as done
-// 				.filter(backie_tasks::queue_name.eq(queue_name))
-// 				.filter(backie_tasks::running_at.is_null()) // that is not marked as running already
-// 				.for_update()
-// 				.skip_locked()
-// 				.limit(1)
-// 				.get_result::<Task>(connection)
-// 				.await
-// 				.ok()
-// 		}
-// 	}
-//
-// 	pub(crate) async fn set_running(connection: &mut AsyncPgConnection, task: Task) -> Result<Task, AsyncQueueError> {
-// 		Ok(diesel::update(&task).set((backie_tasks::running_at.eq(Utc::now()),)).get_result::<Task>(connection).await?)
-// 	}
-//
-// 	pub(crate) async fn set_done(connection: &mut AsyncPgConnection, id: TaskId) -> Result<Task, AsyncQueueError> {
-// 		Ok(
-// 			diesel::update(backie_tasks::table.filter(backie_tasks::id.eq(id)))
-// 				.set((backie_tasks::done_at.eq(Utc::now()),))
-// 				.get_result::<Task>(connection)
-// 				.await?,
-// 		)
-// 	}
-//
-// 	pub(crate) async fn insert(connection: &mut AsyncPgConnection, new_task: NewTask) -> Result<Task, AsyncQueueError> {
-// 		Ok(diesel::insert_into(backie_tasks::table).values(new_task).get_result::<Task>(connection).await?)
-// 	}
-// }
-//
-//
+impl TaskAttempt {
+	#[allow(dead_code)]
+	pub(crate) async fn insert(connection: &mut SqliteConnection, attempt: &Self) -> Result<(), AsyncQueueError> {
+		sqlx::query!(
+			r#"INSERT INTO backie_task_attempts (task_id, attempt, worker_id, started_at, finished_at, outcome, error)
+            VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+			attempt.task_id,
+			attempt.attempt,
+			attempt.worker_id,
+			attempt.started_at,
+			attempt.finished_at,
+			attempt.outcome,
+			attempt.error
+		)
+		.execute(connection)
+		.await?;
+
+		Ok(())
+	}
+
+	#[allow(dead_code)]
+	pub(crate) async fn list_for_task(connection: &mut SqliteConnection, task_id: TaskId) -> Result<Vec<Self>, AsyncQueueError> {
+		let attempts = sqlx::query_as!(Self, "SELECT * FROM backie_task_attempts WHERE task_id = ? ORDER BY attempt ASC", task_id)
+			.fetch_all(connection)
+			.await?;
+
+		Ok(attempts)
+	}
+}
@@ -0,0 +1,832 @@
+use crate::errors::AsyncQueueError;
+use crate::sqlite_helpers::{clamped_millis, time_after, time_before, JsonField, OptionalJsonValue, OptionalSqliteDateTime, SqliteDateTime};
+use crate::sqlite_task::{
+	AttemptOutcome, DeadTask, DeadTaskPage, ErrorHistory, FailedAttempt, NewTask, OptionalTaskHash, Task, TaskAttempt, TaskError, TaskFilter, TaskHash, TaskId, TaskPage,
+	TaskState, TaskTags,
+};
+use crate::stats::{QueueStats, TaskCounts, TaskNameTally};
+use crate::UniqueScope;
+use chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{PgConnection, Postgres, QueryBuilder};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Row of the `backie_tasks` table as stored by Postgres.
+///
+/// Postgres has native types for uuids, timestamps and json, so rows are decoded into those
+/// and converted into the storage agnostic [`Task`] afterwards.
+#[derive(Debug, sqlx::FromRow)]
+struct PgTaskRow {
+	id: Uuid,
+	task_name: String,
+	queue_name: String,
+	uniq_hash: Option<String>,
+	uniq_scope: String,
+	payload: serde_json::Value,
+	timeout_msecs: i64,
+	created_at: DateTime<Utc>,
+	scheduled_at: DateTime<Utc>,
+	running_at: Option<DateTime<Utc>>,
+	lease_expires_at: Option<DateTime<Utc>>,
+	done_at: Option<DateTime<Utc>>,
+	cancelled_at: Option<DateTime<Utc>>,
+	error_info: Option<serde_json::Value>,
+	retries: i32,
+	max_retries: i32,
+	backoff_mode: serde_json::Value,
+	attempt: i32,
+	tags: Json<Vec<String>>,
+	error_history: Json<Vec<FailedAttempt>>,
+	enqueue_seq: i64,
+}
+
+impl TryFrom<PgTaskRow> for Task {
+	type Error = AsyncQueueError;
+
+	fn try_from(row: PgTaskRow) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: TaskId::from(row.id),
+			task_name: row.task_name,
+			queue_name: row.queue_name,
+			uniq_hash: OptionalTaskHash(row.uniq_hash.map(TaskHash::from)),
+			uniq_scope: UniqueScope::from(row.uniq_scope),
+			payload: JsonField(row.payload),
+			timeout_msecs: row.timeout_msecs,
+			created_at: SqliteDateTime(row.created_at),
+			scheduled_at: SqliteDateTime(row.scheduled_at),
+			running_at: OptionalSqliteDateTime(row.running_at.map(SqliteDateTime)),
+			lease_expires_at: OptionalSqliteDateTime(row.lease_expires_at.map(SqliteDateTime)),
+			done_at: OptionalSqliteDateTime(row.done_at.map(SqliteDateTime)),
+			cancelled_at: OptionalSqliteDateTime(row.cancelled_at.map(SqliteDateTime)),
+			error_info: OptionalJsonValue(row.error_info),
+			retries: i64::from(row.retries),
+			max_retries: i64::from(row.max_retries),
+			backoff_mode: serde_json::from_value(row.backoff_mode)?,
+			attempt: i64::from(row.attempt),
+			tags: TaskTags(row.tags.0),
+			error_history: ErrorHistory(row.error_history.0),
+			enqueue_seq: row.enqueue_seq,
+		})
+	}
+}
+
+/// Row of the `backie_dead_tasks` table as stored by Postgres, see [`PgTaskRow`].
+#[derive(Debug, sqlx::FromRow)]
+struct PgDeadTaskRow {
+	id: Uuid,
+	task_name: String,
+	queue_name: String,
+	uniq_hash: Option<String>,
+	uniq_scope: String,
+	payload: serde_json::Value,
+	timeout_msecs: i64,
+	created_at: DateTime<Utc>,
+	max_retries: i32,
+	backoff_mode: serde_json::Value,
+	retries: i32,
+	attempt: i32,
+	tags: Json<Vec<String>>,
+	error_history: Json<Vec<FailedAttempt>>,
+	dead_at: DateTime<Utc>,
+	error_info: Option<serde_json::Value>,
+}
+
+impl TryFrom<PgDeadTaskRow> for DeadTask {
+	type Error = AsyncQueueError;
+
+	fn try_from(row: PgDeadTaskRow) -> Result<Self, Self::Error> {
+		Ok(Self {
+			id: TaskId::from(row.id),
+			task_name: row.task_name,
+			queue_name: row.queue_name,
+			uniq_hash: OptionalTaskHash(row.uniq_hash.map(TaskHash::from)),
+			uniq_scope: UniqueScope::from(row.uniq_scope),
+			payload: JsonField(row.payload),
+			timeout_msecs: row.timeout_msecs,
+			created_at: SqliteDateTime(row.created_at),
+			max_retries: i64::from(row.max_retries),
+			backoff_mode: serde_json::from_value(row.backoff_mode)?,
+			retries: i64::from(row.retries),
+			attempt: i64::from(row.attempt),
+			tags: TaskTags(row.tags.0),
+			error_history: ErrorHistory(row.error_history.0),
+			dead_at: SqliteDateTime(row.dead_at),
+			error_info: OptionalJsonValue(row.error_info),
+		})
+	}
+}
+
+/// Row of the `backie_task_attempts` table as stored by Postgres, see [`PgTaskRow`].
+#[derive(Debug, sqlx::FromRow)]
+struct PgTaskAttemptRow {
+	task_id: Uuid,
+	attempt: i32,
+	worker_id: String,
+	started_at: DateTime<Utc>,
+	finished_at: DateTime<Utc>,
+	outcome: String,
+	error: Option<String>,
+}
+
+impl From<PgTaskAttemptRow> for TaskAttempt {
+	fn from(row: PgTaskAttemptRow) -> Self {
+		Self {
+			task_id: TaskId::from(row.task_id),
+			attempt: i64::from(row.attempt),
+			worker_id: row.worker_id,
+			started_at: SqliteDateTime(row.started_at),
+			finished_at: SqliteDateTime(row.finished_at),
+			outcome: AttemptOutcome::from(row.outcome),
+			error: row.error,
+		}
+	}
+}
+
+/// Aggregates of the tasks of a queue with the same name, see [`queue_stats`].
+#[derive(Debug, sqlx::FromRow)]
+struct PgStatsRow {
+	task_name: String,
+	ready: i64,
+	scheduled: i64,
+	running: i64,
+	failed: i64,
+	done: i64,
+	cancelled: i64,
+	oldest_ready_at: Option<DateTime<Utc>>,
+	waited_msecs: i64,
+	started: i64,
+}
+
+impl PgStatsRow {
+	fn into_tally(self) -> (String, TaskNameTally) {
+		let count = |count: i64| u64::try_from(count).unwrap_or_default();
+		let tally = TaskNameTally {
+			counts: TaskCounts {
+				ready: count(self.ready),
+				scheduled: count(self.scheduled),
+				running: count(self.running),
+				failed: count(self.failed),
+				done: count(self.done),
+				cancelled: count(self.cancelled),
+			},
+			oldest_ready_at: self.oldest_ready_at,
+			waited_msecs: self.waited_msecs,
+			started: count(self.started),
+		};
+		(self.task_name, tally)
+	}
+}
+
+fn timeout_threshold(now: DateTime<Utc>, execution_timeout: Option<Duration>) -> Option<DateTime<Utc>> {
+	execution_timeout.map(|timeout| time_before(now, timeout))
+}
+
+fn lease_expiration(now: DateTime<Utc>, lease_duration: Duration) -> DateTime<Utc> {
+	time_after(now, lease_duration)
+}
+
+/// The current time according to the database, see [`crate::PgTaskStore::database_time`].
+pub(crate) async fn database_now(connection: &mut PgConnection) -> Result<DateTime<Utc>, AsyncQueueError> {
+	let now = sqlx::query_scalar::<_, DateTime<Utc>>("SELECT now()").fetch_one(connection).await?;
+	Ok(now)
+}
+
+pub(crate) async fn remove(connection: &mut PgConnection, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError> {
+	let result = sqlx::query("DELETE FROM backie_tasks WHERE id = $1 AND attempt = $2")
+		.bind(Uuid::from(id))
+		.bind(attempt)
+		.execute(connection)
+		.await?;
+
+	match result.rows_affected() {
+		0 => Err(AsyncQueueError::StaleAttempt(id, attempt)),
+		removed => Ok(removed),
+	}
+}
+
+/// Converts the row updated by a finalization, missing when the attempt does not hold the task anymore.
+fn fenced(row: Option<PgTaskRow>, id: TaskId, attempt: i64) -> Result<Task, AsyncQueueError> {
+	row.ok_or(AsyncQueueError::StaleAttempt(id, attempt)).and_then(Task::try_from)
+}
+
+pub(crate) async fn fail_with_error(connection: &mut PgConnection, id: TaskId, attempt: i64, error: &TaskError, now: DateTime<Utc>) -> Result<Task, AsyncQueueError> {
+	let error_info = error.to_error_info(now)?;
+
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET error_info = $1,
+            error_history = error_history || jsonb_build_array(jsonb_build_object('attempt', attempt, 'error', $5::TEXT)),
+            done_at = $2
+        WHERE id = $3 AND attempt = $4
+        RETURNING *"#,
+	)
+	.bind(error_info)
+	.bind(now)
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.bind(&error.message)
+	.fetch_optional(connection)
+	.await?;
+
+	fenced(row, id, attempt)
+}
+
+/// Records the failure of an attempt and schedules the task to run again after the backoff.
+///
+/// Tasks whose cancellation was requested while they were running are finished as cancelled instead.
+pub(crate) async fn schedule_retry(
+	connection: &mut PgConnection,
+	id: TaskId,
+	attempt: i64,
+	backoff: Duration,
+	error: &TaskError,
+	now: DateTime<Utc>,
+) -> Result<Task, AsyncQueueError> {
+	let error_info = error.to_error_info(now)?;
+	let scheduled_at = time_after(now, backoff);
+
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET error_info = $1,
+            error_history = error_history || jsonb_build_array(jsonb_build_object('attempt', attempt, 'error', $5::TEXT)),
+            done_at = CASE WHEN cancelled_at IS NULL THEN done_at ELSE $6 END,
+            retries = CASE WHEN cancelled_at IS NULL THEN retries + 1 ELSE retries END,
+            scheduled_at = CASE WHEN cancelled_at IS NULL THEN $2 ELSE scheduled_at END,
+            running_at = CASE WHEN cancelled_at IS NULL THEN NULL ELSE running_at END,
+            lease_expires_at = CASE WHEN cancelled_at IS NULL THEN NULL ELSE lease_expires_at END,
+            uniq_hash = CASE
+                WHEN cancelled_at IS NULL AND uniq_scope = 'Pending' AND EXISTS (
+                    SELECT 1 FROM backie_tasks duplicate
+                    WHERE duplicate.uniq_hash = backie_tasks.uniq_hash
+                    AND duplicate.id != backie_tasks.id
+                    AND duplicate.running_at IS NULL
+                    AND duplicate.done_at IS NULL
+                ) THEN NULL
+                ELSE uniq_hash
+            END
+        WHERE id = $3 AND attempt = $4
+        RETURNING *"#,
+	)
+	.bind(error_info)
+	.bind(scheduled_at)
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.bind(&error.message)
+	.bind(now)
+	.fetch_optional(connection)
+	.await?;
+
+	fenced(row, id, attempt)
+}
+
+/// Claims the oldest pending task of the queue in a single statement.
+///
+/// Rows locked by concurrent claims are skipped instead of waited on, so many workers can pull
+/// from the same queue without ever being handed the same task.
+///
+/// Tasks created at the same time are claimed in the order they were inserted in, see the
+/// `enqueue_seq` column.
+///
+/// Running tasks are claimed again once their lease expired, or when they hold no lease, once
+/// they have been running for longer than the execution timeout.
+pub(crate) async fn claim_next_pending(
+	connection: &mut PgConnection,
+	queue_name: &str,
+	execution_timeout: Option<Duration>,
+	lease_duration: Option<Duration>,
+	task_names: &[String],
+	now: DateTime<Utc>,
+) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET running_at = $1, lease_expires_at = $5, attempt = attempt + 1
+        WHERE id = (
+            SELECT id FROM backie_tasks
+            WHERE task_name = ANY($2)
+            AND scheduled_at < $1
+            AND done_at IS NULL
+            AND queue_name = $3
+            AND (running_at IS NULL OR lease_expires_at < $1 OR (lease_expires_at IS NULL AND running_at < $4))
+            ORDER BY created_at ASC, enqueue_seq ASC
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *"#,
+	)
+	.bind(now)
+	.bind(task_names)
+	.bind(queue_name)
+	.bind(timeout_threshold(now, execution_timeout))
+	.bind(lease_duration.map(|lease_duration| lease_expiration(now, lease_duration)))
+	.fetch_optional(connection)
+	.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+/// Extends the lease of a running task, returns `false` if the attempt does not hold the task anymore.
+pub(crate) async fn renew_lease(connection: &mut PgConnection, id: TaskId, attempt: i64, lease_duration: Duration, now: DateTime<Utc>) -> Result<bool, AsyncQueueError> {
+	let result = sqlx::query(
+		r#"UPDATE backie_tasks
+        SET lease_expires_at = $1
+        WHERE id = $2
+        AND attempt = $3
+        AND running_at IS NOT NULL
+        AND done_at IS NULL"#,
+	)
+	.bind(lease_expiration(now, lease_duration))
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.execute(connection)
+	.await?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+pub(crate) async fn set_done(connection: &mut PgConnection, id: TaskId, attempt: i64, now: DateTime<Utc>) -> Result<Task, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET done_at = $1
+        WHERE id = $2 AND attempt = $3
+        RETURNING *"#,
+	)
+	.bind(now)
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.fetch_optional(connection)
+	.await?;
+
+	fenced(row, id, attempt)
+}
+
+/// Requests the cancellation of an unfinished task.
+///
+/// Pending tasks are finished right away, running tasks are only flagged so the worker executing
+/// them can stop them.
+pub(crate) async fn cancel(connection: &mut PgConnection, id: TaskId, now: DateTime<Utc>) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET cancelled_at = COALESCE(cancelled_at, $1),
+            done_at = CASE WHEN running_at IS NULL THEN $1 ELSE done_at END
+        WHERE id = $2
+        AND done_at IS NULL
+        RETURNING *"#,
+	)
+	.bind(now)
+	.bind(Uuid::from(id))
+	.fetch_optional(connection)
+	.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+pub(crate) async fn is_cancellation_requested(connection: &mut PgConnection, id: TaskId) -> Result<bool, AsyncQueueError> {
+	let cancelled = sqlx::query_scalar::<_, bool>("SELECT cancelled_at IS NOT NULL FROM backie_tasks WHERE id = $1")
+		.bind(Uuid::from(id))
+		.fetch_optional(connection)
+		.await?;
+
+	Ok(cancelled.unwrap_or(false))
+}
+
+pub(crate) async fn set_cancelled(connection: &mut PgConnection, id: TaskId, attempt: i64, now: DateTime<Utc>) -> Result<Task, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET cancelled_at = COALESCE(cancelled_at, $1), done_at = $1
+        WHERE id = $2 AND attempt = $3
+        RETURNING *"#,
+	)
+	.bind(now)
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.fetch_optional(connection)
+	.await?;
+
+	fenced(row, id, attempt)
+}
+
+/// Finds the task currently holding the given unique hash, if any.
+pub(crate) async fn find_by_uniq_hash(connection: &mut PgConnection, uniq_hash: &TaskHash) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"SELECT * FROM backie_tasks
+        WHERE uniq_hash = $1
+        AND (done_at IS NULL OR uniq_scope = 'Forever')
+        AND (running_at IS NULL OR done_at IS NOT NULL OR uniq_scope != 'Pending')
+        LIMIT 1"#,
+	)
+	.bind(uniq_hash.as_ref())
+	.fetch_optional(connection)
+	.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+/// Inserts a new task created at the given time, or at the time of the database when none is
+/// given, unless another task holds the same unique hash.
+///
+/// When the unique index rejects the row, the task holding the hash is returned instead.
+pub(crate) async fn insert(connection: &mut PgConnection, new_task: NewTask, now: Option<DateTime<Utc>>) -> Result<Task, AsyncQueueError> {
+	let delay_msecs = new_task.scheduled_in.map(clamped_millis);
+	let (task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, max_retries, backoff_mode, scheduled_at, tags) = new_task.into_values();
+	let backoff_mode = serde_json::to_value(backoff_mode)?;
+
+	for _ in 0..super::INSERT_ATTEMPTS {
+		// The creation time is read once, the delay of the task is counted from it
+		let inserted = sqlx::query_as::<_, PgTaskRow>(
+			r#"INSERT INTO backie_tasks (
+                id, task_name, queue_name, uniq_hash, uniq_scope, payload,
+                timeout_msecs, created_at, scheduled_at,
+                max_retries, backoff_mode, retries, tags
+            )
+            SELECT $1, $2, $3, $4, $5, $6, $7, enqueued.created_at,
+                COALESCE($9, LEAST(enqueued.created_at + $10::bigint * interval '1 millisecond', $11), enqueued.created_at),
+                $12, $13, 0, $14
+            FROM (SELECT COALESCE($8, now()) AS created_at) AS enqueued
+            ON CONFLICT (uniq_hash) WHERE uniq_hash IS NOT NULL
+                AND (done_at IS NULL OR uniq_scope = 'Forever')
+                AND (running_at IS NULL OR done_at IS NOT NULL OR uniq_scope != 'Pending')
+            DO NOTHING
+            RETURNING *"#,
+		)
+		.bind(Uuid::new_v4())
+		.bind(&task_name)
+		.bind(&queue_name)
+		.bind(uniq_hash.as_ref().map(AsRef::<str>::as_ref))
+		.bind(uniq_scope.to_string())
+		.bind(&payload)
+		.bind(timeout_msecs)
+		.bind(now)
+		.bind(scheduled_at)
+		.bind(delay_msecs)
+		.bind(DateTime::<Utc>::MAX_UTC)
+		.bind(max_retries)
+		.bind(&backoff_mode)
+		.bind(Json(&tags.0))
+		.fetch_optional(&mut *connection)
+		.await?;
+
+		if let Some(row) = inserted {
+			return Task::try_from(row);
+		}
+
+		// Only the unique hash index is a conflict target, a task without hash is always inserted
+		let Some(uniq_hash) = &uniq_hash else { break };
+		if let Some(existing) = find_by_uniq_hash(&mut *connection, uniq_hash).await? {
+			log::debug!("Task {} already holds unique hash {}, not enqueueing a duplicate", existing.id, uniq_hash);
+			return Ok(existing);
+		}
+		// The conflicting task was finished in the meantime, try again
+	}
+
+	Err(AsyncQueueError::UniqHashConflict(task_name))
+}
+
+pub(crate) async fn find(connection: &mut PgConnection, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>("SELECT * FROM backie_tasks WHERE id = $1")
+		.bind(Uuid::from(id))
+		.fetch_optional(connection)
+		.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+/// Condition matching the tasks in the given state, the error of [`TaskState::Failed`] is ignored.
+const fn state_condition(state: &TaskState) -> &'static str {
+	match state {
+		TaskState::Ready => " AND done_at IS NULL AND running_at IS NULL",
+		TaskState::Running => " AND done_at IS NULL AND running_at IS NOT NULL",
+		TaskState::Failed(_) => " AND done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NOT NULL",
+		TaskState::Done => " AND done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NULL",
+		TaskState::Cancelled => " AND done_at IS NOT NULL AND cancelled_at IS NOT NULL",
+	}
+}
+
+/// Pushes the criteria of the filter shared by tasks and dead tasks and its cursor, then orders
+/// the matching rows and keeps the first `limit` of them.
+fn push_filter<'a>(query: &mut QueryBuilder<'a, Postgres>, filter: &'a TaskFilter, limit: i64) {
+	if let Some(queue) = &filter.queue {
+		query.push(" AND queue_name = ").push_bind(queue);
+	}
+	if let Some(task_name) = &filter.task_name {
+		query.push(" AND task_name = ").push_bind(task_name);
+	}
+	if let Some(created_after) = filter.created_after {
+		query.push(" AND created_at >= ").push_bind(created_after);
+	}
+	if let Some(created_before) = filter.created_before {
+		query.push(" AND created_at < ").push_bind(created_before);
+	}
+	if let Some(tag) = &filter.tag {
+		query.push(" AND tags @> ").push_bind(Json([tag]));
+	}
+	if let Some(cursor) = filter.cursor {
+		query
+			.push(" AND (created_at, id) > (")
+			.push_bind(cursor.created_at)
+			.push(", ")
+			.push_bind(Uuid::from(cursor.id))
+			.push(")");
+	}
+	query.push(" ORDER BY created_at ASC, id ASC LIMIT ").push_bind(limit);
+}
+
+/// Lists a page of the tasks matching the filter, ordered by creation time then id.
+pub(crate) async fn list(connection: &mut PgConnection, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError> {
+	let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM backie_tasks WHERE 1 = 1");
+	if let Some(state) = &filter.state {
+		query.push(state_condition(state));
+	}
+	push_filter(&mut query, filter, i64::from(filter.limit) + 1);
+
+	let rows = query.build_query_as::<PgTaskRow>().fetch_all(connection).await?;
+	let tasks = rows.into_iter().map(Task::try_from).collect::<Result<Vec<_>, _>>()?;
+
+	Ok(TaskPage::from_overfetched(tasks, filter.limit))
+}
+
+/// Aggregates the tasks of a queue by task name, in a single pass over the queue index.
+pub(crate) async fn queue_stats(connection: &mut PgConnection, queue_name: &str, now: DateTime<Utc>) -> Result<QueueStats, AsyncQueueError> {
+	let rows = sqlx::query_as::<_, PgStatsRow>(
+		r#"SELECT task_name,
+            COUNT(*) FILTER (WHERE done_at IS NULL AND running_at IS NULL AND scheduled_at < $1) AS ready,
+            COUNT(*) FILTER (WHERE done_at IS NULL AND running_at IS NULL AND scheduled_at >= $1) AS scheduled,
+            COUNT(*) FILTER (WHERE done_at IS NULL AND running_at IS NOT NULL) AS running,
+            COUNT(*) FILTER (WHERE done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NOT NULL) AS failed,
+            COUNT(*) FILTER (WHERE done_at IS NOT NULL AND cancelled_at IS NULL AND error_info IS NULL) AS done,
+            COUNT(*) FILTER (WHERE done_at IS NOT NULL AND cancelled_at IS NOT NULL) AS cancelled,
+            MIN(scheduled_at) FILTER (WHERE done_at IS NULL AND running_at IS NULL AND scheduled_at < $1) AS oldest_ready_at,
+            COALESCE(SUM(EXTRACT(EPOCH FROM running_at - scheduled_at) * 1000), 0)::BIGINT AS waited_msecs,
+            COUNT(running_at) AS started
+        FROM backie_tasks
+        WHERE queue_name = $2
+        GROUP BY task_name"#,
+	)
+	.bind(now)
+	.bind(queue_name)
+	.fetch_all(connection)
+	.await?;
+
+	Ok(QueueStats::new(queue_name, rows.into_iter().map(PgStatsRow::into_tally), now))
+}
+
+/// Removes a batch of tasks that finished before the cutoff of their outcome, done ones first and
+/// each outcome through its own index, so the tasks that are still retained are never read.
+pub(crate) async fn prune_finished(
+	connection: &mut PgConnection,
+	queue_name: &str,
+	done_before: DateTime<Utc>,
+	failed_before: DateTime<Utc>,
+	limit: u32,
+) -> Result<u64, AsyncQueueError> {
+	let done = sqlx::query(
+		r#"DELETE FROM backie_tasks
+        WHERE id IN (
+            SELECT id FROM backie_tasks
+            WHERE queue_name = $1
+            AND done_at IS NOT NULL AND error_info IS NULL AND cancelled_at IS NULL
+            AND done_at < $2
+            ORDER BY done_at ASC
+            LIMIT $3
+            FOR UPDATE SKIP LOCKED
+        )"#,
+	)
+	.bind(queue_name)
+	.bind(done_before)
+	.bind(i64::from(limit))
+	.execute(&mut *connection)
+	.await?
+	.rows_affected();
+
+	let remaining = u64::from(limit).saturating_sub(done) as i64;
+	if remaining == 0 {
+		return Ok(done);
+	}
+	let failed = sqlx::query(
+		r#"DELETE FROM backie_tasks
+        WHERE id IN (
+            SELECT id FROM backie_tasks
+            WHERE queue_name = $1
+            AND done_at IS NOT NULL AND (error_info IS NOT NULL OR cancelled_at IS NOT NULL)
+            AND done_at < $2
+            ORDER BY done_at ASC
+            LIMIT $3
+            FOR UPDATE SKIP LOCKED
+        )"#,
+	)
+	.bind(queue_name)
+	.bind(failed_before)
+	.bind(remaining)
+	.execute(connection)
+	.await?
+	.rows_affected();
+
+	Ok(done + failed)
+}
+
+/// Puts a failed task back in its queue, to be executed right away.
+///
+/// The task keeps its error history and its attempt counter. It keeps its unique hash unless
+/// another task holds it in the meantime.
+pub(crate) async fn retry(connection: &mut PgConnection, id: TaskId, reset_retries: bool, now: DateTime<Utc>) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"UPDATE backie_tasks
+        SET done_at = NULL,
+            running_at = NULL,
+            lease_expires_at = NULL,
+            error_info = NULL,
+            scheduled_at = $1,
+            retries = CASE WHEN $2 THEN 0 ELSE retries END,
+            uniq_hash = CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM backie_tasks holder
+                    WHERE holder.uniq_hash = backie_tasks.uniq_hash
+                    AND holder.id != backie_tasks.id
+                    AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                    AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                ) THEN NULL
+                ELSE uniq_hash
+            END
+        WHERE id = $3
+        AND done_at IS NOT NULL
+        AND cancelled_at IS NULL
+        AND error_info IS NOT NULL
+        RETURNING *"#,
+	)
+	.bind(now)
+	.bind(reset_retries)
+	.bind(Uuid::from(id))
+	.fetch_optional(connection)
+	.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+/// Puts the failed tasks matching the filter back in their queue, see [`retry`].
+pub(crate) async fn retry_where(connection: &mut PgConnection, filter: &TaskFilter, reset_retries: bool, now: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+	let mut query = QueryBuilder::<Postgres>::new(
+		r#"UPDATE backie_tasks
+        SET done_at = NULL,
+            running_at = NULL,
+            lease_expires_at = NULL,
+            error_info = NULL,
+            scheduled_at = "#,
+	);
+	query.push_bind(now).push(", retries = CASE WHEN ").push_bind(reset_retries).push(
+		r#" THEN 0 ELSE retries END,
+            uniq_hash = CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM backie_tasks holder
+                    WHERE holder.uniq_hash = backie_tasks.uniq_hash
+                    AND holder.id != backie_tasks.id
+                    AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                    AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                ) THEN NULL
+                ELSE uniq_hash
+            END
+        WHERE id IN (SELECT id FROM backie_tasks WHERE 1 = 1"#,
+	);
+	query.push(state_condition(&TaskState::Failed(TaskError::default())));
+	push_filter(&mut query, filter, i64::from(filter.limit));
+	query.push(" FOR UPDATE SKIP LOCKED)");
+
+	let result = query.build().execute(connection).await?;
+
+	Ok(result.rows_affected())
+}
+
+/// Moves the given attempt of a task to the dead-letter queue, along with the error it failed with.
+///
+/// The task is removed and inserted by a single statement, so it is never lost nor in both tables.
+pub(crate) async fn dead_letter(connection: &mut PgConnection, id: TaskId, attempt: i64, error: &TaskError, now: DateTime<Utc>) -> Result<DeadTask, AsyncQueueError> {
+	let error_info = error.to_error_info(now)?;
+
+	let row = sqlx::query_as::<_, PgDeadTaskRow>(
+		r#"WITH dead AS (
+            DELETE FROM backie_tasks
+            WHERE id = $1 AND attempt = $2
+            RETURNING *
+        )
+        INSERT INTO backie_dead_tasks (
+            id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at,
+            max_retries, backoff_mode, retries, attempt, tags, error_history, dead_at, error_info
+        )
+        SELECT id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at,
+            max_retries, backoff_mode, retries, attempt, tags,
+            error_history || jsonb_build_array(jsonb_build_object('attempt', attempt, 'error', $3::TEXT)), $4, $5
+        FROM dead
+        RETURNING *"#,
+	)
+	.bind(Uuid::from(id))
+	.bind(attempt)
+	.bind(&error.message)
+	.bind(now)
+	.bind(error_info)
+	.fetch_optional(connection)
+	.await?;
+
+	row.ok_or(AsyncQueueError::StaleAttempt(id, attempt)).and_then(DeadTask::try_from)
+}
+
+pub(crate) async fn find_dead(connection: &mut PgConnection, id: TaskId) -> Result<Option<DeadTask>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgDeadTaskRow>("SELECT * FROM backie_dead_tasks WHERE id = $1")
+		.bind(Uuid::from(id))
+		.fetch_optional(connection)
+		.await?;
+
+	row.map(DeadTask::try_from).transpose()
+}
+
+/// Lists a page of the dead tasks matching the filter, ordered by creation time then id.
+pub(crate) async fn list_dead(connection: &mut PgConnection, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError> {
+	let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM backie_dead_tasks WHERE 1 = 1");
+	push_filter(&mut query, filter, i64::from(filter.limit) + 1);
+
+	let rows = query.build_query_as::<PgDeadTaskRow>().fetch_all(connection).await?;
+	let dead_tasks = rows.into_iter().map(DeadTask::try_from).collect::<Result<Vec<_>, _>>()?;
+
+	Ok(DeadTaskPage::from_overfetched(dead_tasks, filter.limit))
+}
+
+/// Puts a dead task back in its queue with its retries reset, to be executed right away.
+///
+/// The task keeps its error history and its attempt counter, so workers that lost the task
+/// before it was dead-lettered still cannot finalize it. It keeps its unique hash unless another
+/// task holds it in the meantime.
+pub(crate) async fn requeue_dead(connection: &mut PgConnection, id: TaskId, now: DateTime<Utc>) -> Result<Option<Task>, AsyncQueueError> {
+	let row = sqlx::query_as::<_, PgTaskRow>(
+		r#"WITH dead AS (
+            DELETE FROM backie_dead_tasks
+            WHERE id = $1
+            RETURNING *
+        )
+        INSERT INTO backie_tasks (
+            id, task_name, queue_name, uniq_hash, uniq_scope, payload, timeout_msecs, created_at, scheduled_at,
+            max_retries, backoff_mode, retries, attempt, tags, error_history
+        )
+        SELECT id, task_name, queue_name,
+            CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM backie_tasks holder
+                    WHERE holder.uniq_hash = dead.uniq_hash
+                    AND (holder.done_at IS NULL OR holder.uniq_scope = 'Forever')
+                    AND (holder.running_at IS NULL OR holder.done_at IS NOT NULL OR holder.uniq_scope != 'Pending')
+                ) THEN NULL
+                ELSE uniq_hash
+            END,
+            uniq_scope, payload, timeout_msecs, created_at, $2,
+            max_retries, backoff_mode, 0, attempt, tags, error_history
+        FROM dead
+        RETURNING *"#,
+	)
+	.bind(Uuid::from(id))
+	.bind(now)
+	.fetch_optional(connection)
+	.await?;
+
+	row.map(Task::try_from).transpose()
+}
+
+pub(crate) async fn purge_dead(connection: &mut PgConnection, id: TaskId) -> Result<bool, AsyncQueueError> {
+	let result = sqlx::query("DELETE FROM backie_dead_tasks WHERE id = $1").bind(Uuid::from(id)).execute(connection).await?;
+
+	Ok(result.rows_affected() > 0)
+}
+
+/// Removes the dead tasks of the queue that were dead-lettered before the cutoff.
+pub(crate) async fn purge_expired_dead(connection: &mut PgConnection, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+	let result = sqlx::query("DELETE FROM backie_dead_tasks WHERE queue_name = $1 AND dead_at < $2")
+		.bind(queue_name)
+		.bind(dead_before)
+		.execute(connection)
+		.await?;
+
+	Ok(result.rows_affected())
+}
+
+pub(crate) async fn insert_attempt(connection: &mut PgConnection, attempt: &TaskAttempt) -> Result<(), AsyncQueueError> {
+	sqlx::query(
+		r#"INSERT INTO backie_task_attempts (task_id, attempt, worker_id, started_at, finished_at, outcome, error)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+	)
+	.bind(Uuid::from(attempt.task_id))
+	.bind(attempt.attempt)
+	.bind(&attempt.worker_id)
+	.bind(attempt.started_at.0)
+	.bind(attempt.finished_at.0)
+	.bind(attempt.outcome.to_string())
+	.bind(&attempt.error)
+	.execute(connection)
+	.await?;
+
+	Ok(())
+}
+
+pub(crate) async fn list_attempts(connection: &mut PgConnection, task_id: TaskId) -> Result<Vec<TaskAttempt>, AsyncQueueError> {
+	let rows = sqlx::query_as::<_, PgTaskAttemptRow>("SELECT * FROM backie_task_attempts WHERE task_id = $1 ORDER BY attempt ASC")
+		.bind(Uuid::from(task_id))
+		.fetch_all(connection)
+		.await?;
+
+	Ok(rows.into_iter().map(TaskAttempt::from).collect())
+}
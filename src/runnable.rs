@@ -1,8 +1,30 @@
 use crate::sqlite_task::{CurrentTask, TaskHash};
-use crate::BackoffMode;
+use crate::{BackoffMode, UniqueScope};
 use async_trait::async_trait;
 use serde::{de::DeserializeOwned, ser::Serialize};
 use std::fmt::Debug;
+use std::time::Duration;
+
+/// What a failed task should do next, see [`BackgroundTask::classify_error`].
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum ErrorClass {
+	/// Retry the task after the backoff of its [`BackgroundTask::BACKOFF_MODE`], as long as it has
+	/// retries left
+	Retryable,
+
+	/// Retrying cannot help, fail the task right away whatever the retries it has left
+	Permanent,
+
+	/// Retry the task once the given delay has passed instead of after its backoff, as long as it
+	/// has retries left
+	RetryAfter(Duration),
+}
+
+impl Default for ErrorClass {
+	fn default() -> Self {
+		Self::Retryable
+	}
+}
 
 /// The [`BackgroundTask`] trait is used to define the behaviour of a task. You must implement this
 /// trait for all tasks you want to execute.
@@ -60,6 +82,16 @@ pub trait BackgroundTask: Serialize + DeserializeOwned + Sync + Send + 'static {
 	/// Backoff mode for tasks.
 	const BACKOFF_MODE: BackoffMode = BackoffMode::ExponentialBackoff;
 
+	/// Maximum time a task can run for before it is failed with a timeout error.
+	///
+	/// By default, it is set to 2 minutes.
+	const TIMEOUT: Duration = Duration::from_secs(120);
+
+	/// How long the hash returned by [`BackgroundTask::uniq`] prevents duplicates.
+	///
+	/// By default, a task is unique until it is finished.
+	const UNIQUE_SCOPE: UniqueScope = UniqueScope::PendingOrRunning;
+
 	/// The application data provided to this task at runtime.
 	type AppData: Clone + Send + 'static;
 
@@ -69,9 +101,53 @@ pub trait BackgroundTask: Serialize + DeserializeOwned + Sync + Send + 'static {
 	/// Execute the task. This method should define its logic
 	async fn run(&self, task: CurrentTask, context: Self::AppData) -> Result<(), Self::Error>;
 
-	/// If set to true, no new tasks with the same metadata will be inserted
-	/// By default it is set to false.
+	/// If a hash is returned, enqueueing a task with the same hash as an existing one within
+	/// [`BackgroundTask::UNIQUE_SCOPE`] returns the existing task instead of inserting a new one.
+	/// By default no hash is returned and every task is inserted.
 	fn uniq(&self) -> Option<TaskHash> {
 		None
 	}
+
+	/// Tell whether the task should be retried after failing with the given error.
+	///
+	/// By default every error is [`ErrorClass::Retryable`]. Tasks whose payload cannot be
+	/// deserialized never reach this method, they are failed as [`ErrorClass::Permanent`].
+	///
+	/// ```
+	/// # use async_trait::async_trait;
+	/// # use foo::{BackgroundTask, CurrentTask, ErrorClass};
+	/// # use serde::{Deserialize, Serialize};
+	/// # use std::time::Duration;
+	/// #[derive(Debug)]
+	/// pub enum DeliveryError {
+	///     InvalidAddress,
+	///     RateLimited { retry_in_secs: u64 },
+	///     Unavailable,
+	/// }
+	///
+	/// #[derive(Serialize, Deserialize)]
+	/// pub struct DeliverEmail {}
+	///
+	/// #[async_trait]
+	/// impl BackgroundTask for DeliverEmail {
+	///     const TASK_NAME: &'static str = "deliver_email";
+	///     type AppData = ();
+	///     type Error = DeliveryError;
+	///
+	///     async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), Self::Error> {
+	///         Err(DeliveryError::Unavailable)
+	///     }
+	///
+	///     fn classify_error(&self, error: &Self::Error) -> ErrorClass {
+	///         match error {
+	///             DeliveryError::InvalidAddress => ErrorClass::Permanent,
+	///             DeliveryError::RateLimited { retry_in_secs } => ErrorClass::RetryAfter(Duration::from_secs(*retry_in_secs)),
+	///             DeliveryError::Unavailable => ErrorClass::Retryable,
+	///         }
+	///     }
+	/// }
+	/// ```
+	fn classify_error(&self, _error: &Self::Error) -> ErrorClass {
+		ErrorClass::Retryable
+	}
 }
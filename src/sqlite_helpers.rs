@@ -1,4 +1,4 @@
-use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use chrono::{DateTime, SubsecRound, TimeDelta, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlite_macros::SqliteType;
@@ -10,25 +10,53 @@ pub trait SqliteValidate {
 	fn validate(s: &str) -> Result<(), Self::Error>;
 }
 
-// Use the derived macro for SqliteDateTime
+// Use the derived macro for SqliteDateTime, stored as milliseconds since the epoch
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, SqliteType)]
 #[sqlite_type(validate, max_length = "20")]
 pub struct SqliteDateTime(pub DateTime<Utc>);
 
 impl fmt::Display for SqliteDateTime {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "{}", self.timestamp())
+		write!(f, "{}", self.timestamp_millis())
 	}
 }
 
 impl SqliteDateTime {
+	/// The current time, truncated to the precision it is stored with.
 	pub(crate) fn now() -> Self {
-		Self(Utc::now())
+		Self::at(Utc::now())
 	}
 
-	pub(crate) fn timestamp(&self) -> i64 {
-		self.0.timestamp()
+	/// The given time, truncated to the precision it is stored with.
+	pub(crate) fn at(time: DateTime<Utc>) -> Self {
+		Self(time.trunc_subsecs(3))
 	}
+
+	pub(crate) fn timestamp_millis(&self) -> i64 {
+		self.0.timestamp_millis()
+	}
+}
+
+/// The time the given duration after `time`, the latest representable time if it overflows.
+pub(crate) fn time_after(time: DateTime<Utc>, duration: std::time::Duration) -> DateTime<Utc> {
+	TimeDelta::from_std(duration)
+		.ok()
+		.and_then(|duration| time.checked_add_signed(duration))
+		.unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// The milliseconds of the given duration, never more than the latest representable time is
+/// away from the epoch, so adding it to a stored time cannot overflow.
+pub(crate) fn clamped_millis(duration: std::time::Duration) -> i64 {
+	i64::try_from(duration.as_millis()).map_or(i64::MAX, |millis| millis.min(DateTime::<Utc>::MAX_UTC.timestamp_millis()))
+}
+
+/// The time the given duration before `time`, never earlier than the epoch stored times start at.
+pub(crate) fn time_before(time: DateTime<Utc>, duration: std::time::Duration) -> DateTime<Utc> {
+	TimeDelta::from_std(duration)
+		.ok()
+		.and_then(|duration| time.checked_sub_signed(duration))
+		.map_or(DateTime::<Utc>::UNIX_EPOCH, |earlier| earlier.max(DateTime::<Utc>::UNIX_EPOCH))
 }
 
 impl FromStr for SqliteDateTime {
@@ -41,7 +69,10 @@ impl FromStr for SqliteDateTime {
 			return Err(sqlx::Error::Protocol("Timestamp cannot be negative".into()));
 		}
 
-		let datetime = Utc.timestamp_opt(timestamp, 0).single().ok_or_else(|| sqlx::Error::Protocol("Invalid timestamp".into()))?;
+		let datetime = Utc
+			.timestamp_millis_opt(timestamp)
+			.single()
+			.ok_or_else(|| sqlx::Error::Protocol("Invalid timestamp".into()))?;
 
 		Ok(Self(datetime))
 	}
@@ -49,7 +80,7 @@ impl FromStr for SqliteDateTime {
 
 impl From<i64> for SqliteDateTime {
 	fn from(timestamp: i64) -> Self {
-		let datetime = Utc.timestamp_opt(timestamp, 0).single().unwrap();
+		let datetime = Utc.timestamp_millis_opt(timestamp).single().unwrap();
 		Self(datetime)
 	}
 }
@@ -71,7 +102,7 @@ impl SqliteValidate for SqliteDateTime {
 				if timestamp < 0 {
 					return Err(sqlx::Error::Protocol("Timestamp cannot be negative".into()));
 				}
-				match Utc.timestamp_opt(timestamp, 0).single() {
+				match Utc.timestamp_millis_opt(timestamp).single() {
 					Some(_) => Ok(()),
 					None => Err(sqlx::Error::Protocol("Invalid timestamp".into())),
 				}
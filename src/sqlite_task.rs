@@ -1,6 +1,7 @@
 use crate::sqlite_helpers::SqliteValidate;
-use crate::sqlite_helpers::{JsonField, OptionalJsonValue, OptionalSqliteDateTime, SqliteDateTime};
-use crate::BackoffMode;
+use crate::sqlite_helpers::{time_after, JsonField, OptionalJsonValue, OptionalSqliteDateTime, SqliteDateTime};
+use crate::{BackoffMode, UniqueScope};
+use chrono::{DateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
 use sqlite_macros::SqliteType;
 use sqlx::{Error, FromRow};
@@ -8,6 +9,7 @@ use std::borrow::Cow;
 use std::fmt;
 use std::str::FromStr;
 use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 // use sqlx::sqlite::{SqliteArgumentValue, SqliteTypeInfo, SqliteValueRef};
@@ -17,8 +19,104 @@ use uuid::Uuid;
 pub enum TaskState {
 	Ready,
 	Running,
-	Failed(String),
+	Failed(TaskError),
 	Done,
+	Cancelled,
+}
+
+/// What made an attempt of a task fail.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskErrorKind {
+	/// The payload of the task could not be decoded.
+	Deserialization,
+	/// The task returned an error, the kind of the errors recorded before it was known.
+	#[default]
+	Execution,
+	Panic,
+	Timeout,
+	Cancelled,
+}
+
+/// Error a task failed with, as kept in its `error_info`.
+///
+/// Errors recorded before their kind was kept, stored as `{"error": "<message>"}`, are read as
+/// [`TaskErrorKind::Execution`] errors of an unknown attempt and time.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TaskError {
+	#[serde(default)]
+	pub kind: TaskErrorKind,
+	#[serde(alias = "error")]
+	pub message: String,
+	/// The attempt that failed, see [`Task::attempt`], zero if unknown.
+	#[serde(default)]
+	pub attempt: i64,
+	/// When the attempt failed, as told by the time of the store that recorded it.
+	#[serde(default, with = "timestamp_millis", skip_serializing_if = "Option::is_none")]
+	pub failed_at: Option<DateTime<Utc>>,
+	/// Where the task panicked, for [`TaskErrorKind::Panic`] errors, only known once
+	/// [`crate::install_panic_hook`] was called.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub location: Option<String>,
+	/// Backtrace of the panic, only captured when enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub backtrace: Option<String>,
+}
+
+impl TaskError {
+	/// An error of the given attempt, timed by the store that records it unless `failed_at` is set.
+	#[must_use]
+	pub fn new(kind: TaskErrorKind, message: impl Into<String>, attempt: i64) -> Self {
+		Self {
+			kind,
+			message: message.into(),
+			attempt,
+			failed_at: None,
+			location: None,
+			backtrace: None,
+		}
+	}
+
+	/// The `error_info` kept for this error, failing at the given time of the store unless it is
+	/// already known.
+	pub(crate) fn to_error_info(&self, now: DateTime<Utc>) -> serde_json::Result<serde_json::Value> {
+		serde_json::to_value(Self {
+			failed_at: self.failed_at.or(Some(now.trunc_subsecs(3))),
+			..self.clone()
+		})
+	}
+
+	/// Reads the `error_info` of a task, falling back to its raw JSON as the message if it is no
+	/// error record.
+	fn from_error_info(error_info: &serde_json::Value) -> Self {
+		serde_json::from_value(error_info.clone()).unwrap_or_else(|_| Self {
+			message: error_info.to_string(),
+			..Self::default()
+		})
+	}
+}
+
+impl fmt::Display for TaskError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+/// Timestamps kept as milliseconds since the epoch, chrono is built without serde support.
+mod timestamp_millis {
+	use chrono::{DateTime, TimeZone, Utc};
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub(super) fn serialize<S: Serializer>(timestamp: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+		match timestamp {
+			Some(timestamp) => serializer.serialize_some(&timestamp.timestamp_millis()),
+			None => serializer.serialize_none(),
+		}
+	}
+
+	pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+		Ok(Option::<i64>::deserialize(deserializer)?.and_then(|millis| Utc.timestamp_millis_opt(millis).single()))
+	}
 }
 
 #[derive(Clone, Copy, Debug, Ord, PartialOrd, Hash, PartialEq, Eq, Serialize, Deserialize, SqliteType)]
@@ -146,12 +244,90 @@ impl FromStr for OptionalTaskHash {
 	}
 }
 
+/// Free-form labels attached to a task when it is enqueued, see [`EnqueueOptions::tag`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, SqliteType)]
+pub struct TaskTags(pub Vec<String>);
+
+impl TaskTags {
+	#[must_use]
+	pub fn contains(&self, tag: &str) -> bool {
+		self.0.iter().any(|t| t == tag)
+	}
+}
+
+impl fmt::Display for TaskTags {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match serde_json::to_string(&self.0) {
+			Ok(s) => write!(f, "{}", s),
+			Err(e) => write!(f, "{{\"error\": \"{}\"}}", e),
+		}
+	}
+}
+
+impl FromStr for TaskTags {
+	type Err = serde_json::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		serde_json::from_str(s).map(TaskTags)
+	}
+}
+
+impl From<String> for TaskTags {
+	fn from(s: String) -> Self {
+		serde_json::from_str(&s).unwrap_or_else(|e| panic!("Failed to parse task tags: {}. Error: {}", s, e))
+	}
+}
+
+/// The error an attempt of a task failed with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedAttempt {
+	/// The attempt that failed, see [`Task::attempt`].
+	pub attempt: i64,
+	pub error: String,
+}
+
+/// Errors of the failed attempts of a task, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, SqliteType)]
+pub struct ErrorHistory(pub Vec<FailedAttempt>);
+
+impl ErrorHistory {
+	/// The error of the last failed attempt.
+	#[must_use]
+	pub fn last(&self) -> Option<&FailedAttempt> {
+		self.0.last()
+	}
+}
+
+impl fmt::Display for ErrorHistory {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match serde_json::to_string(&self.0) {
+			Ok(s) => write!(f, "{}", s),
+			Err(e) => write!(f, "{{\"error\": \"{}\"}}", e),
+		}
+	}
+}
+
+impl FromStr for ErrorHistory {
+	type Err = serde_json::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		serde_json::from_str(s).map(ErrorHistory)
+	}
+}
+
+impl From<String> for ErrorHistory {
+	fn from(s: String) -> Self {
+		serde_json::from_str(&s).unwrap_or_else(|e| panic!("Failed to parse error history: {}. Error: {}", s, e))
+	}
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, FromRow)]
 pub struct Task {
 	pub id: TaskId,
 	pub task_name: String,
 	pub queue_name: String,
 	pub uniq_hash: OptionalTaskHash,
+	pub uniq_scope: UniqueScope,
 	pub payload: JsonField,
 	pub timeout_msecs: i64,
 	#[sqlx(rename = "created_at")]
@@ -160,24 +336,166 @@ pub struct Task {
 	pub scheduled_at: SqliteDateTime,
 	#[sqlx(rename = "running_at")]
 	pub running_at: OptionalSqliteDateTime,
+	#[sqlx(rename = "lease_expires_at")]
+	pub lease_expires_at: OptionalSqliteDateTime,
 	#[sqlx(rename = "done_at")]
 	pub done_at: OptionalSqliteDateTime,
+	#[sqlx(rename = "cancelled_at")]
+	pub cancelled_at: OptionalSqliteDateTime,
 	pub error_info: OptionalJsonValue,
 	pub retries: i64,
 	pub max_retries: i64,
 	pub backoff_mode: BackoffMode,
+	/// Fencing token of the current claim, incremented each time the task is pulled.
+	pub attempt: i64,
+	pub tags: TaskTags,
+	/// Errors of the attempts that failed so far, the last one is also kept in `error_info`.
+	pub error_history: ErrorHistory,
+	/// Order the task was enqueued in, which breaks ties between tasks created in the same
+	/// millisecond.
+	pub enqueue_seq: i64,
 }
 
 impl Task {
 	#[must_use]
 	pub fn state(&self) -> TaskState {
 		match (self.done_at.0, &self.error_info.0) {
-			(Some(_), Some(error)) => TaskState::Failed(error.to_string()),
+			(Some(_), _) if self.cancelled_at.0.is_some() => TaskState::Cancelled,
+			(Some(_), Some(error)) => TaskState::Failed(TaskError::from_error_info(error)),
 			(Some(_), None) => TaskState::Done,
 			(None, _) if self.running_at.0.is_some() => TaskState::Running,
 			_ => TaskState::Ready,
 		}
 	}
+
+	/// Whether cancellation was requested for this task, it may still be running.
+	#[must_use]
+	pub const fn is_cancellation_requested(&self) -> bool {
+		self.cancelled_at.0.is_some()
+	}
+
+	/// Whether this task still prevents other tasks with the same unique hash from being enqueued.
+	///
+	/// Mirrors the partial unique index on `uniq_hash` used by the SQL stores.
+	#[must_use]
+	pub fn holds_uniq_hash(&self) -> bool {
+		if self.uniq_hash.0.is_none() {
+			return false;
+		}
+		let finished = self.done_at.0.is_some();
+		let running = !finished && self.running_at.0.is_some();
+		match self.uniq_scope {
+			UniqueScope::Pending => !finished && !running,
+			UniqueScope::PendingOrRunning => !finished,
+			UniqueScope::Forever => true,
+		}
+	}
+}
+
+/// How an attempt of a task ended.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Serialize, Deserialize, SqliteType)]
+pub enum AttemptOutcome {
+	Done,
+	Failed,
+	TimedOut,
+	Panicked,
+	Cancelled,
+}
+
+impl fmt::Display for AttemptOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Done => write!(f, "Done"),
+			Self::Failed => write!(f, "Failed"),
+			Self::TimedOut => write!(f, "TimedOut"),
+			Self::Panicked => write!(f, "Panicked"),
+			Self::Cancelled => write!(f, "Cancelled"),
+		}
+	}
+}
+
+impl FromStr for AttemptOutcome {
+	type Err = sqlx::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_lowercase().as_str() {
+			"done" => Ok(Self::Done),
+			"failed" => Ok(Self::Failed),
+			"timedout" => Ok(Self::TimedOut),
+			"panicked" => Ok(Self::Panicked),
+			"cancelled" => Ok(Self::Cancelled),
+			_ => Err(sqlx::Error::Protocol("Invalid attempt outcome".into())),
+		}
+	}
+}
+
+impl From<String> for AttemptOutcome {
+	fn from(s: String) -> Self {
+		Self::from_str(s.as_str()).unwrap_or(Self::Failed)
+	}
+}
+
+impl SqliteValidate for AttemptOutcome {
+	type Error = sqlx::Error;
+
+	fn validate(s: &str) -> Result<(), Self::Error> {
+		Self::from_str(s).map(|_| ())
+	}
+}
+
+/// An execution of a task by a worker, see [`crate::TaskStore::task_attempts`].
+#[derive(Debug, Eq, PartialEq, Clone, FromRow)]
+pub struct TaskAttempt {
+	pub task_id: TaskId,
+	/// The attempt that was executed, see [`Task::attempt`].
+	pub attempt: i64,
+	/// The worker that executed the attempt, unique to the worker pool it belongs to.
+	pub worker_id: String,
+	#[sqlx(rename = "started_at")]
+	pub started_at: SqliteDateTime,
+	#[sqlx(rename = "finished_at")]
+	pub finished_at: SqliteDateTime,
+	pub outcome: AttemptOutcome,
+	/// The error the attempt failed with, if it did not succeed.
+	pub error: Option<String>,
+}
+
+/// A task that exhausted its retries, moved out of its queue to the dead-letter queue.
+///
+/// Dead tasks are never executed, until they are put back in their queue with
+/// [`crate::TaskStore::requeue_dead_task`].
+#[derive(Debug, Eq, PartialEq, Clone, FromRow)]
+pub struct DeadTask {
+	pub id: TaskId,
+	pub task_name: String,
+	pub queue_name: String,
+	pub uniq_hash: OptionalTaskHash,
+	pub uniq_scope: UniqueScope,
+	pub payload: JsonField,
+	pub timeout_msecs: i64,
+	#[sqlx(rename = "created_at")]
+	pub created_at: SqliteDateTime,
+	pub max_retries: i64,
+	pub backoff_mode: BackoffMode,
+	pub retries: i64,
+	/// Attempt the task failed for the last time, requeued tasks keep counting from there.
+	pub attempt: i64,
+	pub tags: TaskTags,
+	pub error_history: ErrorHistory,
+	/// When the task was moved to the dead-letter queue.
+	#[sqlx(rename = "dead_at")]
+	pub dead_at: SqliteDateTime,
+	/// The error the task failed with for the last time, missing for tasks dead-lettered before it
+	/// was kept.
+	pub error_info: OptionalJsonValue,
+}
+
+impl DeadTask {
+	/// The error the task failed with for the last time, see [`DeadTask::error_info`].
+	#[must_use]
+	pub fn error(&self) -> Option<TaskError> {
+		self.error_info.0.as_ref().map(TaskError::from_error_info)
+	}
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -185,10 +503,14 @@ pub struct NewTask {
 	pub(crate) task_name: String,
 	pub(crate) queue_name: String,
 	pub(crate) uniq_hash: Option<TaskHash>,
+	pub(crate) uniq_scope: UniqueScope,
 	pub(crate) payload: serde_json::Value,
 	pub(crate) timeout_msecs: i64,
 	pub(crate) max_retries: i32,
 	pub(crate) backoff_mode: BackoffMode,
+	pub(crate) scheduled_at: Option<DateTime<Utc>>,
+	pub(crate) scheduled_in: Option<Duration>,
+	pub(crate) tags: Vec<String>,
 }
 
 impl NewTask {
@@ -200,10 +522,14 @@ impl NewTask {
 			task_name: T::TASK_NAME.to_string(),
 			queue_name: T::QUEUE.to_string(),
 			uniq_hash: background_task.uniq(),
+			uniq_scope: T::UNIQUE_SCOPE,
 			payload: serde_json::to_value(background_task)?,
 			timeout_msecs: timeout.as_millis() as i64,
 			max_retries: T::MAX_RETRIES,
 			backoff_mode: T::BACKOFF_MODE,
+			scheduled_at: None,
+			scheduled_in: None,
+			tags: Vec::new(),
 		})
 	}
 
@@ -211,37 +537,408 @@ impl NewTask {
 	where
 		T: crate::BackgroundTask,
 	{
-		Self::with_timeout(background_task, Duration::from_secs(120))
+		Self::with_timeout(background_task, T::TIMEOUT)
 	}
 
+	/// Override the defaults taken from the task type with the given options.
 	#[must_use]
-	pub fn into_values(self) -> (String, String, Option<TaskHash>, serde_json::Value, i64, i32, BackoffMode) {
+	pub fn with_options(mut self, options: EnqueueOptions) -> Self {
+		let EnqueueOptions {
+			queue,
+			max_retries,
+			backoff_mode,
+			timeout,
+			scheduled_at,
+			scheduled_in,
+			uniq_hash,
+			uniq_scope,
+			tags,
+		} = options;
+
+		if let Some(queue) = queue {
+			self.queue_name = queue;
+		}
+		if let Some(max_retries) = max_retries {
+			self.max_retries = max_retries;
+		}
+		if let Some(backoff_mode) = backoff_mode {
+			self.backoff_mode = backoff_mode;
+		}
+		if let Some(timeout) = timeout {
+			self.timeout_msecs = timeout.as_millis() as i64;
+		}
+		if scheduled_at.is_some() || scheduled_in.is_some() {
+			self.scheduled_at = scheduled_at;
+			self.scheduled_in = scheduled_in;
+		}
+		if let Some(uniq_hash) = uniq_hash {
+			self.uniq_hash = Some(uniq_hash);
+		}
+		if let Some(uniq_scope) = uniq_scope {
+			self.uniq_scope = uniq_scope;
+		}
+		self.tags.extend(tags);
+		self
+	}
+
+	/// When the task is due, for a task enqueued at the given time.
+	#[allow(dead_code)]
+	pub(crate) fn due_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+		match (self.scheduled_at, self.scheduled_in) {
+			(Some(scheduled_at), _) => scheduled_at,
+			(None, Some(delay)) => time_after(now, delay),
+			(None, None) => now,
+		}
+	}
+
+	#[must_use]
+	pub fn into_values(
+		self,
+	) -> (
+		String,
+		String,
+		Option<TaskHash>,
+		UniqueScope,
+		serde_json::Value,
+		i64,
+		i32,
+		BackoffMode,
+		Option<DateTime<Utc>>,
+		TaskTags,
+	) {
 		(
 			self.task_name,
 			self.queue_name,
 			self.uniq_hash,
+			self.uniq_scope,
 			self.payload,
 			self.timeout_msecs,
 			self.max_retries,
 			self.backoff_mode,
+			self.scheduled_at,
+			TaskTags(self.tags),
 		)
 	}
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Options to override, for a single enqueued task, the defaults declared by its task type.
+///
+/// # Examples
+///
+/// Route a task to a dedicated queue with more retries:
+/// ```
+/// # use foo::EnqueueOptions;
+/// # use std::time::Duration;
+/// let options = EnqueueOptions::new()
+///     .queue("vip")
+///     .max_retries(10)
+///     .timeout(Duration::from_secs(30));
+/// ```
+/// The target queue must be configured in the worker pool for the task to be executed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnqueueOptions {
+	pub(crate) queue: Option<String>,
+	pub(crate) max_retries: Option<i32>,
+	pub(crate) backoff_mode: Option<BackoffMode>,
+	pub(crate) timeout: Option<Duration>,
+	pub(crate) scheduled_at: Option<DateTime<Utc>>,
+	pub(crate) scheduled_in: Option<Duration>,
+	pub(crate) uniq_hash: Option<TaskHash>,
+	pub(crate) uniq_scope: Option<UniqueScope>,
+	pub(crate) tags: Vec<String>,
+}
+
+impl EnqueueOptions {
+	/// Create options that keep all the defaults of the task type.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Set the queue the task is routed to, instead of [`crate::BackgroundTask::QUEUE`].
+	#[must_use]
+	pub fn queue(mut self, queue: impl ToString) -> Self {
+		self.queue = Some(queue.to_string());
+		self
+	}
+
+	/// Set the number of retries, instead of [`crate::BackgroundTask::MAX_RETRIES`].
+	#[must_use]
+	pub const fn max_retries(mut self, max_retries: i32) -> Self {
+		self.max_retries = Some(max_retries);
+		self
+	}
+
+	/// Set the backoff mode between retries, instead of [`crate::BackgroundTask::BACKOFF_MODE`].
+	#[must_use]
+	pub fn backoff_mode(mut self, backoff_mode: BackoffMode) -> Self {
+		self.backoff_mode = Some(backoff_mode);
+		self
+	}
+
+	/// Set the maximum time the task can run for.
+	#[must_use]
+	pub const fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Set the time before which the task is not executed.
+	#[must_use]
+	pub const fn scheduled_at(mut self, scheduled_at: DateTime<Utc>) -> Self {
+		self.scheduled_at = Some(scheduled_at);
+		self.scheduled_in = None;
+		self
+	}
+
+	/// Set the delay before which the task is not executed, counted from the time of the store
+	/// when the task is enqueued, see [`crate::TaskStore::clock`].
+	#[must_use]
+	pub const fn scheduled_in(mut self, delay: Duration) -> Self {
+		self.scheduled_in = Some(delay);
+		self.scheduled_at = None;
+		self
+	}
+
+	/// Set the unique hash of the task, instead of the one returned by [`crate::BackgroundTask::uniq`].
+	#[must_use]
+	pub fn uniq(mut self, uniq_hash: TaskHash) -> Self {
+		self.uniq_hash = Some(uniq_hash);
+		self
+	}
+
+	/// Set how long the unique hash prevents duplicates, instead of [`crate::BackgroundTask::UNIQUE_SCOPE`].
+	#[must_use]
+	pub const fn unique_scope(mut self, uniq_scope: UniqueScope) -> Self {
+		self.uniq_scope = Some(uniq_scope);
+		self
+	}
+
+	/// Attach a tag to the task, which can be used to find it again with [`TaskFilter::tag`].
+	#[must_use]
+	pub fn tag(mut self, tag: impl ToString) -> Self {
+		self.tags.push(tag.to_string());
+		self
+	}
+}
+
+/// Criteria to find tasks with [`crate::TaskStore::list_tasks`], or dead tasks with
+/// [`crate::TaskStore::list_dead_tasks`], in which case the state is ignored.
+///
+/// Tasks are listed oldest first, a page at a time. The [`TaskPage::next_cursor`] of a page is
+/// given to [`TaskFilter::after`] to get the next one.
+///
+/// # Examples
+///
+/// Failed tasks of a queue, created during the last day:
+/// ```
+/// # use foo::{TaskError, TaskFilter, TaskState};
+/// # use chrono::{Duration, Utc};
+/// let filter = TaskFilter::new()
+///     .queue("emails")
+///     .state(TaskState::Failed(TaskError::default()))
+///     .created_after(Utc::now() - Duration::days(1))
+///     .limit(20);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskFilter {
+	pub(crate) queue: Option<String>,
+	pub(crate) task_name: Option<String>,
+	pub(crate) state: Option<TaskState>,
+	pub(crate) created_after: Option<DateTime<Utc>>,
+	pub(crate) created_before: Option<DateTime<Utc>>,
+	pub(crate) tag: Option<String>,
+	pub(crate) cursor: Option<TaskCursor>,
+	pub(crate) limit: u32,
+}
+
+impl Default for TaskFilter {
+	fn default() -> Self {
+		Self {
+			queue: None,
+			task_name: None,
+			state: None,
+			created_after: None,
+			created_before: None,
+			tag: None,
+			cursor: None,
+			limit: 100,
+		}
+	}
+}
+
+impl TaskFilter {
+	/// Create a filter matching every task, listed 100 at a time.
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Only list tasks of the given queue.
+	#[must_use]
+	pub fn queue(mut self, queue: impl ToString) -> Self {
+		self.queue = Some(queue.to_string());
+		self
+	}
+
+	/// Only list tasks with the given [`crate::BackgroundTask::TASK_NAME`].
+	#[must_use]
+	pub fn task_name(mut self, task_name: impl ToString) -> Self {
+		self.task_name = Some(task_name.to_string());
+		self
+	}
+
+	/// Only list tasks in the given state, the error of [`TaskState::Failed`] is ignored.
+	#[must_use]
+	pub fn state(mut self, state: TaskState) -> Self {
+		self.state = Some(state);
+		self
+	}
+
+	/// Only list tasks created at or after the given time.
+	#[must_use]
+	pub const fn created_after(mut self, created_after: DateTime<Utc>) -> Self {
+		self.created_after = Some(created_after);
+		self
+	}
+
+	/// Only list tasks created before the given time.
+	#[must_use]
+	pub const fn created_before(mut self, created_before: DateTime<Utc>) -> Self {
+		self.created_before = Some(created_before);
+		self
+	}
+
+	/// Only list tasks enqueued with the given tag, see [`EnqueueOptions::tag`].
+	#[must_use]
+	pub fn tag(mut self, tag: impl ToString) -> Self {
+		self.tag = Some(tag.to_string());
+		self
+	}
+
+	/// Only list tasks coming after the given cursor, to get the next page of a listing.
+	#[must_use]
+	pub const fn after(mut self, cursor: TaskCursor) -> Self {
+		self.cursor = Some(cursor);
+		self
+	}
+
+	/// Set the maximum number of tasks in a page, pages hold at least one task.
+	#[must_use]
+	pub const fn limit(mut self, limit: u32) -> Self {
+		self.limit = if limit == 0 { 1 } else { limit };
+		self
+	}
+
+	/// Whether the task matches the filter, regardless of the cursor and the limit.
+	pub(crate) fn matches(&self, task: &Task) -> bool {
+		self.state
+			.as_ref()
+			.map_or(true, |state| std::mem::discriminant(state) == std::mem::discriminant(&task.state()))
+			&& self.matches_fields(&task.queue_name, &task.task_name, task.created_at, &task.tags)
+	}
+
+	/// Whether the dead task matches the filter, regardless of the state, the cursor and the limit.
+	pub(crate) fn matches_dead(&self, task: &DeadTask) -> bool {
+		self.matches_fields(&task.queue_name, &task.task_name, task.created_at, &task.tags)
+	}
+
+	fn matches_fields(&self, queue_name: &str, task_name: &str, created_at: SqliteDateTime, tags: &TaskTags) -> bool {
+		self.queue.as_ref().map_or(true, |queue| queue_name == queue)
+			&& self.task_name.as_ref().map_or(true, |name| task_name == name)
+			&& self.created_after.map_or(true, |created_after| created_at.0 >= created_after)
+			&& self.created_before.map_or(true, |created_before| created_at.0 < created_before)
+			&& self.tag.as_ref().map_or(true, |tag| tags.contains(tag))
+	}
+}
+
+/// Position of a task in a listing, tasks being ordered by creation time then id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskCursor {
+	pub(crate) created_at: DateTime<Utc>,
+	pub(crate) id: TaskId,
+}
+
+impl From<&Task> for TaskCursor {
+	fn from(task: &Task) -> Self {
+		Self {
+			created_at: task.created_at.0,
+			id: task.id,
+		}
+	}
+}
+
+impl From<&DeadTask> for TaskCursor {
+	fn from(task: &DeadTask) -> Self {
+		Self {
+			created_at: task.created_at.0,
+			id: task.id,
+		}
+	}
+}
+
+/// A page of tasks returned by [`crate::TaskStore::list_tasks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskPage {
+	pub tasks: Vec<Task>,
+	/// Cursor to get the next page, `None` if this is the last one.
+	pub next_cursor: Option<TaskCursor>,
+}
+
+impl TaskPage {
+	/// Build a page from the tasks matching a filter, fetched with one task more than its limit to
+	/// know whether another page follows.
+	pub(crate) fn from_overfetched(mut tasks: Vec<Task>, limit: u32) -> Self {
+		let limit = limit as usize;
+		let next_cursor = if tasks.len() > limit {
+			tasks.truncate(limit);
+			tasks.last().map(TaskCursor::from)
+		} else {
+			None
+		};
+		Self { tasks, next_cursor }
+	}
+}
+
+/// A page of dead tasks returned by [`crate::TaskStore::list_dead_tasks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadTaskPage {
+	pub tasks: Vec<DeadTask>,
+	/// Cursor to get the next page, `None` if this is the last one.
+	pub next_cursor: Option<TaskCursor>,
+}
+
+impl DeadTaskPage {
+	/// Build a page from the dead tasks matching a filter, fetched with one task more than its
+	/// limit to know whether another page follows.
+	pub(crate) fn from_overfetched(mut tasks: Vec<DeadTask>, limit: u32) -> Self {
+		let limit = limit as usize;
+		let next_cursor = if tasks.len() > limit {
+			tasks.truncate(limit);
+			tasks.last().map(TaskCursor::from)
+		} else {
+			None
+		};
+		Self { tasks, next_cursor }
+	}
+}
+
+#[derive(Debug, Clone)]
 pub struct CurrentTask {
 	id: TaskId,
 	retries: i64,
 	created_at: SqliteDateTime,
+	cancellation: CancellationToken,
 }
 
 impl CurrentTask {
 	#[must_use]
-	pub const fn new(task: &Task) -> Self {
+	pub fn new(task: &Task) -> Self {
 		Self {
 			id: task.id,
 			retries: task.retries,
 			created_at: task.created_at,
+			cancellation: CancellationToken::new(),
 		}
 	}
 
@@ -259,4 +956,19 @@ impl CurrentTask {
 	pub const fn created_at(&self) -> SqliteDateTime {
 		self.created_at
 	}
+
+	/// Token triggered when the task is cancelled while running.
+	///
+	/// Tasks are expected to stop early once it is triggered, otherwise they are aborted after the
+	/// cancellation grace period of the queue.
+	#[must_use]
+	pub fn cancellation_token(&self) -> CancellationToken {
+		self.cancellation.clone()
+	}
+
+	/// Whether the task was cancelled while running.
+	#[must_use]
+	pub fn is_cancelled(&self) -> bool {
+		self.cancellation.is_cancelled()
+	}
 }
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Number of tasks in each state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TaskCounts {
+	/// Pending tasks that are due and wait for a worker.
+	pub ready: u64,
+	/// Pending tasks that are not due yet, including retries waiting for their backoff.
+	pub scheduled: u64,
+	pub running: u64,
+	pub failed: u64,
+	pub done: u64,
+	pub cancelled: u64,
+}
+
+impl TaskCounts {
+	/// Tasks that are not finished yet.
+	#[must_use]
+	pub const fn unfinished(&self) -> u64 {
+		self.ready + self.scheduled + self.running
+	}
+
+	/// Tasks in any state.
+	#[must_use]
+	pub const fn total(&self) -> u64 {
+		self.unfinished() + self.failed + self.done + self.cancelled
+	}
+
+	fn add(&mut self, other: &Self) {
+		self.ready += other.ready;
+		self.scheduled += other.scheduled;
+		self.running += other.running;
+		self.failed += other.failed;
+		self.done += other.done;
+		self.cancelled += other.cancelled;
+	}
+}
+
+/// Backlog of a set of tasks, see [`crate::TaskStore::queue_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TaskStats {
+	pub counts: TaskCounts,
+	/// How long the ready task that became due first has been waiting for a worker.
+	pub oldest_pending_age: Option<Duration>,
+	/// Average time tasks waited between becoming due and being started, for the tasks that were
+	/// started. Retried tasks only count the wait of their last attempt.
+	pub average_wait: Option<Duration>,
+}
+
+/// Backlog of a queue, overall and for each task name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+	pub queue_name: String,
+	pub total: TaskStats,
+	pub by_task_name: BTreeMap<String, TaskStats>,
+}
+
+/// Aggregates of the tasks of a queue with the same name, as computed by the stores.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TaskNameTally {
+	pub(crate) counts: TaskCounts,
+	/// Time the oldest ready task became due.
+	pub(crate) oldest_ready_at: Option<DateTime<Utc>>,
+	/// Total time waited by the started tasks, and how many of them there are.
+	pub(crate) waited_msecs: i64,
+	pub(crate) started: u64,
+}
+
+impl TaskNameTally {
+	fn stats(&self, now: DateTime<Utc>) -> TaskStats {
+		TaskStats {
+			counts: self.counts,
+			oldest_pending_age: self.oldest_ready_at.map(|oldest_ready_at| (now - oldest_ready_at).to_std().unwrap_or_default()),
+			average_wait: (self.started > 0).then(|| Duration::from_millis(u64::try_from(self.waited_msecs).unwrap_or_default() / self.started)),
+		}
+	}
+}
+
+impl QueueStats {
+	pub(crate) fn new(queue_name: &str, tallies: impl IntoIterator<Item = (String, TaskNameTally)>, now: DateTime<Utc>) -> Self {
+		let mut total = TaskNameTally::default();
+		let mut by_task_name = BTreeMap::new();
+
+		for (task_name, tally) in tallies {
+			total.counts.add(&tally.counts);
+			total.oldest_ready_at = total.oldest_ready_at.into_iter().chain(tally.oldest_ready_at).min();
+			total.waited_msecs += tally.waited_msecs;
+			total.started += tally.started;
+			by_task_name.insert(task_name, tally.stats(now));
+		}
+
+		Self {
+			queue_name: queue_name.to_string(),
+			total: total.stats(now),
+			by_task_name,
+		}
+	}
+}
@@ -1,12 +1,21 @@
 use crate::errors::AsyncQueueError;
-use crate::sqlite_task::{Task, TaskId, TaskState};
-use crate::BackgroundTask;
+use crate::sqlite_task::{DeadTask, DeadTaskPage, EnqueueOptions, Task, TaskAttempt, TaskError, TaskFilter, TaskId, TaskPage, TaskState};
+use crate::stats::QueueStats;
+use crate::{BackgroundTask, Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(any(test, feature = "memory_store"))]
+mod memory_task_store;
+#[cfg(feature = "async_postgres")]
+mod pg_task_store;
 mod sqlite_task_store;
 
-// #[cfg(feature = "async_postgres")]
-// pub use self::pg_task_store::*;
+#[cfg(any(test, feature = "memory_store"))]
+pub use self::memory_task_store::*;
+#[cfg(feature = "async_postgres")]
+pub use self::pg_task_store::*;
 #[allow(unused_imports)]
 pub use self::sqlite_task_store::*;
 
@@ -18,7 +27,19 @@ pub trait BackgroundTaskExt {
 	/// This method accepts a connection thus enabling the user to use a transaction while
 	/// scheduling tasks. This is useful if you want to schedule a task only if some other
 	/// condition is met.
-	async fn enqueue<S: TaskStore>(self, connection: &mut S::Connection) -> Result<(), AsyncQueueError>;
+	///
+	/// Returns the id of the enqueued task, which can be handed out to look the task up later. If
+	/// the task is a duplicate of an existing unique task, the id of the existing task is returned.
+	async fn enqueue<S: TaskStore>(self, connection: &mut S::Connection) -> Result<TaskId, AsyncQueueError>;
+
+	/// Enqueue a task, overriding the defaults of its task type with the given options.
+	async fn enqueue_with<S: TaskStore>(self, connection: &mut S::Connection, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError>;
+
+	/// Enqueue a task to be executed no earlier than the given time.
+	async fn enqueue_at<S: TaskStore>(self, connection: &mut S::Connection, scheduled_at: DateTime<Utc>) -> Result<TaskId, AsyncQueueError>;
+
+	/// Enqueue a task to be executed once the given delay has passed.
+	async fn enqueue_in<S: TaskStore>(self, connection: &mut S::Connection, delay: Duration) -> Result<TaskId, AsyncQueueError>;
 }
 
 #[async_trait::async_trait]
@@ -26,120 +47,187 @@ impl<T> BackgroundTaskExt for T
 where
 	T: BackgroundTask,
 {
-	async fn enqueue<S: TaskStore>(self, connection: &mut S::Connection) -> Result<(), AsyncQueueError> {
+	async fn enqueue<S: TaskStore>(self, connection: &mut S::Connection) -> Result<TaskId, AsyncQueueError> {
 		S::enqueue(connection, self).await
 	}
+
+	async fn enqueue_with<S: TaskStore>(self, connection: &mut S::Connection, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		S::enqueue_with(connection, self, options).await
+	}
+
+	async fn enqueue_at<S: TaskStore>(self, connection: &mut S::Connection, scheduled_at: DateTime<Utc>) -> Result<TaskId, AsyncQueueError> {
+		S::enqueue_with(connection, self, EnqueueOptions::new().scheduled_at(scheduled_at)).await
+	}
+
+	async fn enqueue_in<S: TaskStore>(self, connection: &mut S::Connection, delay: Duration) -> Result<TaskId, AsyncQueueError> {
+		S::enqueue_with(connection, self, EnqueueOptions::new().scheduled_in(delay)).await
+	}
 }
 
-#[cfg(test)]
-pub mod test_store {
-	use super::*;
-	use crate::NewTask;
-	use itertools::Itertools;
-	use std::collections::BTreeMap;
-	use std::sync::Arc;
-	use tokio::sync::Mutex;
-
-	#[derive(Default, Clone)]
-	pub struct MemoryTaskStore {
-		pub tasks: Arc<Mutex<BTreeMap<TaskId, Task>>>,
+#[async_trait::async_trait]
+pub trait TaskStore: Send + Sync + 'static {
+	type Connection: Send;
+
+	/// The clock the store tells the time with, which the workers pulling from it tell the time
+	/// with too, see [`Clock`].
+	fn clock(&self) -> Arc<dyn Clock> {
+		Arc::new(SystemClock)
 	}
 
-	#[async_trait::async_trait]
-	impl TaskStore for MemoryTaskStore {
-		type Connection = Self;
-
-		async fn pull_next_task(&self, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Result<Option<Task>, AsyncQueueError> {
-			let mut tasks = self.tasks.lock().await;
-			let mut next_task = None;
-			for (_, task) in tasks
-				.iter_mut()
-				.filter(|(_, task)| task_names.contains(&task.task_name))
-				.sorted_by(|a, b| a.1.created_at.cmp(&b.1.created_at))
-			{
-				if task.queue_name == queue_name && task.state() == TaskState::Ready {
-					task.running_at = Some(chrono::Utc::now());
-					next_task = Some(task.clone());
-					break;
-				} else if let Some(execution_timeout) = execution_timeout {
-					if let Some(running_at) = task.running_at {
-						let execution_timeout = chrono::Duration::from_std(execution_timeout).unwrap();
-						if running_at + execution_timeout < chrono::Utc::now() {
-							task.running_at = Some(chrono::Utc::now());
-							next_task = Some(task.clone());
-							break;
-						}
-					}
-				}
-			}
-			Ok(next_task)
-		}
+	/// Claim the next task of the queue that is ready to be executed.
+	///
+	/// When a lease duration is given, the claimed task holds a lease that the worker keeps renewing
+	/// while executing it, see [`TaskStore::renew_task_lease`]. Running tasks are claimed again
+	/// once their lease expired, or when they hold no lease, once they have been running for longer
+	/// than the execution timeout.
+	async fn pull_next_task(
+		&self,
+		queue_name: &str,
+		execution_timeout: Option<Duration>,
+		lease_duration: Option<Duration>,
+		task_names: &[String],
+	) -> Result<Option<Task>, AsyncQueueError>;
+
+	/// Finalize the given attempt of a task with its outcome.
+	///
+	/// The attempt is the fencing token handed out when the task was pulled, see [`Task::attempt`].
+	/// Fails with [`AsyncQueueError::StaleAttempt`] if the task was pulled again since, so a worker
+	/// that lost its task cannot overwrite the outcome of the attempt that replaced it.
+	async fn set_task_state(&self, id: TaskId, attempt: i64, state: TaskState) -> Result<(), AsyncQueueError>;
 
-		async fn set_task_state(&self, id: TaskId, state: TaskState) -> Result<(), AsyncQueueError> {
-			let mut tasks = self.tasks.lock().await;
-			let task = tasks.get_mut(&id).unwrap();
-
-			use TaskState::*;
-			match state {
-				Done => task.done_at = Some(chrono::Utc::now()),
-				Failed(error_msg) => {
-					let error_payload = serde_json::json!({
-							"error": error_msg,
-					});
-					task.error_info = Some(error_payload);
-					task.done_at = Some(chrono::Utc::now());
-				}
-				_ => {}
-			}
-
-			Ok(())
-		}
+	/// Remove the given attempt of a task, failing with [`AsyncQueueError::StaleAttempt`] if the
+	/// task was pulled again since.
+	async fn remove_task(&self, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError>;
 
-		async fn remove_task(&self, id: TaskId) -> Result<u64, AsyncQueueError> {
-			let mut tasks = self.tasks.lock().await;
-			let res = tasks.remove(&id);
-			if res.is_some() {
-				Ok(1)
-			} else {
-				Ok(0)
-			}
-		}
+	/// Put the given attempt of a task back in the queue to be retried after the backoff, failing
+	/// with [`AsyncQueueError::StaleAttempt`] if the task was pulled again since.
+	///
+	/// The error is kept as the `error_info` of the task, its message in the error history. A task
+	/// whose cancellation was requested is not retried, it is finished in the [`TaskState::Cancelled`] state.
+	async fn schedule_task_retry(&self, id: TaskId, attempt: i64, backoff: Duration, error: &TaskError) -> Result<Task, AsyncQueueError>;
+
+	/// Cancel a task that is not finished yet.
+	///
+	/// A pending task is never executed, it is finished in the [`TaskState::Cancelled`] state
+	/// right away. A running task keeps running until the worker executing it notices the
+	/// request, see [`crate::CurrentTask::cancellation_token`].
+	///
+	/// Returns the updated task, or `None` if there is no such task or it is already finished.
+	async fn cancel_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError>;
+
+	/// Extend the lease held by the given attempt of a running task by the given duration from now.
+	///
+	/// Returns `false` if the attempt does not hold the task anymore, in which case the lease was
+	/// not renewed.
+	async fn renew_task_lease(&self, id: TaskId, attempt: i64, lease_duration: Duration) -> Result<bool, AsyncQueueError>;
 
-		async fn schedule_task_retry(&self, id: TaskId, backoff: Duration, error: &str) -> Result<Task, AsyncQueueError> {
-			let mut tasks = self.tasks.lock().await;
-			let task = tasks.get_mut(&id).unwrap();
+	/// Whether the cancellation of the given task was requested.
+	async fn is_cancellation_requested(&self, id: TaskId) -> Result<bool, AsyncQueueError>;
 
-			let error_payload = serde_json::json!({
-					"error": error,
-			});
-			task.error_info = Some(error_payload);
-			task.running_at = None;
-			task.retries += 1;
-			task.scheduled_at = chrono::Utc::now() + chrono::Duration::from_std(backoff).unwrap_or(chrono::Duration::max_value());
+	/// Fetch a task by id, whatever its state.
+	async fn get_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError>;
 
-			Ok(task.clone())
-		}
+	/// List a page of the tasks matching the filter, oldest first.
+	async fn list_tasks(&self, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError>;
+
+	/// Remove at most `limit` finished tasks of the queue, the successfully finished ones if they
+	/// finished before `done_before`, the failed and cancelled ones if they finished before
+	/// `failed_before`.
+	///
+	/// Returns the number of removed tasks, the oldest finished tasks being removed first.
+	async fn prune_finished_tasks(&self, queue_name: &str, done_before: DateTime<Utc>, failed_before: DateTime<Utc>, limit: u32) -> Result<u64, AsyncQueueError>;
+
+	/// Count the tasks of a queue by state, overall and for each task name, along with how long
+	/// they wait for a worker.
+	async fn queue_stats(&self, queue_name: &str) -> Result<QueueStats, AsyncQueueError>;
 
-		async fn enqueue<T: BackgroundTask>(store: &mut Self::Connection, task: T) -> Result<(), AsyncQueueError> {
-			let mut tasks = store.tasks.lock().await;
-			let new_task = NewTask::new(task)?;
-			let task = Task::from(new_task);
-			tasks.insert(task.id, task);
-			Ok(())
+	/// Number of tasks of a queue that are not finished yet.
+	async fn queue_size(&self, queue_name: &str) -> Result<u64, AsyncQueueError> {
+		Ok(self.queue_stats(queue_name).await?.total.counts.unfinished())
+	}
+
+	/// Record an execution of a task, see [`TaskStore::task_attempts`].
+	async fn record_task_attempt(&self, attempt: &TaskAttempt) -> Result<(), AsyncQueueError>;
+
+	/// The attempts of a task executed by the workers so far, oldest first.
+	///
+	/// Attempts are kept for as long as the task, in its queue or in the dead-letter queue. An
+	/// attempt whose worker died while executing it is never recorded.
+	async fn task_attempts(&self, id: TaskId) -> Result<Vec<TaskAttempt>, AsyncQueueError>;
+
+	/// Put a failed task back in its queue, to be executed right away.
+	///
+	/// When `reset_retries` is set the task gets all its retries back, otherwise it is only retried
+	/// as many times as it had retries left. Returns the task, or `None` if there is no such failed
+	/// task.
+	async fn retry(&self, id: TaskId, reset_retries: bool) -> Result<Option<Task>, AsyncQueueError>;
+
+	/// Put the failed tasks matching the filter back in their queue, see [`TaskStore::retry`].
+	///
+	/// The state of the filter is ignored. At most the limit of the filter are retried, oldest
+	/// first, returns how many were.
+	async fn retry_where(&self, filter: &TaskFilter, reset_retries: bool) -> Result<u64, AsyncQueueError>;
+
+	/// Move the given attempt of a task, which exhausted its retries, to the dead-letter queue.
+	///
+	/// The error the attempt failed with is kept as the error of the dead task and appended to its
+	/// error history. Fails with [`AsyncQueueError::StaleAttempt`] if the task was pulled again since.
+	async fn dead_letter_task(&self, id: TaskId, attempt: i64, error: &TaskError) -> Result<DeadTask, AsyncQueueError>;
+
+	/// Fetch a dead task by id.
+	async fn get_dead_task(&self, id: TaskId) -> Result<Option<DeadTask>, AsyncQueueError>;
+
+	/// List a page of the dead tasks matching the filter, oldest first. The state of the filter is
+	/// ignored.
+	async fn list_dead_tasks(&self, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError>;
+
+	/// Put a dead task back in its queue, with its retries reset, to be executed right away.
+	///
+	/// Returns the requeued task, or `None` if there is no such dead task.
+	async fn requeue_dead_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError>;
+
+	/// Remove a dead task for good, returns `false` if there is no such dead task.
+	async fn purge_dead_task(&self, id: TaskId) -> Result<bool, AsyncQueueError>;
+
+	/// Remove the dead tasks of a queue that were dead-lettered before `dead_before`.
+	///
+	/// Returns the number of removed dead tasks.
+	async fn purge_dead_tasks(&self, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError>;
+
+	/// Fetch a task by id along with its payload decoded as `T`.
+	///
+	/// Fails with [`AsyncQueueError::TaskTypeMismatch`] if the task is not a `T` task.
+	async fn get<T: BackgroundTask>(&self, id: TaskId) -> Result<Option<(Task, T)>, AsyncQueueError>
+	where
+		Self: Sized,
+	{
+		let Some(task) = self.get_task(id).await? else {
+			return Ok(None);
+		};
+		if task.task_name != T::TASK_NAME {
+			return Err(AsyncQueueError::TaskTypeMismatch(id, task.task_name, T::TASK_NAME));
 		}
+		let payload = serde_json::from_value(task.payload.0.clone())?;
+		Ok(Some((task, payload)))
 	}
-}
 
-#[async_trait::async_trait]
-pub trait TaskStore: Send + Sync + 'static {
-	type Connection: Send;
+	/// Enqueue a task, created at the current time of the store: the time of its [`Clock`], or
+	/// of the database when the store reads the time from it.
+	///
+	/// Unlike [`BackgroundTaskExt::enqueue_with`], the task is enqueued on a connection of the
+	/// store, outside of any transaction of the caller.
+	async fn enqueue_task<T: BackgroundTask>(&self, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError>
+	where
+		Self: Sized;
 
-	async fn pull_next_task(&self, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Result<Option<Task>, AsyncQueueError>;
-	async fn set_task_state(&self, id: TaskId, state: TaskState) -> Result<(), AsyncQueueError>;
-	async fn remove_task(&self, id: TaskId) -> Result<u64, AsyncQueueError>;
-	async fn schedule_task_retry(&self, id: TaskId, backoff: Duration, error: &str) -> Result<Task, AsyncQueueError>;
+	async fn enqueue<T: BackgroundTask>(conn: &mut Self::Connection, task: T) -> Result<TaskId, AsyncQueueError>
+	where
+		Self: Sized,
+	{
+		Self::enqueue_with(conn, task, EnqueueOptions::default()).await
+	}
 
-	async fn enqueue<T: BackgroundTask>(conn: &mut Self::Connection, task: T) -> Result<(), AsyncQueueError>
+	async fn enqueue_with<T: BackgroundTask>(conn: &mut Self::Connection, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError>
 	where
 		Self: Sized;
 }
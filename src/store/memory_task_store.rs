@@ -0,0 +1,924 @@
+use crate::errors::AsyncQueueError;
+use crate::sqlite_helpers::{time_after, time_before, JsonField, OptionalJsonValue, OptionalSqliteDateTime, SqliteDateTime};
+use crate::sqlite_task::{
+	DeadTask, DeadTaskPage, EnqueueOptions, ErrorHistory, FailedAttempt, NewTask, OptionalTaskHash, Task, TaskAttempt, TaskCursor, TaskError, TaskFilter, TaskHash, TaskId,
+	TaskPage, TaskState, TaskTags,
+};
+use crate::stats::{QueueStats, TaskCounts, TaskNameTally};
+use crate::{BackgroundTask, Clock, SystemClock, TaskStore, UniqueScope};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// An async queue that keeps tasks in memory.
+///
+/// Tasks are lost when the store is dropped, which makes it a good fit for ephemeral queues and
+/// for tests. Clones of the store share the same tasks.
+#[derive(Debug, Clone)]
+pub struct MemoryTaskStore {
+	inner: Arc<Mutex<Tasks>>,
+	clock: Arc<dyn Clock>,
+}
+
+impl Default for MemoryTaskStore {
+	fn default() -> Self {
+		Self {
+			inner: Arc::default(),
+			clock: Arc::new(SystemClock),
+		}
+	}
+}
+
+impl MemoryTaskStore {
+	#[must_use]
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Set the clock the store tells the time with, enqueueing tasks included, see [`Clock`].
+	///
+	/// Set it before the store is cloned, clones made earlier keep the previous clock.
+	#[must_use]
+	pub fn with_clock(mut self, clock: impl Clock) -> Self {
+		self.clock = Arc::new(clock);
+		self
+	}
+
+	/// The current time of the clock of the store.
+	fn now(&self) -> SqliteDateTime {
+		SqliteDateTime::at(self.clock.now())
+	}
+
+	/// Number of tasks in the store, whatever their state.
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.lock().tasks.len()
+	}
+
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Tasks> {
+		// The indexes are only touched by code that cannot panic halfway through
+		self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+/// Tasks by id, along with the indexes used to claim them without scanning the whole store.
+///
+/// Unfinished tasks are always in exactly one of `ready`, `scheduled`, `leased` or `unleased`.
+#[derive(Debug, Default)]
+struct Tasks {
+	tasks: HashMap<TaskId, Task>,
+
+	/// All tasks by creation time, the order they are listed in.
+	created: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Pending tasks that are due, by queue and task name, oldest first.
+	ready: BTreeMap<String, BTreeMap<String, BTreeSet<(SqliteDateTime, i64, TaskId)>>>,
+
+	/// The sequence number of the last enqueued task, see [`Task::enqueue_seq`].
+	last_enqueue_seq: i64,
+
+	/// Pending tasks that are not due yet, by scheduled time.
+	scheduled: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Running tasks holding a lease, by lease expiration.
+	leased: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Running tasks without a lease, by start time.
+	unleased: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Done tasks, by queue and the time they finished.
+	succeeded: HashMap<String, BTreeSet<(SqliteDateTime, TaskId)>>,
+
+	/// Failed and cancelled tasks, by queue and the time they finished, as they are retained apart from done ones.
+	unsucceeded: HashMap<String, BTreeSet<(SqliteDateTime, TaskId)>>,
+
+	/// The task holding each unique hash, see [`Task::holds_uniq_hash`].
+	uniq_hashes: HashMap<TaskHash, TaskId>,
+
+	/// Statistics of the tasks, by queue and task name.
+	tallies: HashMap<String, BTreeMap<String, Tally>>,
+
+	/// Tasks that exhausted their retries, kept apart from the claimable ones.
+	dead: HashMap<TaskId, DeadTask>,
+
+	/// Dead tasks by creation time, the order they are listed in.
+	dead_created: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Executions of the tasks, by task and in attempt order, whether the task is dead or not.
+	attempts: HashMap<TaskId, Vec<TaskAttempt>>,
+}
+
+/// Counts and wait times of the tasks of a queue with the same name, kept up to date as tasks change.
+#[derive(Debug, Default)]
+struct Tally {
+	counts: TaskCounts,
+
+	/// Ready tasks by the time they became due.
+	ready_since: BTreeSet<(SqliteDateTime, TaskId)>,
+
+	/// Total time waited by the started tasks, and how many of them there are.
+	waited_msecs: i64,
+	started: u64,
+}
+
+impl Tally {
+	fn of<'a>(tallies: &'a mut HashMap<String, BTreeMap<String, Self>>, task: &Task) -> &'a mut Self {
+		tallies.entry(task.queue_name.clone()).or_default().entry(task.task_name.clone()).or_default()
+	}
+
+	fn count(&mut self, task: &Task, due: bool) -> &mut u64 {
+		match task.state() {
+			TaskState::Ready if due => &mut self.counts.ready,
+			TaskState::Ready => &mut self.counts.scheduled,
+			TaskState::Running => &mut self.counts.running,
+			TaskState::Failed(_) => &mut self.counts.failed,
+			TaskState::Done => &mut self.counts.done,
+			TaskState::Cancelled => &mut self.counts.cancelled,
+		}
+	}
+
+	/// Counts a task, `due` telling whether a pending task is ready or scheduled.
+	fn record(&mut self, task: &Task, due: bool) {
+		*self.count(task, due) += 1;
+		if due && task.running_at.0.is_none() && task.done_at.0.is_none() {
+			self.ready_since.insert((task.scheduled_at, task.id));
+		}
+		if let Some(running_at) = task.running_at.0 {
+			self.waited_msecs += (running_at.0 - task.scheduled_at.0).num_milliseconds();
+			self.started += 1;
+		}
+	}
+
+	/// Stops counting a task, given as it was when it was recorded.
+	fn forget(&mut self, task: &Task, due: bool) {
+		let count = self.count(task, due);
+		*count = count.saturating_sub(1);
+		self.ready_since.remove(&(task.scheduled_at, task.id));
+		if let Some(running_at) = task.running_at.0 {
+			self.waited_msecs -= (running_at.0 - task.scheduled_at.0).num_milliseconds();
+			self.started = self.started.saturating_sub(1);
+		}
+	}
+
+	fn snapshot(&self) -> TaskNameTally {
+		TaskNameTally {
+			counts: self.counts,
+			oldest_ready_at: self.ready_since.first().map(|(ready_since, _)| ready_since.0),
+			waited_msecs: self.waited_msecs,
+			started: self.started,
+		}
+	}
+}
+
+impl Tasks {
+	fn insert(&mut self, new_task: NewTask, now: SqliteDateTime) -> TaskId {
+		if let Some(holder) = new_task.uniq_hash.as_ref().and_then(|uniq_hash| self.uniq_hashes.get(uniq_hash)) {
+			log::debug!("Task {} already holds unique hash {:?}, not enqueueing a duplicate", holder, new_task.uniq_hash);
+			return *holder;
+		}
+
+		let scheduled_at = SqliteDateTime(new_task.due_at(now.0));
+		let task = Task {
+			id: TaskId::from(uuid::Uuid::new_v4()),
+			task_name: new_task.task_name,
+			queue_name: new_task.queue_name,
+			uniq_hash: OptionalTaskHash(new_task.uniq_hash),
+			uniq_scope: new_task.uniq_scope,
+			payload: JsonField(new_task.payload),
+			timeout_msecs: new_task.timeout_msecs,
+			created_at: now,
+			scheduled_at,
+			running_at: OptionalSqliteDateTime(None),
+			lease_expires_at: OptionalSqliteDateTime(None),
+			done_at: OptionalSqliteDateTime(None),
+			cancelled_at: OptionalSqliteDateTime(None),
+			error_info: OptionalJsonValue(None),
+			retries: 0,
+			max_retries: i64::from(new_task.max_retries),
+			backoff_mode: new_task.backoff_mode,
+			attempt: 0,
+			tags: TaskTags(new_task.tags),
+			error_history: ErrorHistory::default(),
+			enqueue_seq: self.next_enqueue_seq(),
+		};
+		let id = task.id;
+		self.index(&task, now);
+		self.tasks.insert(id, task);
+		id
+	}
+
+	fn next_enqueue_seq(&mut self) -> i64 {
+		self.last_enqueue_seq += 1;
+		self.last_enqueue_seq
+	}
+
+	/// Where the task stands in the queue, see [`Task::enqueue_seq`].
+	fn queue_position(task: &Task) -> (SqliteDateTime, i64, TaskId) {
+		(task.created_at, task.enqueue_seq, task.id)
+	}
+
+	fn remove(&mut self, id: TaskId) -> Option<Task> {
+		let task = self.tasks.remove(&id)?;
+		self.unindex(&task);
+		Some(task)
+	}
+
+	/// Changes a task, keeping the indexes in sync with its new state.
+	fn update<R>(&mut self, id: TaskId, now: SqliteDateTime, change: impl FnOnce(&mut Task) -> R) -> Option<R> {
+		let mut task = self.remove(id)?;
+		let result = change(&mut task);
+		self.index(&task, now);
+		self.tasks.insert(id, task);
+		Some(result)
+	}
+
+	/// The task, as long as it was not claimed again since the given attempt.
+	fn held_by(&self, id: TaskId, attempt: i64) -> Result<&Task, AsyncQueueError> {
+		self.tasks.get(&id).filter(|task| task.attempt == attempt).ok_or(AsyncQueueError::StaleAttempt(id, attempt))
+	}
+
+	fn index(&mut self, task: &Task, now: SqliteDateTime) {
+		self.created.insert((task.created_at, task.id));
+		Tally::of(&mut self.tallies, task).record(task, task.scheduled_at < now);
+		if let Some(uniq_hash) = task.uniq_hash.0.as_ref().filter(|_| task.holds_uniq_hash()) {
+			self.uniq_hashes.insert(uniq_hash.clone(), task.id);
+		}
+
+		match (task.done_at.0, task.running_at.0, task.lease_expires_at.0) {
+			(Some(done_at), _, _) => {
+				self.finished_mut(task).entry(task.queue_name.clone()).or_default().insert((done_at, task.id));
+			}
+			(None, Some(_), Some(lease_expires_at)) => {
+				self.leased.insert((lease_expires_at, task.id));
+			}
+			(None, Some(running_at), None) => {
+				self.unleased.insert((running_at, task.id));
+			}
+			(None, None, _) if task.scheduled_at < now => {
+				let position = Self::queue_position(task);
+				self.ready
+					.entry(task.queue_name.clone())
+					.or_default()
+					.entry(task.task_name.clone())
+					.or_default()
+					.insert(position);
+			}
+			(None, None, _) => {
+				self.scheduled.insert((task.scheduled_at, task.id));
+			}
+		}
+	}
+
+	/// The index of finished tasks with the same outcome as the given one.
+	fn finished_mut(&mut self, task: &Task) -> &mut HashMap<String, BTreeSet<(SqliteDateTime, TaskId)>> {
+		if task.error_info.0.is_none() && task.cancelled_at.0.is_none() {
+			&mut self.succeeded
+		} else {
+			&mut self.unsucceeded
+		}
+	}
+
+	fn unindex(&mut self, task: &Task) {
+		self.created.remove(&(task.created_at, task.id));
+		if let Some(uniq_hash) = &task.uniq_hash.0 {
+			if self.uniq_hashes.get(uniq_hash) == Some(&task.id) {
+				self.uniq_hashes.remove(uniq_hash);
+			}
+		}
+
+		if let Some(done_at) = task.done_at.0 {
+			if let Some(finished) = self.finished_mut(task).get_mut(&task.queue_name) {
+				finished.remove(&(done_at, task.id));
+			}
+		}
+		if let Some(lease_expires_at) = task.lease_expires_at.0 {
+			self.leased.remove(&(lease_expires_at, task.id));
+		}
+		if let Some(running_at) = task.running_at.0 {
+			self.unleased.remove(&(running_at, task.id));
+		}
+		let was_scheduled = self.scheduled.remove(&(task.scheduled_at, task.id));
+		if let Some(by_name) = self.tallies.get_mut(&task.queue_name) {
+			if let Some(tally) = by_name.get_mut(&task.task_name) {
+				tally.forget(task, !was_scheduled);
+				if tally.counts.total() == 0 {
+					by_name.remove(&task.task_name);
+				}
+			}
+		}
+		let position = Self::queue_position(task);
+		if let Some(by_name) = self.ready.get_mut(&task.queue_name) {
+			if let Some(ready) = by_name.get_mut(&task.task_name) {
+				ready.remove(&position);
+			}
+		}
+	}
+
+	fn list(&self, filter: &TaskFilter) -> TaskPage {
+		let tasks = self
+			.created
+			.range((after(filter.cursor), Bound::Unbounded))
+			.filter_map(|(_, id)| self.tasks.get(id))
+			.filter(|task| filter.matches(task))
+			.take(filter.limit as usize + 1)
+			.cloned()
+			.collect();
+
+		TaskPage::from_overfetched(tasks, filter.limit)
+	}
+
+	/// Puts a failed task back in its queue, keeping its unique hash unless another task holds it.
+	fn retry(&mut self, id: TaskId, reset_retries: bool, now: SqliteDateTime) -> Option<Task> {
+		let task = self.tasks.get(&id).filter(|task| matches!(task.state(), TaskState::Failed(_)))?;
+		let duplicate = task
+			.uniq_hash
+			.0
+			.as_ref()
+			.and_then(|uniq_hash| self.uniq_hashes.get(uniq_hash))
+			.map_or(false, |holder| *holder != id);
+
+		self.update(id, now, |task| {
+			task.done_at = OptionalSqliteDateTime(None);
+			task.running_at = OptionalSqliteDateTime(None);
+			task.lease_expires_at = OptionalSqliteDateTime(None);
+			task.error_info = OptionalJsonValue(None);
+			task.scheduled_at = now;
+			if reset_retries {
+				task.retries = 0;
+			}
+			if duplicate {
+				task.uniq_hash = OptionalTaskHash(None);
+			}
+			task.clone()
+		})
+	}
+
+	fn retry_where(&mut self, filter: &TaskFilter, reset_retries: bool, now: SqliteDateTime) -> u64 {
+		let filter = filter.clone().state(TaskState::Failed(TaskError::default()));
+		let failed = self
+			.created
+			.range((after(filter.cursor), Bound::Unbounded))
+			.filter_map(|(_, id)| self.tasks.get(id))
+			.filter(|task| filter.matches(task))
+			.map(|task| task.id)
+			.take(filter.limit as usize)
+			.collect::<Vec<_>>();
+
+		for id in &failed {
+			self.retry(*id, reset_retries, now);
+		}
+		failed.len() as u64
+	}
+
+	fn prune_finished(&mut self, queue_name: &str, done_before: SqliteDateTime, failed_before: SqliteDateTime, limit: u32) -> u64 {
+		let expired_before = |finished: &HashMap<String, BTreeSet<(SqliteDateTime, TaskId)>>, cutoff: SqliteDateTime| {
+			finished
+				.get(queue_name)
+				.into_iter()
+				.flat_map(move |finished| finished.iter().take_while(move |(done_at, _)| *done_at < cutoff))
+				.map(|(_, id)| *id)
+		};
+		let expired = expired_before(&self.succeeded, done_before)
+			.chain(expired_before(&self.unsucceeded, failed_before))
+			.take(limit as usize)
+			.collect::<Vec<_>>();
+
+		for id in &expired {
+			self.remove(*id);
+			self.attempts.remove(id);
+		}
+		expired.len() as u64
+	}
+
+	fn dead_letter(&mut self, id: TaskId, attempt: i64, error: &TaskError, now: SqliteDateTime) -> Result<DeadTask, AsyncQueueError> {
+		self.held_by(id, attempt)?;
+		let error_info = error_info(error, now)?;
+		let task = self.remove(id).ok_or(AsyncQueueError::StaleAttempt(id, attempt))?;
+
+		let mut error_history = task.error_history;
+		error_history.0.push(FailedAttempt {
+			attempt,
+			error: error.message.clone(),
+		});
+		let dead_task = DeadTask {
+			id,
+			task_name: task.task_name,
+			queue_name: task.queue_name,
+			uniq_hash: task.uniq_hash,
+			uniq_scope: task.uniq_scope,
+			payload: task.payload,
+			timeout_msecs: task.timeout_msecs,
+			created_at: task.created_at,
+			max_retries: task.max_retries,
+			backoff_mode: task.backoff_mode,
+			retries: task.retries,
+			attempt: task.attempt,
+			tags: task.tags,
+			error_history,
+			dead_at: now,
+			error_info,
+		};
+		self.dead_created.insert((dead_task.created_at, id));
+		self.dead.insert(id, dead_task.clone());
+		Ok(dead_task)
+	}
+
+	fn remove_dead(&mut self, id: TaskId) -> Option<DeadTask> {
+		let dead_task = self.dead.remove(&id)?;
+		self.dead_created.remove(&(dead_task.created_at, id));
+		Some(dead_task)
+	}
+
+	fn list_dead(&self, filter: &TaskFilter) -> DeadTaskPage {
+		let dead_tasks = self
+			.dead_created
+			.range((after(filter.cursor), Bound::Unbounded))
+			.filter_map(|(_, id)| self.dead.get(id))
+			.filter(|dead_task| filter.matches_dead(dead_task))
+			.take(filter.limit as usize + 1)
+			.cloned()
+			.collect();
+
+		DeadTaskPage::from_overfetched(dead_tasks, filter.limit)
+	}
+
+	/// Puts a dead task back in its queue, keeping its unique hash unless another task holds it.
+	fn requeue_dead(&mut self, id: TaskId, now: SqliteDateTime) -> Option<Task> {
+		let dead_task = self.remove_dead(id)?;
+		let task = Task {
+			id,
+			task_name: dead_task.task_name,
+			queue_name: dead_task.queue_name,
+			uniq_hash: OptionalTaskHash(dead_task.uniq_hash.0.filter(|uniq_hash| !self.uniq_hashes.contains_key(uniq_hash))),
+			uniq_scope: dead_task.uniq_scope,
+			payload: dead_task.payload,
+			timeout_msecs: dead_task.timeout_msecs,
+			created_at: dead_task.created_at,
+			scheduled_at: now,
+			running_at: OptionalSqliteDateTime(None),
+			lease_expires_at: OptionalSqliteDateTime(None),
+			done_at: OptionalSqliteDateTime(None),
+			cancelled_at: OptionalSqliteDateTime(None),
+			error_info: OptionalJsonValue(None),
+			retries: 0,
+			max_retries: dead_task.max_retries,
+			backoff_mode: dead_task.backoff_mode,
+			attempt: dead_task.attempt,
+			tags: dead_task.tags,
+			error_history: dead_task.error_history,
+			enqueue_seq: self.next_enqueue_seq(),
+		};
+		self.index(&task, now);
+		self.tasks.insert(id, task.clone());
+		Some(task)
+	}
+
+	fn purge_expired_dead(&mut self, queue_name: &str, dead_before: SqliteDateTime) -> u64 {
+		let expired = self
+			.dead
+			.values()
+			.filter(|dead_task| dead_task.queue_name == queue_name && dead_task.dead_at < dead_before)
+			.map(|dead_task| dead_task.id)
+			.collect::<Vec<_>>();
+
+		for id in &expired {
+			self.remove_dead(*id);
+			self.attempts.remove(id);
+		}
+		expired.len() as u64
+	}
+
+	/// Records an attempt in order, a worker that lost its task may finish after the one that replaced it.
+	fn record_attempt(&mut self, attempt: &TaskAttempt) {
+		let attempts = self.attempts.entry(attempt.task_id).or_default();
+		attempts.push(attempt.clone());
+		attempts.sort_by_key(|recorded| recorded.attempt);
+	}
+
+	fn queue_stats(&mut self, queue_name: &str, now: SqliteDateTime) -> QueueStats {
+		self.promote_due(now);
+		let tallies = self
+			.tallies
+			.get(queue_name)
+			.into_iter()
+			.flatten()
+			.map(|(task_name, tally)| (task_name.clone(), tally.snapshot()));
+		QueueStats::new(queue_name, tallies, now.0)
+	}
+
+	/// Moves the scheduled tasks that became due to the ready index.
+	fn promote_due(&mut self, now: SqliteDateTime) {
+		while let Some(&(scheduled_at, id)) = self.scheduled.first() {
+			if scheduled_at >= now {
+				break;
+			}
+			self.scheduled.remove(&(scheduled_at, id));
+			if let Some(task) = self.tasks.get(&id) {
+				let position = Self::queue_position(task);
+				let tally = Tally::of(&mut self.tallies, task);
+				tally.forget(task, false);
+				tally.record(task, true);
+				self.ready
+					.entry(task.queue_name.clone())
+					.or_default()
+					.entry(task.task_name.clone())
+					.or_default()
+					.insert(position);
+			}
+		}
+	}
+
+	fn claim(&mut self, queue_name: &str, execution_timeout: Option<Duration>, lease_duration: Option<Duration>, task_names: &[String], now: SqliteDateTime) -> Option<Task> {
+		self.promote_due(now);
+
+		let oldest_ready = self
+			.ready
+			.get(queue_name)
+			.and_then(|by_name| task_names.iter().filter_map(|task_name| by_name.get(task_name).and_then(BTreeSet::first).copied()).min());
+
+		// Running tasks whose worker is presumed gone
+		let timeout_threshold = execution_timeout.map(|timeout| SqliteDateTime(time_before(now.0, timeout)));
+		let expired_leases = self.leased.iter().take_while(|(lease_expires_at, _)| *lease_expires_at < now);
+		let timed_out = self
+			.unleased
+			.iter()
+			.take_while(|(running_at, _)| timeout_threshold.map_or(false, |threshold| *running_at < threshold));
+		let oldest_abandoned = expired_leases
+			.chain(timed_out)
+			.filter_map(|(_, id)| self.tasks.get(id))
+			.filter(|task| task.queue_name == queue_name && task_names.contains(&task.task_name))
+			.map(Self::queue_position)
+			.min();
+
+		let (_, _, id) = oldest_ready.into_iter().chain(oldest_abandoned).min()?;
+		self.update(id, now, |task| {
+			task.attempt += 1;
+			task.running_at = OptionalSqliteDateTime(Some(now));
+			task.lease_expires_at = OptionalSqliteDateTime(lease_duration.map(|lease| SqliteDateTime(time_after(now.0, lease))));
+			task.clone()
+		})
+	}
+}
+
+/// Start of a listing, right after the task the cursor points to.
+fn after(cursor: Option<TaskCursor>) -> Bound<(SqliteDateTime, TaskId)> {
+	cursor.map_or(Bound::Unbounded, |cursor| Bound::Excluded((SqliteDateTime(cursor.created_at), cursor.id)))
+}
+
+fn error_info(error: &TaskError, now: SqliteDateTime) -> Result<OptionalJsonValue, AsyncQueueError> {
+	Ok(OptionalJsonValue(Some(error.to_error_info(now.0)?)))
+}
+
+#[async_trait::async_trait]
+impl TaskStore for MemoryTaskStore {
+	type Connection = Self;
+
+	fn clock(&self) -> Arc<dyn Clock> {
+		self.clock.clone()
+	}
+
+	async fn pull_next_task(
+		&self,
+		queue_name: &str,
+		execution_timeout: Option<Duration>,
+		lease_duration: Option<Duration>,
+		task_names: &[String],
+	) -> Result<Option<Task>, AsyncQueueError> {
+		Ok(self.lock().claim(queue_name, execution_timeout, lease_duration, task_names, self.now()))
+	}
+
+	async fn set_task_state(&self, id: TaskId, attempt: i64, state: TaskState) -> Result<(), AsyncQueueError> {
+		let mut tasks = self.lock();
+		tasks.held_by(id, attempt)?;
+
+		let now = self.now();
+		let error_info = match &state {
+			TaskState::Failed(error) => error_info(error, now)?,
+			_ => OptionalJsonValue(None),
+		};
+		tasks.update(id, now, |task| match state {
+			TaskState::Done => task.done_at = OptionalSqliteDateTime(Some(now)),
+			TaskState::Failed(error) => {
+				task.error_info = error_info;
+				task.error_history.0.push(FailedAttempt { attempt, error: error.message });
+				task.done_at = OptionalSqliteDateTime(Some(now));
+			}
+			TaskState::Cancelled => {
+				task.cancelled_at = OptionalSqliteDateTime(Some(task.cancelled_at.0.unwrap_or(now)));
+				task.done_at = OptionalSqliteDateTime(Some(now));
+			}
+			TaskState::Ready | TaskState::Running => {}
+		});
+
+		Ok(())
+	}
+
+	async fn remove_task(&self, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError> {
+		let mut tasks = self.lock();
+		tasks.held_by(id, attempt)?;
+		tasks.remove(id);
+		tasks.attempts.remove(&id);
+		Ok(1)
+	}
+
+	async fn schedule_task_retry(&self, id: TaskId, attempt: i64, backoff: Duration, error: &TaskError) -> Result<Task, AsyncQueueError> {
+		let now = self.now();
+		let error_info = error_info(error, now)?;
+		let mut tasks = self.lock();
+		let task = tasks.held_by(id, attempt)?;
+
+		// A fresh duplicate may have been enqueued while a pending scoped task was running
+		let duplicate_pending = task.uniq_scope == UniqueScope::Pending
+			&& task
+				.uniq_hash
+				.0
+				.as_ref()
+				.and_then(|uniq_hash| tasks.uniq_hashes.get(uniq_hash))
+				.map_or(false, |holder| *holder != id);
+
+		let task = tasks
+			.update(id, now, |task| {
+				task.error_info = error_info;
+				task.error_history.0.push(FailedAttempt {
+					attempt,
+					error: error.message.clone(),
+				});
+				if task.is_cancellation_requested() {
+					// The task was cancelled while running, it is not retried
+					task.done_at = OptionalSqliteDateTime(Some(now));
+					return task.clone();
+				}
+				task.running_at = OptionalSqliteDateTime(None);
+				task.lease_expires_at = OptionalSqliteDateTime(None);
+				task.retries += 1;
+				task.scheduled_at = SqliteDateTime(time_after(now.0, backoff));
+				if duplicate_pending {
+					task.uniq_hash = OptionalTaskHash(None);
+				}
+				task.clone()
+			})
+			.ok_or(AsyncQueueError::StaleAttempt(id, attempt))?;
+
+		Ok(task)
+	}
+
+	async fn cancel_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut tasks = self.lock();
+		if tasks.tasks.get(&id).map_or(true, |task| task.done_at.0.is_some()) {
+			return Ok(None);
+		}
+
+		let now = self.now();
+		Ok(tasks.update(id, now, |task| {
+			task.cancelled_at = OptionalSqliteDateTime(Some(task.cancelled_at.0.unwrap_or(now)));
+			if task.running_at.0.is_none() {
+				task.done_at = OptionalSqliteDateTime(Some(now));
+			}
+			task.clone()
+		}))
+	}
+
+	async fn renew_task_lease(&self, id: TaskId, attempt: i64, lease_duration: Duration) -> Result<bool, AsyncQueueError> {
+		let mut tasks = self.lock();
+		if !tasks.held_by(id, attempt).map_or(false, |task| task.state() == TaskState::Running) {
+			return Ok(false);
+		}
+
+		let now = self.now();
+		tasks.update(id, now, |task| {
+			task.lease_expires_at = OptionalSqliteDateTime(Some(SqliteDateTime(time_after(now.0, lease_duration))));
+		});
+		Ok(true)
+	}
+
+	async fn is_cancellation_requested(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
+		Ok(self.lock().tasks.get(&id).map_or(false, Task::is_cancellation_requested))
+	}
+
+	async fn get_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		Ok(self.lock().tasks.get(&id).cloned())
+	}
+
+	async fn list_tasks(&self, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError> {
+		Ok(self.lock().list(filter))
+	}
+
+	async fn prune_finished_tasks(&self, queue_name: &str, done_before: DateTime<Utc>, failed_before: DateTime<Utc>, limit: u32) -> Result<u64, AsyncQueueError> {
+		Ok(self.lock().prune_finished(queue_name, SqliteDateTime(done_before), SqliteDateTime(failed_before), limit))
+	}
+
+	async fn queue_stats(&self, queue_name: &str) -> Result<QueueStats, AsyncQueueError> {
+		Ok(self.lock().queue_stats(queue_name, self.now()))
+	}
+
+	async fn record_task_attempt(&self, attempt: &TaskAttempt) -> Result<(), AsyncQueueError> {
+		self.lock().record_attempt(attempt);
+		Ok(())
+	}
+
+	async fn task_attempts(&self, id: TaskId) -> Result<Vec<TaskAttempt>, AsyncQueueError> {
+		Ok(self.lock().attempts.get(&id).cloned().unwrap_or_default())
+	}
+
+	async fn retry(&self, id: TaskId, reset_retries: bool) -> Result<Option<Task>, AsyncQueueError> {
+		Ok(self.lock().retry(id, reset_retries, self.now()))
+	}
+
+	async fn retry_where(&self, filter: &TaskFilter, reset_retries: bool) -> Result<u64, AsyncQueueError> {
+		Ok(self.lock().retry_where(filter, reset_retries, self.now()))
+	}
+
+	async fn dead_letter_task(&self, id: TaskId, attempt: i64, error: &TaskError) -> Result<DeadTask, AsyncQueueError> {
+		self.lock().dead_letter(id, attempt, error, self.now())
+	}
+
+	async fn get_dead_task(&self, id: TaskId) -> Result<Option<DeadTask>, AsyncQueueError> {
+		Ok(self.lock().dead.get(&id).cloned())
+	}
+
+	async fn list_dead_tasks(&self, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError> {
+		Ok(self.lock().list_dead(filter))
+	}
+
+	async fn requeue_dead_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		Ok(self.lock().requeue_dead(id, self.now()))
+	}
+
+	async fn purge_dead_task(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let mut tasks = self.lock();
+		tasks.attempts.remove(&id);
+		Ok(tasks.remove_dead(id).is_some())
+	}
+
+	async fn purge_dead_tasks(&self, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+		Ok(self.lock().purge_expired_dead(queue_name, SqliteDateTime(dead_before)))
+	}
+
+	async fn enqueue_task<T: BackgroundTask>(&self, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		let new_task = NewTask::new(task)?.with_options(options);
+		Ok(self.lock().insert(new_task, self.now()))
+	}
+
+	async fn enqueue_with<T: BackgroundTask>(store: &mut Self::Connection, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		store.enqueue_task(task, options).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::sqlite_task::{CurrentTask, TaskErrorKind};
+	use crate::BackgroundTaskExt;
+	use async_trait::async_trait;
+
+	#[derive(serde::Serialize, serde::Deserialize)]
+	struct MemoryTask {
+		number: u16,
+	}
+
+	#[async_trait]
+	impl BackgroundTask for MemoryTask {
+		const TASK_NAME: &'static str = "memory_task";
+		type AppData = ();
+		type Error = ();
+
+		async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+			Ok(())
+		}
+
+		fn uniq(&self) -> Option<TaskHash> {
+			(self.number == 0).then(|| TaskHash::new("zero"))
+		}
+	}
+
+	fn task_names() -> Vec<String> {
+		vec![MemoryTask::TASK_NAME.to_string()]
+	}
+
+	#[tokio::test]
+	async fn tasks_are_pulled_oldest_first_once_due() {
+		let mut store = MemoryTaskStore::new();
+
+		let later = MemoryTask { number: 1 }.enqueue_in::<MemoryTaskStore>(&mut store, Duration::from_secs(3600)).await.unwrap();
+		let first = MemoryTask { number: 2 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap();
+		let second = MemoryTask { number: 3 }
+			.enqueue_at::<MemoryTaskStore>(&mut store, Utc::now() - chrono::Duration::seconds(10))
+			.await
+			.unwrap();
+
+		let pulled = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!(pulled.id, first);
+		assert_eq!(pulled.state(), TaskState::Running);
+		assert_eq!(pulled.attempt, 1);
+		let pulled = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!(pulled.id, second);
+
+		assert!(store.pull_next_task("default", None, None, &task_names()).await.unwrap().is_none());
+		assert!(store.pull_next_task("other", None, None, &task_names()).await.unwrap().is_none());
+		assert_eq!(store.get_task(later).await.unwrap().unwrap().state(), TaskState::Ready);
+	}
+
+	#[tokio::test]
+	async fn retried_task_waits_for_its_backoff() {
+		let mut store = MemoryTaskStore::new();
+		let id = MemoryTask { number: 1 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap();
+
+		let task = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		store
+			.schedule_task_retry(id, task.attempt, Duration::ZERO, &TaskError::new(TaskErrorKind::Execution, "failed", 0))
+			.await
+			.unwrap();
+		tokio::time::sleep(Duration::from_millis(5)).await;
+
+		let task = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!((task.id, task.retries, task.attempt), (id, 1, 2));
+
+		let task = store
+			.schedule_task_retry(id, task.attempt, Duration::from_secs(3600), &TaskError::new(TaskErrorKind::Execution, "failed", 0))
+			.await
+			.unwrap();
+		assert_eq!(task.retries, 2);
+		assert_eq!(task.state(), TaskState::Ready);
+		assert!(store.pull_next_task("default", None, None, &task_names()).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn scheduled_task_is_due_once_the_clock_reaches_it() {
+		let clock = crate::ManualClock::default();
+		let mut store = MemoryTaskStore::new().with_clock(clock.clone());
+
+		let id = MemoryTask { number: 1 }
+			.enqueue_at::<MemoryTaskStore>(&mut store, clock.now() + chrono::Duration::hours(1))
+			.await
+			.unwrap();
+		assert!(store.pull_next_task("default", None, None, &task_names()).await.unwrap().is_none());
+
+		clock.advance(Duration::from_secs(3600));
+		let task = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+		assert_eq!(task.running_at.0, Some(SqliteDateTime::at(clock.now()).0));
+	}
+
+	#[tokio::test]
+	async fn delayed_task_is_due_once_the_clock_passes_its_delay() {
+		let clock = crate::ManualClock::new(Utc::now() - chrono::Duration::days(1));
+		let mut store = MemoryTaskStore::new().with_clock(clock.clone());
+
+		let id = MemoryTask { number: 1 }.enqueue_in::<MemoryTaskStore>(&mut store, Duration::from_secs(3600)).await.unwrap();
+		let task = store.get_task(id).await.unwrap().unwrap();
+		assert_eq!(task.scheduled_at.0 - task.created_at.0, chrono::Duration::hours(1));
+		assert!(store.pull_next_task("default", None, None, &task_names()).await.unwrap().is_none());
+
+		clock.advance(Duration::from_secs(3601));
+		let task = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+	}
+
+	#[tokio::test]
+	async fn abandoned_task_is_claimed_again() {
+		let mut store = MemoryTaskStore::new();
+		let id = MemoryTask { number: 1 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap();
+
+		let stale = store.pull_next_task("default", None, Some(Duration::ZERO), &task_names()).await.unwrap().unwrap();
+		tokio::time::sleep(Duration::from_millis(5)).await;
+
+		let current = store.pull_next_task("default", None, Some(Duration::from_secs(60)), &task_names()).await.unwrap().unwrap();
+		assert_eq!(current.id, id);
+		assert!(store.pull_next_task("default", None, Some(Duration::from_secs(60)), &task_names()).await.unwrap().is_none());
+
+		let rejected = store.set_task_state(id, stale.attempt, TaskState::Done).await;
+		assert!(matches!(rejected, Err(AsyncQueueError::StaleAttempt(..))));
+		store.set_task_state(id, current.attempt, TaskState::Done).await.unwrap();
+		assert_eq!(store.get_task(id).await.unwrap().unwrap().state(), TaskState::Done);
+	}
+
+	#[tokio::test]
+	async fn unique_hash_is_released_once_finished() {
+		let mut store = MemoryTaskStore::new();
+
+		let id = MemoryTask { number: 0 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap();
+		assert_eq!(MemoryTask { number: 0 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap(), id);
+		assert_eq!(store.len(), 1);
+
+		let task = store.pull_next_task("default", None, None, &task_names()).await.unwrap().unwrap();
+		store.remove_task(id, task.attempt).await.unwrap();
+		assert!(store.is_empty());
+
+		assert_ne!(MemoryTask { number: 0 }.enqueue::<MemoryTaskStore>(&mut store).await.unwrap(), id);
+	}
+
+	mod conformance {
+		async fn store(clock: crate::ManualClock) -> super::MemoryTaskStore {
+			super::MemoryTaskStore::new().with_clock(clock)
+		}
+
+		crate::task_store_conformance_tests!(store);
+	}
+}
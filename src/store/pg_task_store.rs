@@ -1,73 +1,412 @@
-// use diesel::result::Error::QueryBuilderError;
-// use diesel_async::scoped_futures::ScopedFutureExt;
-// use diesel_async::AsyncConnection;
-// use diesel_async::{pg::AsyncPgConnection, pooled_connection::bb8::Pool};
-// use std::time::Duration;
-//
-// use crate::errors::AsyncQueueError;
-// use crate::{BackgroundTask, NewTask, Task, TaskId, TaskState, TaskStore};
-//
-// /// An async queue that is used to manipulate tasks, it uses PostgreSQL as storage.
-// #[derive(Debug, Clone)]
-// pub struct PgTaskStore {
-// 	pool: Pool<AsyncPgConnection>,
-// }
-//
-// impl PgTaskStore {
-// 	pub fn new(pool: Pool<AsyncPgConnection>) -> Self {
-// 		PgTaskStore { pool }
-// 	}
-// }
-//
-// #[async_trait::async_trait]
-// impl TaskStore for PgTaskStore {
-// 	type Connection = AsyncPgConnection;
-//
-// 	async fn pull_next_task(&self, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Result<Option<Task>, AsyncQueueError> {
-// 		let mut connection = self.pool.get().await.map_err(|e| QueryBuilderError(e.into()))?;
-// 		connection
-// 			.transaction::<Option<Task>, AsyncQueueError, _>(|conn| {
-// 				async move {
-// 					let Some(pending_task) = Task::fetch_next_pending(conn, queue_name, execution_timeout, task_names).await else {
-// 						return Ok(None);
-// 					};
-//
-// 					Task::set_running(conn, pending_task).await.map(Some)
-// 				}
-// 				.scope_boxed()
-// 			})
-// 			.await
-// 	}
-//
-// 	async fn set_task_state(&self, id: TaskId, state: TaskState) -> Result<(), AsyncQueueError> {
-// 		let mut connection = self.pool.get().await.map_err(|e| QueryBuilderError(e.into()))?;
-// 		match state {
-// 			TaskState::Done => {
-// 				Task::set_done(&mut connection, id).await?;
-// 			}
-// 			TaskState::Failed(error_msg) => {
-// 				Task::fail_with_message(&mut connection, id, &error_msg).await?;
-// 			}
-// 			_ => (),
-// 		};
-// 		Ok(())
-// 	}
-//
-// 	async fn remove_task(&self, id: TaskId) -> Result<u64, AsyncQueueError> {
-// 		let mut connection = self.pool.get().await.map_err(|e| QueryBuilderError(e.into()))?;
-// 		let result = Task::remove(&mut connection, id).await?;
-// 		Ok(result)
-// 	}
-//
-// 	async fn enqueue<T: BackgroundTask>(connection: &mut Self::Connection, task: T) -> Result<(), AsyncQueueError> {
-// 		let new_task = NewTask::new(task)?;
-// 		Task::insert(connection, new_task).await?;
-// 		Ok(())
-// 	}
-//
-// 	async fn schedule_task_retry(&self, id: TaskId, backoff: Duration, error: &str) -> Result<Task, AsyncQueueError> {
-// 		let mut connection = self.pool.get().await.map_err(|e| QueryBuilderError(e.into()))?;
-// 		let task = Task::schedule_retry(&mut connection, id, backoff, error).await?;
-// 		Ok(task)
-// 	}
-// }
+use crate::errors::AsyncQueueError;
+use crate::queries::pg;
+use crate::sqlite_task::{DeadTask, DeadTaskPage, EnqueueOptions, NewTask, Task, TaskAttempt, TaskError, TaskFilter, TaskId, TaskPage, TaskState};
+use crate::stats::QueueStats;
+use crate::{BackgroundTask, Clock, SystemClock, TaskStore};
+use chrono::{DateTime, Utc};
+use sqlx::{PgConnection, PgPool};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An async queue that uses `PostgreSQL` as storage for tasks.
+#[derive(Debug, Clone)]
+pub struct PgTaskStore {
+	pub pool: PgPool,
+	database_time: bool,
+	clock: Arc<dyn Clock>,
+}
+
+impl PgTaskStore {
+	pub fn new(pool: PgPool) -> Self {
+		Self {
+			pool,
+			database_time: false,
+			clock: Arc::new(SystemClock),
+		}
+	}
+
+	/// Set whether the current time is read from the database rather than from the clock of the
+	/// store, when enqueueing tasks with [`TaskStore::enqueue_task`], claiming and finalizing tasks.
+	///
+	/// Workers running on hosts whose clocks drift apart then agree on when scheduled tasks are
+	/// due and when leases and execution timeouts expire, at the cost of a query to read the time.
+	/// Tasks enqueued on a connection of the caller, see [`crate::BackgroundTaskExt`], are still
+	/// created at the time of the process enqueueing them. Disabled by default.
+	#[must_use]
+	pub const fn database_time(mut self, database_time: bool) -> Self {
+		self.database_time = database_time;
+		self
+	}
+
+	/// Set the clock the store tells the time with when it does not read it from the database,
+	/// enqueueing tasks with [`TaskStore::enqueue_task`] included, see [`Clock`].
+	#[must_use]
+	pub fn with_clock(mut self, clock: impl Clock) -> Self {
+		self.clock = Arc::new(clock);
+		self
+	}
+
+	/// The current time, according to the database if the store uses its clock.
+	async fn now(&self, connection: &mut PgConnection) -> Result<DateTime<Utc>, AsyncQueueError> {
+		if self.database_time {
+			pg::database_now(connection).await
+		} else {
+			Ok(self.clock.now())
+		}
+	}
+
+	/// Create a new `PostgreSQL` pool with the given connection string
+	pub async fn create(database_url: &str) -> Result<Self, sqlx::Error> {
+		let pool = PgPool::connect(database_url).await?;
+		Ok(Self::new(pool))
+	}
+
+	/// Apply the schema migrations bundled with this crate.
+	///
+	/// Migrations already applied to the database are skipped, so this is safe to call every time
+	/// the application starts.
+	pub async fn migrate(&self) -> Result<(), sqlx::Error> {
+		sqlx::migrate!("./pg_migrations").run(&self.pool).await?;
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl TaskStore for PgTaskStore {
+	type Connection = PgConnection;
+
+	fn clock(&self) -> Arc<dyn Clock> {
+		self.clock.clone()
+	}
+
+	async fn pull_next_task(
+		&self,
+		queue_name: &str,
+		execution_timeout: Option<Duration>,
+		lease_duration: Option<Duration>,
+		task_names: &[String],
+	) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::claim_next_pending(&mut conn, queue_name, execution_timeout, lease_duration, task_names, now).await
+	}
+
+	async fn set_task_state(&self, id: TaskId, attempt: i64, state: TaskState) -> Result<(), AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		match state {
+			TaskState::Done => {
+				pg::set_done(&mut conn, id, attempt, now).await?;
+			}
+			TaskState::Failed(error) => {
+				pg::fail_with_error(&mut conn, id, attempt, &error, now).await?;
+			}
+			TaskState::Cancelled => {
+				pg::set_cancelled(&mut conn, id, attempt, now).await?;
+			}
+			_ => (),
+		}
+		Ok(())
+	}
+
+	async fn remove_task(&self, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let result = pg::remove(&mut conn, id, attempt).await?;
+
+		Ok(result)
+	}
+
+	async fn cancel_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::cancel(&mut conn, id, now).await
+	}
+
+	async fn renew_task_lease(&self, id: TaskId, attempt: i64, lease_duration: Duration) -> Result<bool, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::renew_lease(&mut conn, id, attempt, lease_duration, now).await
+	}
+
+	async fn is_cancellation_requested(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::is_cancellation_requested(&mut conn, id).await
+	}
+
+	async fn get_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::find(&mut conn, id).await
+	}
+
+	async fn list_tasks(&self, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::list(&mut conn, filter).await
+	}
+
+	async fn prune_finished_tasks(&self, queue_name: &str, done_before: DateTime<Utc>, failed_before: DateTime<Utc>, limit: u32) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::prune_finished(&mut conn, queue_name, done_before, failed_before, limit).await
+	}
+
+	async fn queue_stats(&self, queue_name: &str) -> Result<QueueStats, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::queue_stats(&mut conn, queue_name, now).await
+	}
+
+	async fn record_task_attempt(&self, attempt: &TaskAttempt) -> Result<(), AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::insert_attempt(&mut conn, attempt).await
+	}
+
+	async fn task_attempts(&self, id: TaskId) -> Result<Vec<TaskAttempt>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::list_attempts(&mut conn, id).await
+	}
+
+	async fn retry(&self, id: TaskId, reset_retries: bool) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::retry(&mut conn, id, reset_retries, now).await
+	}
+
+	async fn retry_where(&self, filter: &TaskFilter, reset_retries: bool) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::retry_where(&mut conn, filter, reset_retries, now).await
+	}
+
+	async fn dead_letter_task(&self, id: TaskId, attempt: i64, error: &TaskError) -> Result<DeadTask, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::dead_letter(&mut conn, id, attempt, error, now).await
+	}
+
+	async fn get_dead_task(&self, id: TaskId) -> Result<Option<DeadTask>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::find_dead(&mut conn, id).await
+	}
+
+	async fn list_dead_tasks(&self, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::list_dead(&mut conn, filter).await
+	}
+
+	async fn requeue_dead_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		pg::requeue_dead(&mut conn, id, now).await
+	}
+
+	async fn purge_dead_task(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::purge_dead(&mut conn, id).await
+	}
+
+	async fn purge_dead_tasks(&self, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		pg::purge_expired_dead(&mut conn, queue_name, dead_before).await
+	}
+
+	async fn enqueue_task<T: BackgroundTask>(&self, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		let new_task = NewTask::new(task)?.with_options(options);
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		// The database time is read by the insert itself
+		let now = (!self.database_time).then(|| self.clock.now());
+		let task = pg::insert(&mut conn, new_task, now).await?;
+		Ok(task.id)
+	}
+
+	async fn enqueue_with<T: BackgroundTask>(connection: &mut Self::Connection, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		let new_task = NewTask::new(task)?.with_options(options);
+		let task = pg::insert(connection, new_task, Some(Utc::now())).await?;
+		Ok(task.id)
+	}
+
+	async fn schedule_task_retry(&self, id: TaskId, attempt: i64, backoff: Duration, error: &TaskError) -> Result<Task, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		let task = pg::schedule_retry(&mut conn, id, attempt, backoff, error, now).await?;
+		Ok(task)
+	}
+}
+
+/// These tests need a running Postgres, see `make db`, and are run with:
+/// `DATABASE_URL=postgres://... cargo test --features async_postgres -- --ignored --test-threads 1`
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::sqlite_task::{CurrentTask, TaskErrorKind};
+	use crate::BackgroundTaskExt;
+	use async_trait::async_trait;
+	use std::collections::BTreeSet;
+
+	#[derive(serde::Serialize, serde::Deserialize)]
+	struct PgTestTask {
+		number: u16,
+	}
+
+	#[async_trait]
+	impl BackgroundTask for PgTestTask {
+		const TASK_NAME: &'static str = "pg_test_task";
+		const QUEUE: &'static str = "pg_test_queue";
+		type AppData = ();
+		type Error = ();
+
+		async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+			Ok(())
+		}
+	}
+
+	async fn pg_task_store() -> PgTaskStore {
+		let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+		let store = PgTaskStore::create(&url).await.unwrap();
+		store.migrate().await.unwrap();
+		sqlx::query("DELETE FROM backie_tasks WHERE queue_name = $1")
+			.bind(PgTestTask::QUEUE)
+			.execute(&store.pool)
+			.await
+			.unwrap();
+		store
+	}
+
+	fn task_names() -> Vec<String> {
+		vec![PgTestTask::TASK_NAME.to_string()]
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn pull_next_task_marks_task_as_running() {
+		let store = pg_task_store().await;
+
+		let id = PgTestTask { number: 1 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+
+		let task = store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+		assert_eq!(task.state(), TaskState::Running);
+		assert_eq!(task.payload.0, serde_json::json!({"number": 1}));
+
+		assert!(store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn pull_next_task_ignores_unregistered_task_names() {
+		let store = pg_task_store().await;
+
+		PgTestTask { number: 1 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+
+		let pulled = store.pull_next_task(PgTestTask::QUEUE, None, None, &["other_task".to_string()]).await.unwrap();
+		assert!(pulled.is_none());
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn finished_tasks_are_not_pulled_again() {
+		let store = pg_task_store().await;
+
+		PgTestTask { number: 1 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+
+		let task = store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().unwrap();
+		store
+			.set_task_state(task.id, task.attempt, TaskState::Failed(TaskError::new(TaskErrorKind::Execution, "boom", 0)))
+			.await
+			.unwrap();
+
+		// An execution timeout must not resurrect finished tasks
+		let pulled = store.pull_next_task(PgTestTask::QUEUE, Some(Duration::ZERO), None, &task_names()).await.unwrap();
+		assert!(pulled.is_none());
+		assert_eq!(store.remove_task(task.id, task.attempt).await.unwrap(), 1);
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn schedule_task_retry_postpones_the_task() {
+		let store = pg_task_store().await;
+
+		PgTestTask { number: 1 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+
+		let task = store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().unwrap();
+		let task = store
+			.schedule_task_retry(task.id, task.attempt, Duration::from_secs(60), &TaskError::new(TaskErrorKind::Execution, "try again", 0))
+			.await
+			.unwrap();
+
+		assert_eq!(task.retries, 1);
+		assert_eq!(task.state(), TaskState::Ready);
+		assert!(store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn cancel_task_finishes_pending_tasks_and_flags_running_ones() {
+		let store = pg_task_store().await;
+
+		let running = PgTestTask { number: 1 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+		assert_eq!(store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().unwrap().id, running);
+
+		let pending = PgTestTask { number: 2 }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+
+		let cancelled = store.cancel_task(pending).await.unwrap().unwrap();
+		assert_eq!(cancelled.state(), TaskState::Cancelled);
+		assert!(store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap().is_none());
+
+		let flagged = store.cancel_task(running).await.unwrap().unwrap();
+		assert_eq!(flagged.state(), TaskState::Running);
+		assert!(store.is_cancellation_requested(running).await.unwrap());
+	}
+
+	#[tokio::test]
+	#[ignore]
+	async fn concurrent_pulls_never_claim_the_same_task() {
+		let store = pg_task_store().await;
+
+		for number in 0..50 {
+			PgTestTask { number }.enqueue::<PgTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+		}
+
+		let workers = (0..8).map(|_| {
+			let store = store.clone();
+			tokio::spawn(async move {
+				let mut claimed = Vec::new();
+				while let Some(task) = store.pull_next_task(PgTestTask::QUEUE, None, None, &task_names()).await.unwrap() {
+					claimed.push(task.id);
+				}
+				claimed
+			})
+		});
+
+		let mut claimed = Vec::new();
+		for worker in workers.collect::<Vec<_>>() {
+			claimed.extend(worker.await.unwrap());
+		}
+
+		let unique = claimed.iter().collect::<BTreeSet<_>>();
+		assert_eq!(claimed.len(), 50);
+		assert_eq!(unique.len(), 50);
+	}
+
+	mod conformance {
+		use crate::conformance::ConformanceTask;
+		use crate::BackgroundTask;
+
+		async fn store(clock: crate::ManualClock) -> super::PgTaskStore {
+			let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+			let store = super::PgTaskStore::create(&url).await.unwrap().with_clock(clock);
+			store.migrate().await.unwrap();
+			for table in ["backie_tasks", "backie_dead_tasks"] {
+				sqlx::query(&format!("DELETE FROM {table} WHERE queue_name = $1"))
+					.bind(ConformanceTask::QUEUE)
+					.execute(&store.pool)
+					.await
+					.unwrap();
+			}
+			store
+		}
+
+		crate::task_store_conformance_tests!(
+			#[ignore]
+			store
+		);
+	}
+}
@@ -1,83 +1,754 @@
 use crate::errors::AsyncQueueError;
-use crate::sqlite_task::{NewTask, Task, TaskId, TaskState};
-use crate::{BackgroundTask, TaskStore};
-use sqlx::{Acquire, SqliteConnection, SqlitePool};
+use crate::queries;
+use crate::sqlite_helpers::SqliteDateTime;
+use crate::sqlite_task::{DeadTask, DeadTaskPage, EnqueueOptions, NewTask, Task, TaskAttempt, TaskError, TaskFilter, TaskId, TaskPage, TaskState};
+use crate::stats::QueueStats;
+use crate::{BackgroundTask, Clock, SystemClock, TaskStore};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::sync::Arc;
 use std::time::Duration;
 
 /// An async queue that uses `SQLite` as storage for tasks.
 #[derive(Debug, Clone)]
 pub struct SqliteTaskStore {
 	pub pool: SqlitePool,
+	database_time: bool,
+	clock: Arc<dyn Clock>,
 }
 
 impl SqliteTaskStore {
 	#[allow(dead_code)]
-	pub const fn new(pool: SqlitePool) -> Self {
-		Self { pool }
+	pub fn new(pool: SqlitePool) -> Self {
+		Self {
+			pool,
+			database_time: false,
+			clock: Arc::new(SystemClock),
+		}
+	}
+
+	/// Set whether the current time is read from the database rather than from the clock of the
+	/// store, when enqueueing tasks with [`TaskStore::enqueue_task`], claiming and finalizing tasks.
+	///
+	/// `SQLite` runs in the process, so the database clock is the clock of the host, this is mostly
+	/// useful to keep the stores of an application interchangeable. Tasks enqueued on a connection of
+	/// the caller, see [`crate::BackgroundTaskExt`], are still created at the time of the process
+	/// enqueueing them. Disabled by default.
+	#[must_use]
+	pub const fn database_time(mut self, database_time: bool) -> Self {
+		self.database_time = database_time;
+		self
+	}
+
+	/// Set the clock the store tells the time with when it does not read it from the database,
+	/// enqueueing tasks with [`TaskStore::enqueue_task`] included, see [`Clock`].
+	#[must_use]
+	pub fn with_clock(mut self, clock: impl Clock) -> Self {
+		self.clock = Arc::new(clock);
+		self
+	}
+
+	/// The current time, according to the database if the store uses its clock.
+	async fn now(&self, connection: &mut SqliteConnection) -> Result<SqliteDateTime, AsyncQueueError> {
+		if self.database_time {
+			queries::database_now(connection).await
+		} else {
+			Ok(SqliteDateTime::at(self.clock.now()))
+		}
 	}
 
 	/// Create a new `SQLite` pool with the given connection string
+	///
+	/// The schema is not created, see [`SqliteTaskStore::migrate`].
 	#[allow(dead_code)]
 	pub async fn create(database_url: &str) -> Result<Self, sqlx::Error> {
 		let pool = SqlitePool::connect(database_url).await?;
 		Ok(Self::new(pool))
 	}
+
+	/// Create a store backed by a private in-memory database, with the schema already applied.
+	///
+	/// The database lives as long as the store, which makes it a good fit for tests.
+	pub async fn in_memory() -> Result<Self, sqlx::Error> {
+		// Every connection to `:memory:` opens a distinct database, so the pool keeps a single one alive
+		let pool = SqlitePoolOptions::new()
+			.max_connections(1)
+			.min_connections(1)
+			.idle_timeout(None)
+			.max_lifetime(None)
+			.connect_with(SqliteConnectOptions::new().in_memory(true))
+			.await?;
+		let store = Self::new(pool);
+		store.migrate().await?;
+		Ok(store)
+	}
+
+	/// Apply the schema migrations bundled with this crate.
+	///
+	/// Migrations already applied to the database are skipped, so this is safe to call every time
+	/// the application starts.
+	pub async fn migrate(&self) -> Result<(), sqlx::Error> {
+		sqlx::migrate!("./migrations").run(&self.pool).await?;
+		Ok(())
+	}
 }
 
 #[async_trait::async_trait]
 impl TaskStore for SqliteTaskStore {
 	type Connection = SqliteConnection;
 
-	async fn pull_next_task(&self, queue_name: &str, execution_timeout: Option<Duration>, task_names: &[String]) -> Result<Option<Task>, AsyncQueueError> {
-		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
-
-		let mut tx = conn.begin().await.map_err(AsyncQueueError::from)?;
-
-		let pending_task = match Task::fetch_next_pending(&mut tx, queue_name, execution_timeout, task_names).await {
-			Some(task) => task,
-			None => {
-				tx.commit().await.map_err(AsyncQueueError::from)?;
-				return Ok(None);
-			}
-		};
-
-		let result = Task::set_running(&mut tx, pending_task).await?;
-
-		tx.commit().await.map_err(AsyncQueueError::from)?;
+	fn clock(&self) -> Arc<dyn Clock> {
+		self.clock.clone()
+	}
 
-		Ok(Some(result))
+	async fn pull_next_task(
+		&self,
+		queue_name: &str,
+		execution_timeout: Option<Duration>,
+		lease_duration: Option<Duration>,
+		task_names: &[String],
+	) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::claim_next_pending(&mut conn, queue_name, execution_timeout, lease_duration, task_names, now).await
 	}
 
-	async fn set_task_state(&self, id: TaskId, state: TaskState) -> Result<(), AsyncQueueError> {
+	async fn set_task_state(&self, id: TaskId, attempt: i64, state: TaskState) -> Result<(), AsyncQueueError> {
 		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
 		match state {
 			TaskState::Done => {
-				Task::set_done(&mut conn, id).await?;
+				Task::set_done(&mut conn, id, attempt, now).await?;
+			}
+			TaskState::Failed(error) => {
+				Task::fail_with_error(&mut conn, id, attempt, &error, now).await?;
 			}
-			TaskState::Failed(error_msg) => {
-				Task::fail_with_message(&mut conn, id, &error_msg).await?;
+			TaskState::Cancelled => {
+				Task::set_cancelled(&mut conn, id, attempt, now).await?;
 			}
 			_ => (),
 		}
 		Ok(())
 	}
 
-	async fn remove_task(&self, id: TaskId) -> Result<u64, AsyncQueueError> {
+	async fn remove_task(&self, id: TaskId, attempt: i64) -> Result<u64, AsyncQueueError> {
 		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
-		let result = Task::remove(&mut conn, id).await?;
+		let result = Task::remove(&mut conn, id, attempt).await?;
 
 		Ok(result)
 	}
 
-	async fn enqueue<T: BackgroundTask>(connection: &mut Self::Connection, task: T) -> Result<(), AsyncQueueError> {
-		let new_task = NewTask::new(task)?;
-		Task::insert(connection, new_task).await?;
-		Ok(())
+	async fn cancel_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::cancel(&mut conn, id, now).await
+	}
+
+	async fn renew_task_lease(&self, id: TaskId, attempt: i64, lease_duration: Duration) -> Result<bool, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::renew_lease(&mut conn, id, attempt, lease_duration, now).await
 	}
 
-	async fn schedule_task_retry(&self, id: TaskId, backoff: Duration, error: &str) -> Result<Task, AsyncQueueError> {
+	async fn is_cancellation_requested(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
 		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
-		let task = Task::schedule_retry(&mut conn, id, backoff, error).await?;
+		Task::is_cancellation_requested(&mut conn, id).await
+	}
+
+	async fn get_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		Task::find(&mut conn, id).await
+	}
+
+	async fn list_tasks(&self, filter: &TaskFilter) -> Result<TaskPage, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		Task::list(&mut conn, filter).await
+	}
+
+	async fn prune_finished_tasks(&self, queue_name: &str, done_before: DateTime<Utc>, failed_before: DateTime<Utc>, limit: u32) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		Task::prune_finished(&mut conn, queue_name, done_before, failed_before, limit).await
+	}
+
+	async fn queue_stats(&self, queue_name: &str) -> Result<QueueStats, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::queue_stats(&mut conn, queue_name, now).await
+	}
+
+	async fn record_task_attempt(&self, attempt: &TaskAttempt) -> Result<(), AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		TaskAttempt::insert(&mut conn, attempt).await
+	}
+
+	async fn task_attempts(&self, id: TaskId) -> Result<Vec<TaskAttempt>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		TaskAttempt::list_for_task(&mut conn, id).await
+	}
+
+	async fn retry(&self, id: TaskId, reset_retries: bool) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::retry(&mut conn, id, reset_retries, now).await
+	}
+
+	async fn retry_where(&self, filter: &TaskFilter, reset_retries: bool) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		Task::retry_where(&mut conn, filter, reset_retries, now).await
+	}
+
+	async fn dead_letter_task(&self, id: TaskId, attempt: i64, error: &TaskError) -> Result<DeadTask, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		DeadTask::dead_letter(&mut conn, id, attempt, error, now).await
+	}
+
+	async fn get_dead_task(&self, id: TaskId) -> Result<Option<DeadTask>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		DeadTask::find(&mut conn, id).await
+	}
+
+	async fn list_dead_tasks(&self, filter: &TaskFilter) -> Result<DeadTaskPage, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		DeadTask::list(&mut conn, filter).await
+	}
+
+	async fn requeue_dead_task(&self, id: TaskId) -> Result<Option<Task>, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		DeadTask::requeue(&mut conn, id, now).await
+	}
+
+	async fn purge_dead_task(&self, id: TaskId) -> Result<bool, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		DeadTask::purge(&mut conn, id).await
+	}
+
+	async fn purge_dead_tasks(&self, queue_name: &str, dead_before: DateTime<Utc>) -> Result<u64, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		DeadTask::purge_expired(&mut conn, queue_name, dead_before).await
+	}
+
+	async fn enqueue_task<T: BackgroundTask>(&self, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		let new_task = NewTask::new(task)?.with_options(options);
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		// The database time is read by the insert itself
+		let now = (!self.database_time).then(|| SqliteDateTime::at(self.clock.now()));
+		let task = Task::insert(&mut conn, new_task, now).await?;
+		Ok(task.id)
+	}
+
+	async fn enqueue_with<T: BackgroundTask>(connection: &mut Self::Connection, task: T, options: EnqueueOptions) -> Result<TaskId, AsyncQueueError> {
+		let new_task = NewTask::new(task)?.with_options(options);
+		let task = Task::insert(connection, new_task, Some(SqliteDateTime::now())).await?;
+		Ok(task.id)
+	}
+
+	async fn schedule_task_retry(&self, id: TaskId, attempt: i64, backoff: Duration, error: &TaskError) -> Result<Task, AsyncQueueError> {
+		let mut conn = self.pool.acquire().await.map_err(AsyncQueueError::from)?;
+		let now = self.now(&mut conn).await?;
+		let task = Task::schedule_retry(&mut conn, id, attempt, backoff, error, now).await?;
 		Ok(task)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::sqlite_helpers::SqliteDateTime;
+	use crate::sqlite_task::{CurrentTask, TaskErrorKind, TaskHash};
+	use crate::{BackgroundTaskExt, UniqueScope};
+	use async_trait::async_trait;
+	use chrono::Utc;
+	use sqlx::sqlite::SqliteJournalMode;
+	use std::collections::BTreeSet;
+	use std::path::{Path, PathBuf};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+
+	#[derive(serde::Serialize, serde::Deserialize)]
+	struct ClaimedTask {
+		number: u16,
+	}
+
+	#[async_trait]
+	impl BackgroundTask for ClaimedTask {
+		const TASK_NAME: &'static str = "claimed_task";
+		type AppData = ();
+		type Error = ();
+
+		async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+			Ok(())
+		}
+	}
+
+	/// Opens a new pool on the database file, the way a separate worker process would.
+	async fn open_store(path: &Path) -> SqliteTaskStore {
+		let options = SqliteConnectOptions::new()
+			.filename(path)
+			.create_if_missing(true)
+			.journal_mode(SqliteJournalMode::Wal)
+			.busy_timeout(Duration::from_secs(30));
+		SqliteTaskStore::new(SqlitePool::connect_with(options).await.unwrap())
+	}
+
+	#[derive(serde::Serialize, serde::Deserialize)]
+	struct WebhookTask {
+		delivery: String,
+	}
+
+	#[async_trait]
+	impl BackgroundTask for WebhookTask {
+		const TASK_NAME: &'static str = "webhook_task";
+		type AppData = ();
+		type Error = ();
+
+		async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+			Ok(())
+		}
+
+		fn uniq(&self) -> Option<TaskHash> {
+			Some(TaskHash::new(&self.delivery))
+		}
+	}
+
+	#[derive(serde::Serialize, serde::Deserialize)]
+	struct SyncTask;
+
+	#[async_trait]
+	impl BackgroundTask for SyncTask {
+		const TASK_NAME: &'static str = "sync_task";
+		const UNIQUE_SCOPE: UniqueScope = UniqueScope::Pending;
+		type AppData = ();
+		type Error = ();
+
+		async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+			Ok(())
+		}
+
+		fn uniq(&self) -> Option<TaskHash> {
+			Some(TaskHash::new("sync"))
+		}
+	}
+
+	fn temp_database_path() -> PathBuf {
+		std::env::temp_dir().join(format!("backie-{}.db", uuid::Uuid::new_v4()))
+	}
+
+	async fn migrated_store(path: &Path) -> SqliteTaskStore {
+		let store = open_store(path).await;
+		store.migrate().await.unwrap();
+		store
+	}
+
+	async fn remove_database(store: SqliteTaskStore, path: &Path) {
+		store.pool.close().await;
+		for suffix in ["", "-wal", "-shm"] {
+			let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+		}
+	}
+
+	async fn count_tasks(store: &SqliteTaskStore) -> i64 {
+		sqlx::query_scalar("SELECT COUNT(*) FROM backie_tasks").fetch_one(&store.pool).await.unwrap()
+	}
+
+	#[tokio::test]
+	async fn migrate_can_run_again_on_a_migrated_database() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+
+		store.migrate().await.unwrap();
+		assert_eq!(count_tasks(&store).await, 0);
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn in_memory_store_is_ready_to_use() {
+		let store = SqliteTaskStore::in_memory().await.unwrap();
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+
+		let id = ClaimedTask { number: 1 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), Utc::now() - chrono::Duration::seconds(1))
+			.await
+			.unwrap();
+		let task = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+
+		// Each in-memory store has its own database
+		let other = SqliteTaskStore::in_memory().await.unwrap();
+		assert_eq!(count_tasks(&other).await, 0);
+	}
+
+	#[tokio::test]
+	async fn uniq_task_is_enqueued_once_until_finished() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![WebhookTask::TASK_NAME.to_string()];
+
+		let mut ids = Vec::new();
+		for _ in 0..3 {
+			let id = WebhookTask {
+				delivery: "delivery-1".to_string(),
+			}
+			.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap())
+			.await
+			.unwrap();
+			ids.push(id);
+		}
+		assert_eq!(count_tasks(&store).await, 1);
+		assert!(ids.iter().all(|id| *id == ids[0]));
+
+		// Still unique while running
+		let task = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+		let id = WebhookTask {
+			delivery: "delivery-1".to_string(),
+		}
+		.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap())
+		.await
+		.unwrap();
+		assert_eq!(id, task.id);
+		assert_eq!(count_tasks(&store).await, 1);
+
+		// Can be enqueued again once finished
+		store.set_task_state(task.id, task.attempt, TaskState::Done).await.unwrap();
+		let id = WebhookTask {
+			delivery: "delivery-1".to_string(),
+		}
+		.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap())
+		.await
+		.unwrap();
+		assert_ne!(id, task.id);
+		assert_eq!(count_tasks(&store).await, 2);
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn pending_scoped_task_can_be_enqueued_while_running() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![SyncTask::TASK_NAME.to_string()];
+
+		SyncTask.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+		let running = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+
+		SyncTask.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+		SyncTask.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap()).await.unwrap();
+		assert_eq!(count_tasks(&store).await, 2);
+
+		// Retrying the running task must not clash with the pending duplicate
+		let retried = store
+			.schedule_task_retry(running.id, running.attempt, Duration::ZERO, &TaskError::new(TaskErrorKind::Execution, "failed", 0))
+			.await
+			.unwrap();
+		assert!(retried.uniq_hash.0.is_none());
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn delayed_task_is_pulled_once_its_scheduled_time_passed() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+
+		let delayed = ClaimedTask { number: 1 }
+			.enqueue_in::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), Duration::from_secs(3600))
+			.await
+			.unwrap();
+		assert!(store.pull_next_task("default", None, None, &task_names).await.unwrap().is_none());
+
+		let due = ClaimedTask { number: 2 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), Utc::now() - chrono::Duration::seconds(1))
+			.await
+			.unwrap();
+		let pulled = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+		assert_eq!(pulled.id, due);
+		assert_ne!(pulled.id, delayed);
+		assert!(store.pull_next_task("default", None, None, &task_names).await.unwrap().is_none());
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn cancel_task_finishes_pending_tasks_and_flags_running_ones() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+		let due = Utc::now() - chrono::Duration::seconds(1);
+
+		let running = ClaimedTask { number: 1 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), due)
+			.await
+			.unwrap();
+		let claimed = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+		assert_eq!(claimed.id, running);
+
+		let pending = ClaimedTask { number: 2 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), due)
+			.await
+			.unwrap();
+
+		let cancelled = store.cancel_task(pending).await.unwrap().unwrap();
+		assert_eq!(cancelled.state(), TaskState::Cancelled);
+		assert!(store.pull_next_task("default", None, None, &task_names).await.unwrap().is_none());
+		assert!(store.cancel_task(pending).await.unwrap().is_none());
+
+		assert!(!store.is_cancellation_requested(running).await.unwrap());
+		let flagged = store.cancel_task(running).await.unwrap().unwrap();
+		assert_eq!(flagged.state(), TaskState::Running);
+		assert!(store.is_cancellation_requested(running).await.unwrap());
+
+		store.set_task_state(running, claimed.attempt, TaskState::Cancelled).await.unwrap();
+		assert!(store.cancel_task(running).await.unwrap().is_none());
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn running_task_is_reclaimed_only_once_its_lease_expired() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+		let lease = Some(Duration::from_secs(3600));
+
+		let id = ClaimedTask { number: 1 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), Utc::now() - chrono::Duration::seconds(1))
+			.await
+			.unwrap();
+		let task = store.pull_next_task("default", None, lease, &task_names).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+		assert!(task.lease_expires_at.0.is_some());
+
+		// A healthy long running task is not stolen, even past the execution timeout
+		assert!(store.pull_next_task("default", Some(Duration::ZERO), lease, &task_names).await.unwrap().is_none());
+		assert!(store.renew_task_lease(id, task.attempt, Duration::from_secs(3600)).await.unwrap());
+
+		// The worker executing the task is gone and its lease expired
+		sqlx::query("UPDATE backie_tasks SET lease_expires_at = ? WHERE id = ?")
+			.bind(SqliteDateTime(Utc::now() - chrono::Duration::seconds(10)))
+			.bind(id)
+			.execute(&store.pool)
+			.await
+			.unwrap();
+		let reclaimed = store.pull_next_task("default", None, lease, &task_names).await.unwrap().unwrap();
+		assert_eq!(reclaimed.id, id);
+
+		store.set_task_state(id, reclaimed.attempt, TaskState::Done).await.unwrap();
+		assert!(!store.renew_task_lease(id, reclaimed.attempt, Duration::from_secs(3600)).await.unwrap());
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn stale_attempt_cannot_finalize_a_reclaimed_task() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+
+		ClaimedTask { number: 1 }
+			.enqueue_at::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), Utc::now() - chrono::Duration::seconds(1))
+			.await
+			.unwrap();
+		let stale = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+
+		// Claimed again once the execution timeout passed, while the first worker is still around
+		sqlx::query("UPDATE backie_tasks SET running_at = ? WHERE id = ?")
+			.bind(SqliteDateTime(Utc::now() - chrono::Duration::seconds(10)))
+			.bind(stale.id)
+			.execute(&store.pool)
+			.await
+			.unwrap();
+		let current = store.pull_next_task("default", Some(Duration::from_secs(5)), None, &task_names).await.unwrap().unwrap();
+		assert_eq!(current.id, stale.id);
+		assert_eq!(current.attempt, stale.attempt + 1);
+
+		let rejected = store
+			.set_task_state(stale.id, stale.attempt, TaskState::Failed(TaskError::new(TaskErrorKind::Execution, "too late", 0)))
+			.await;
+		assert!(matches!(rejected, Err(AsyncQueueError::StaleAttempt(id, attempt)) if id == stale.id && attempt == stale.attempt));
+		let rejected = store
+			.schedule_task_retry(stale.id, stale.attempt, Duration::ZERO, &TaskError::new(TaskErrorKind::Execution, "too late", 0))
+			.await;
+		assert!(matches!(rejected, Err(AsyncQueueError::StaleAttempt(..))));
+		assert!(matches!(store.remove_task(stale.id, stale.attempt).await, Err(AsyncQueueError::StaleAttempt(..))));
+		assert!(!store.renew_task_lease(stale.id, stale.attempt, Duration::from_secs(60)).await.unwrap());
+
+		store.set_task_state(current.id, current.attempt, TaskState::Done).await.unwrap();
+		assert_eq!(count_tasks(&store).await, 1);
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn enqueue_options_override_task_defaults() {
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+
+		let options = EnqueueOptions::new()
+			.queue("vip")
+			.max_retries(7)
+			.backoff_mode(crate::BackoffMode::NoBackoff)
+			.timeout(Duration::from_secs(5));
+		let id = ClaimedTask { number: 1 }
+			.enqueue_with::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap(), options)
+			.await
+			.unwrap();
+
+		assert!(store.pull_next_task("default", None, None, &task_names).await.unwrap().is_none());
+		let task = store.pull_next_task("vip", None, None, &task_names).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+		assert_eq!(task.max_retries, 7);
+		assert_eq!(task.backoff_mode, crate::BackoffMode::NoBackoff);
+		assert_eq!(task.timeout_msecs, 5_000);
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn concurrent_workers_claim_each_attempt_exactly_once() {
+		const TASKS: usize = 100;
+		const WORKERS: usize = 8;
+
+		let path = temp_database_path();
+		let store = migrated_store(&path).await;
+
+		for number in 0..TASKS {
+			ClaimedTask { number: number as u16 }
+				.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap())
+				.await
+				.unwrap();
+		}
+
+		let finished = Arc::new(AtomicUsize::new(0));
+		let task_names = vec![ClaimedTask::TASK_NAME.to_string()];
+
+		let mut workers = Vec::new();
+		for _ in 0..WORKERS {
+			let store = open_store(&path).await;
+			let finished = finished.clone();
+			let task_names = task_names.clone();
+			workers.push(tokio::spawn(async move {
+				let mut claims = Vec::new();
+				while finished.load(Ordering::SeqCst) < TASKS {
+					match store.pull_next_task("default", None, None, &task_names).await.unwrap() {
+						// Every task fails its first attempt, so each row is claimed twice
+						Some(task) if task.retries == 0 => {
+							claims.push((task.id, task.retries));
+							store
+								.schedule_task_retry(task.id, task.attempt, Duration::ZERO, &TaskError::new(TaskErrorKind::Execution, "first attempt fails", 0))
+								.await
+								.unwrap();
+						}
+						Some(task) => {
+							claims.push((task.id, task.retries));
+							store.set_task_state(task.id, task.attempt, TaskState::Done).await.unwrap();
+							finished.fetch_add(1, Ordering::SeqCst);
+						}
+						None => tokio::time::sleep(Duration::from_millis(10)).await,
+					}
+				}
+				claims
+			}));
+		}
+
+		let mut claims = Vec::new();
+		for worker in workers {
+			claims.extend(worker.await.unwrap());
+		}
+
+		let unique_claims = claims.iter().collect::<BTreeSet<_>>();
+		assert_eq!(claims.len(), TASKS * 2);
+		assert_eq!(unique_claims.len(), TASKS * 2, "some attempt was claimed by more than one worker");
+
+		remove_database(store, &path).await;
+	}
+
+	#[tokio::test]
+	async fn database_time_is_the_time_of_the_host() {
+		let store = SqliteTaskStore::in_memory().await.unwrap().database_time(true);
+		let mut conn = store.pool.acquire().await.unwrap();
+
+		let drift = store.now(&mut conn).await.unwrap().0 - Utc::now();
+		assert!(drift.num_milliseconds().abs() < 1000, "the database is {drift} away from the host");
+	}
+
+	#[tokio::test]
+	async fn tasks_are_enqueued_at_the_database_time() {
+		let clock = crate::ManualClock::new(Utc::now() - chrono::Duration::days(1));
+		let store = SqliteTaskStore::in_memory().await.unwrap().database_time(true).with_clock(clock);
+		let id = store.enqueue_task(ClaimedTask { number: 1 }, EnqueueOptions::new()).await.unwrap();
+		let delayed = store
+			.enqueue_task(ClaimedTask { number: 2 }, EnqueueOptions::new().scheduled_in(Duration::from_secs(3600)))
+			.await
+			.unwrap();
+
+		let task = store.get_task(id).await.unwrap().unwrap();
+		let drift = task.created_at.0 - Utc::now();
+		assert!(drift.num_milliseconds().abs() < 1000, "the task was created {drift} away from the database");
+		assert_eq!(task.scheduled_at, task.created_at);
+		let task = store.get_task(delayed).await.unwrap().unwrap();
+		assert_eq!(task.scheduled_at.0 - task.created_at.0, chrono::Duration::hours(1));
+	}
+
+	#[tokio::test]
+	async fn expired_lease_is_claimed_again_once_the_clock_passes_it() {
+		let clock = crate::ManualClock::default();
+		let store = SqliteTaskStore::in_memory().await.unwrap().with_clock(clock.clone());
+		// enqueued with the time of the system, which is already past the clock
+		let id = ClaimedTask { number: 1 }
+			.enqueue::<SqliteTaskStore>(&mut store.pool.acquire().await.unwrap())
+			.await
+			.unwrap();
+		clock.advance(Duration::from_secs(1));
+
+		let task_names = [ClaimedTask::TASK_NAME.to_string()];
+		let stale = store.pull_next_task("default", None, Some(Duration::from_secs(60)), &task_names).await.unwrap().unwrap();
+		assert_eq!(stale.id, id);
+		assert!(store.pull_next_task("default", None, Some(Duration::from_secs(60)), &task_names).await.unwrap().is_none());
+
+		clock.advance(Duration::from_secs(61));
+		let current = store.pull_next_task("default", None, Some(Duration::from_secs(60)), &task_names).await.unwrap().unwrap();
+		assert_eq!((current.id, current.attempt), (id, stale.attempt + 1));
+	}
+
+	#[tokio::test]
+	async fn claims_and_retries_ignore_the_clock_with_database_time() {
+		let clock = crate::ManualClock::new(Utc::now() + chrono::Duration::days(1));
+		let store = SqliteTaskStore::in_memory().await.unwrap().database_time(true).with_clock(clock);
+		let task_names = [ClaimedTask::TASK_NAME.to_string()];
+
+		let later = EnqueueOptions::new().scheduled_at(Utc::now() + chrono::Duration::hours(1));
+		store.enqueue_task(ClaimedTask { number: 1 }, later).await.unwrap();
+		assert!(
+			store.pull_next_task("default", None, None, &task_names).await.unwrap().is_none(),
+			"a task was claimed at the time of the clock"
+		);
+
+		let due = EnqueueOptions::new().scheduled_at(Utc::now() - chrono::Duration::seconds(1));
+		let id = store.enqueue_task(ClaimedTask { number: 2 }, due).await.unwrap();
+		let task = store.pull_next_task("default", None, None, &task_names).await.unwrap().unwrap();
+		assert_eq!(task.id, id);
+		let drift = task.running_at.0.unwrap().0 - Utc::now();
+		assert!(drift.num_milliseconds().abs() < 1000, "the task was claimed {drift} away from the database");
+
+		let retried = store
+			.schedule_task_retry(id, task.attempt, Duration::from_secs(60), &TaskError::new(TaskErrorKind::Execution, "failed", 0))
+			.await
+			.unwrap();
+		let drift = retried.scheduled_at.0 - (Utc::now() + chrono::Duration::seconds(60));
+		assert!(drift.num_milliseconds().abs() < 1000, "the retry was scheduled {drift} away from the database");
+	}
+
+	mod conformance {
+		async fn store(clock: crate::ManualClock) -> super::SqliteTaskStore {
+			super::SqliteTaskStore::in_memory().await.unwrap().with_clock(clock)
+		}
+
+		crate::task_store_conformance_tests!(store);
+	}
+}
@@ -1,16 +1,20 @@
+use crate::backoff::BackoffFn;
 use crate::catch_unwind::CatchUnwindFuture;
+use crate::clock::Clock;
 use crate::errors::{AsyncQueueError, BackieError};
-use crate::runnable::BackgroundTask;
-use crate::sqlite_helpers::JsonField;
-use crate::sqlite_task::{CurrentTask, Task, TaskState};
+use crate::runnable::{BackgroundTask, ErrorClass};
+use crate::sqlite_helpers::{JsonField, SqliteDateTime};
+use crate::sqlite_task::{AttemptOutcome, CurrentTask, Task, TaskAttempt, TaskError, TaskErrorKind, TaskState};
 use crate::store::TaskStore;
-use crate::{QueueConfig, RetentionMode};
+use crate::{BackoffMode, QueueConfig, RetentionMode};
 use futures::future::FutureExt;
 use futures::select;
 use std::collections::BTreeMap;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 pub type ExecuteTaskFn<AppData> = Arc<dyn Fn(CurrentTask, JsonField, AppData) -> Pin<Box<dyn Future<Output = Result<(), TaskExecError>> + Send>> + Send + Sync>;
 
@@ -21,11 +25,53 @@ pub enum TaskExecError {
 	#[error("Task deserialization failed: {0}")]
 	TaskDeserializationFailed(#[from] serde_json::Error),
 
-	#[error("Task execution failed: {0}")]
-	ExecutionFailed(String),
+	#[error("Task execution failed: {message}")]
+	ExecutionFailed { message: String, class: ErrorClass },
 
-	#[error("Task panicked with: {0}")]
-	Panicked(String),
+	#[error("Task panicked with: {message}")]
+	Panicked {
+		message: String,
+		location: Option<String>,
+		backtrace: Option<String>,
+	},
+
+	#[error("Task timed out after {0:?}")]
+	Timeout(Duration),
+
+	#[error("Task was cancelled")]
+	Cancelled,
+}
+
+impl TaskExecError {
+	/// The record of this error kept by the store for the given attempt of a task, failed at the
+	/// given time.
+	pub(crate) fn to_task_error(&self, attempt: i64, failed_at: SqliteDateTime) -> TaskError {
+		let kind = match self {
+			Self::TaskDeserializationFailed(_) => TaskErrorKind::Deserialization,
+			Self::ExecutionFailed { .. } => TaskErrorKind::Execution,
+			Self::Panicked { .. } => TaskErrorKind::Panic,
+			Self::Timeout(_) => TaskErrorKind::Timeout,
+			Self::Cancelled => TaskErrorKind::Cancelled,
+		};
+		let mut error = TaskError::new(kind, self.to_string(), attempt);
+		error.failed_at = Some(failed_at.0);
+		if let Self::Panicked { location, backtrace, .. } = self {
+			error.location = location.clone();
+			error.backtrace = backtrace.clone();
+		}
+		error
+	}
+
+	/// Whether the task that failed with this error should be retried.
+	///
+	/// A payload that cannot be deserialized stays so however many times it is retried.
+	pub(crate) const fn class(&self) -> ErrorClass {
+		match self {
+			Self::TaskDeserializationFailed(_) => ErrorClass::Permanent,
+			Self::ExecutionFailed { class, .. } => *class,
+			Self::Panicked { .. } | Self::Timeout(_) | Self::Cancelled => ErrorClass::Retryable,
+		}
+	}
 }
 
 pub fn runnable<BT>(task_info: CurrentTask, payload: JsonField, app_context: BT::AppData) -> Pin<Box<dyn Future<Output = Result<(), TaskExecError>> + Send>>
@@ -36,23 +82,56 @@ where
 		let background_task: BT = serde_json::from_value(payload.0)?;
 		match background_task.run(task_info, app_context).await {
 			Ok(()) => Ok(()),
-			Err(err) => Err(TaskExecError::ExecutionFailed(format!("{err:?}"))),
+			Err(err) => Err(TaskExecError::ExecutionFailed {
+				message: format!("{err:?}"),
+				class: background_task.classify_error(&err),
+			}),
 		}
 	})
 }
 
+/// How long a retried task waited after its previous attempt failed.
+fn previous_backoff(task: &Task) -> Option<Duration> {
+	if task.retries == 0 {
+		return None;
+	}
+	let error = serde_json::from_value::<TaskError>(task.error_info.0.clone()?).ok()?;
+	(task.scheduled_at.0 - error.failed_at?).to_std().ok()
+}
+
+/// Maximum execution time of the task, a non-positive `timeout_msecs` means no limit.
+fn task_timeout(task: &Task) -> Option<Duration> {
+	u64::try_from(task.timeout_msecs).ok().filter(|msecs| *msecs > 0).map(Duration::from_millis)
+}
+
+/// Interval that first ticks once a full period has elapsed.
+fn ticker(period: Duration) -> tokio::time::Interval {
+	let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	interval
+}
+
 /// Worker that executes tasks.
 pub struct Worker<AppData, S>
 where
 	AppData: Clone + Send + 'static,
 	S: TaskStore + Clone,
 {
+	/// Identifies the worker in the attempts it records, see [`TaskAttempt::worker_id`].
+	id: String,
+
 	store: S,
 
+	/// The clock of the store, which tells when the attempts finished.
+	clock: Arc<dyn Clock>,
+
 	config: QueueConfig,
 
 	task_registry: BTreeMap<String, ExecuteTaskFn<AppData>>,
 
+	/// The backoff strategies of [`BackoffMode::Custom`] by name.
+	backoff_strategies: BTreeMap<String, BackoffFn>,
+
 	app_data_fn: StateFn<AppData>,
 
 	/// Notification for the worker to stop.
@@ -65,16 +144,21 @@ where
 	S: TaskStore + Clone,
 {
 	pub(crate) fn new(
+		id: String,
 		store: S,
 		config: QueueConfig,
 		task_registry: BTreeMap<String, ExecuteTaskFn<AppData>>,
+		backoff_strategies: BTreeMap<String, BackoffFn>,
 		app_data_fn: StateFn<AppData>,
 		shutdown: Option<tokio::sync::watch::Receiver<()>>,
 	) -> Self {
 		Self {
+			id,
+			clock: store.clock(),
 			store,
 			config,
 			task_registry,
+			backoff_strategies,
 			app_data_fn,
 			shutdown,
 		}
@@ -90,9 +174,19 @@ where
 				}
 			};
 
-			match self.store.pull_next_task(&self.config.name, self.config.execution_timeout, &registered_task_names).await? {
+			match self
+				.store
+				.pull_next_task(&self.config.name, self.config.execution_timeout, self.config.lease_duration, &registered_task_names)
+				.await?
+			{
 				Some(task) => {
-					self.run(task).await?;
+					let (id, attempt) = (task.id, task.attempt);
+					match self.run(task).await {
+						Err(BackieError::QueueProcessingError(AsyncQueueError::StaleAttempt(..))) => {
+							log::warn!("Task {id} was pulled again while attempt {attempt} was running, the outcome of attempt {attempt} is discarded");
+						}
+						result => result?,
+					}
 				}
 				None => {
 					// Listen to watchable future
@@ -119,41 +213,70 @@ where
 	}
 
 	async fn run(&self, task: Task) -> Result<(), BackieError> {
+		if task.is_cancellation_requested() {
+			// Cancelled while running on a worker that is gone, nothing left to stop
+			log::debug!("Task {} was cancelled before it could be resumed", task.id);
+			return self.finalize_task(task, Err(TaskExecError::Cancelled)).await;
+		}
+
 		let task_info = CurrentTask::new(&task);
+		let cancellation = task_info.cancellation_token();
 		let runnable_task_caller = self
 			.task_registry
 			.get(&task.task_name)
 			.ok_or_else(|| AsyncQueueError::TaskNotRegistered(task.task_name.clone()))?;
 
 		// catch panics
-		let result: Result<(), TaskExecError> = CatchUnwindFuture::create({
+		let execution = CatchUnwindFuture::create({
 			let task_payload = task.payload.clone();
 			let app_data = (self.app_data_fn)();
 			let runnable_task_caller = runnable_task_caller.clone();
 			async move { runnable_task_caller(task_info, task_payload, app_data).await }
-		})
-		.await
-		.and_then(|result| {
-			result?;
-			Ok(())
 		});
+
+		// the task future is dropped once its timeout elapses
+		let timeout = task_timeout(&task);
+		let execution = async move {
+			match timeout {
+				Some(timeout) => tokio::time::timeout(timeout, execution).await.unwrap_or_else(|_| Err(TaskExecError::Timeout(timeout))),
+				None => execution.await,
+			}
+			.and_then(|result| {
+				result?;
+				Ok(())
+			})
+		};
+		let result = self.supervise(&task, execution, cancellation).await;
+		self.record_attempt(&task, &result).await;
 		log::info!("begin setting up finalize_task...");
 
 		match &result {
-			Ok(()) => self.finalize_task(task, result).await?,
+			Ok(()) | Err(TaskExecError::Cancelled) => self.finalize_task(task, result).await?,
 			Err(error) => {
 				log::error!("matched some error! {:?}", error);
-				if task.retries < task.max_retries {
-					let retries_i32 = i32::try_from(task.retries).unwrap();
-					let backoff = task.backoff_mode.next_attempt(retries_i32);
-
-					log::debug!("Task {} failed to run and will be retried in {} seconds", task.id, backoff.as_secs());
-
-					let error_message = format!("{error}");
-
-					self.store.schedule_task_retry(task.id, backoff, &error_message).await?;
+				let backoff = match error.class() {
+					ErrorClass::Permanent => {
+						log::debug!("Task {} failed with a permanent error and will not be retried", task.id);
+						None
+					}
+					_ if task.retries >= task.max_retries => {
+						log::debug!("Task {} failed and reached the maximum retries", task.id);
+						None
+					}
+					ErrorClass::RetryAfter(delay) => Some(delay),
+					ErrorClass::Retryable => Some(self.backoff(&task)),
+				};
+
+				if let Some(backoff) = backoff {
+					log::debug!("Task {} failed to run and will be retried in {:?}", task.id, backoff);
+
+					self.store
+						.schedule_task_retry(task.id, task.attempt, backoff, &error.to_task_error(task.attempt, self.now()))
+						.await?;
+				} else if self.config.dead_letter_queue {
+					log::debug!("Moving task {} to the dead-letter queue", task.id);
+					self.store.dead_letter_task(task.id, task.attempt, &error.to_task_error(task.attempt, self.now())).await?;
 				} else {
-					log::debug!("Task {} failed and reached the maximum retries", task.id);
 					self.finalize_task(task, result).await?;
 				}
 			}
@@ -161,31 +284,127 @@ where
 		Ok(())
 	}
 
+	/// Drives the execution of a task, renewing its lease and watching the store for its cancellation.
+	///
+	/// Once cancellation is requested the task token is triggered, and the task is given the
+	/// cancellation grace period of the queue to stop before its future is dropped.
+	async fn supervise<F>(&self, task: &Task, execution: F, cancellation: CancellationToken) -> Result<(), TaskExecError>
+	where
+		F: Future<Output = Result<(), TaskExecError>>,
+	{
+		let (id, attempt) = (task.id, task.attempt);
+		tokio::pin!(execution);
+
+		let lease_duration = self.config.lease_duration;
+		let mut polling = ticker(self.config.cancellation_poll_interval);
+		// renewed well before it expires, so a slow store does not cost the lease
+		let mut heartbeat = ticker(lease_duration.map_or(self.config.cancellation_poll_interval, |lease| lease / 3));
+
+		loop {
+			tokio::select! {
+				result = &mut execution => return result,
+				_ = polling.tick() => match self.store.is_cancellation_requested(id).await {
+					Ok(true) => break,
+					Ok(false) => {}
+					Err(error) => log::warn!("Failed to check whether task {id} was cancelled: {error}"),
+				},
+				_ = heartbeat.tick(), if lease_duration.is_some() => {
+					let lease_duration = lease_duration.unwrap_or_default();
+					match self.store.renew_task_lease(id, attempt, lease_duration).await {
+						Ok(true) => log::trace!("Renewed the lease of task {id} for {lease_duration:?}"),
+						Ok(false) => log::warn!("Task {id} is not held by attempt {attempt} anymore, its lease could not be renewed"),
+						Err(error) => log::warn!("Failed to renew the lease of task {id}: {error}"),
+					}
+				}
+			}
+		}
+
+		log::debug!("Task {id} was cancelled, waiting up to {:?} for it to stop", self.config.cancellation_grace_period);
+		cancellation.cancel();
+		if tokio::time::timeout(self.config.cancellation_grace_period, execution).await.is_err() {
+			log::warn!("Task {id} did not stop within its cancellation grace period and was aborted");
+		}
+		Err(TaskExecError::Cancelled)
+	}
+
+	/// Delay before retrying a task that just failed, according to its backoff mode.
+	fn backoff(&self, task: &Task) -> Duration {
+		if let BackoffMode::Custom { name } = &task.backoff_mode {
+			if let Some(strategy) = self.backoff_strategies.get(name.as_ref()) {
+				return strategy(task);
+			}
+			log::warn!("Backoff strategy {name} of task {} is not registered, falling back to the default backoff", task.id);
+		}
+		task.backoff_mode.next_attempt(task.retries, previous_backoff(task))
+	}
+
+	/// The current time of the clock of the store.
+	fn now(&self) -> SqliteDateTime {
+		SqliteDateTime::at(self.clock.now())
+	}
+
+	/// Records the outcome of the attempt in the history of the task.
+	///
+	/// The history is informative only, failing to record it does not fail the task.
+	async fn record_attempt(&self, task: &Task, result: &Result<(), TaskExecError>) {
+		let (outcome, error) = match result {
+			Ok(()) => (AttemptOutcome::Done, None),
+			Err(TaskExecError::Cancelled) => (AttemptOutcome::Cancelled, None),
+			Err(error @ TaskExecError::Timeout(_)) => (AttemptOutcome::TimedOut, Some(format!("{error}"))),
+			Err(error @ TaskExecError::Panicked { .. }) => (AttemptOutcome::Panicked, Some(format!("{error}"))),
+			Err(error) => (AttemptOutcome::Failed, Some(format!("{error}"))),
+		};
+		let attempt = TaskAttempt {
+			task_id: task.id,
+			attempt: task.attempt,
+			worker_id: self.id.clone(),
+			started_at: task.running_at.0.unwrap_or_else(|| self.now()),
+			finished_at: self.now(),
+			outcome,
+			error,
+		};
+		if let Err(error) = self.store.record_task_attempt(&attempt).await {
+			log::warn!("Failed to record attempt {} of task {}: {error}", task.attempt, task.id);
+		}
+	}
+
 	async fn finalize_task(&self, task: Task, result: Result<(), TaskExecError>) -> Result<(), BackieError> {
 		log::info!("finalize task called...");
 		match self.config.retention_mode {
-			RetentionMode::KeepAll => match result {
+			RetentionMode::KeepAll | RetentionMode::KeepFor { .. } => match result {
 				Ok(()) => {
-					self.store.set_task_state(task.id, TaskState::Done).await?;
+					self.store.set_task_state(task.id, task.attempt, TaskState::Done).await?;
 					log::debug!("Task {} done and kept in the database", task.id);
 				}
+				Err(TaskExecError::Cancelled) => {
+					log::debug!("Task {} cancelled and kept in the database", task.id);
+					self.store.set_task_state(task.id, task.attempt, TaskState::Cancelled).await?;
+				}
 				Err(error) => {
 					log::debug!("Task {} failed and kept in the database", task.id);
-					self.store.set_task_state(task.id, TaskState::Failed(format!("{error}"))).await?;
+					self.store
+						.set_task_state(task.id, task.attempt, TaskState::Failed(error.to_task_error(task.attempt, self.now())))
+						.await?;
 				}
 			},
 			RetentionMode::RemoveAll => {
 				log::debug!("Task {} finalized and deleted from the database", task.id);
-				self.store.remove_task(task.id).await?;
+				self.store.remove_task(task.id, task.attempt).await?;
 			}
 			RetentionMode::RemoveDone => match result {
 				Ok(()) => {
 					log::debug!("Task {} done and deleted from the database", task.id);
-					self.store.remove_task(task.id).await?;
+					self.store.remove_task(task.id, task.attempt).await?;
+				}
+				Err(TaskExecError::Cancelled) => {
+					log::debug!("Task {} cancelled and kept in the database", task.id);
+					self.store.set_task_state(task.id, task.attempt, TaskState::Cancelled).await?;
 				}
 				Err(error) => {
 					log::debug!("Task {} failed and kept in the database", task.id);
-					self.store.set_task_state(task.id, TaskState::Failed(format!("{error}"))).await?;
+					self.store
+						.set_task_state(task.id, task.attempt, TaskState::Failed(error.to_task_error(task.attempt, self.now())))
+						.await?;
 				}
 			},
 		};
@@ -1,5 +1,8 @@
+use crate::backoff::BackoffFn;
 use crate::errors::BackieError;
+use crate::janitor::Janitor;
 use crate::runnable::BackgroundTask;
+use crate::sqlite_task::Task;
 use crate::store::TaskStore;
 use crate::worker::{runnable, ExecuteTaskFn};
 use crate::worker::{StateFn, Worker};
@@ -30,6 +33,9 @@ where
 	/// The types of task the worker pool can execute and the loaders for them.
 	task_registry: BTreeMap<String, ExecuteTaskFn<AppData>>,
 
+	/// The backoff strategies of [`crate::BackoffMode::Custom`] by name.
+	backoff_strategies: BTreeMap<String, BackoffFn>,
+
 	/// The queue names for the registered tasks.
 	queue_tasks: BTreeMap<String, Vec<String>>,
 
@@ -51,6 +57,7 @@ where
 			task_store,
 			application_data_fn: Arc::new(application_data_fn),
 			task_registry: BTreeMap::new(),
+			backoff_strategies: BTreeMap::new(),
 			queue_tasks: BTreeMap::new(),
 			worker_queues: BTreeMap::new(),
 		}
@@ -66,6 +73,19 @@ where
 		self
 	}
 
+	/// Register the backoff strategy of the tasks whose backoff mode is
+	/// [`crate::BackoffMode::Custom`] with the given name.
+	///
+	/// The strategy is given the task that just failed, with the number of retries it already had,
+	/// and returns how long to wait before retrying it.
+	pub fn register_backoff<F>(mut self, name: impl ToString, strategy: F) -> Self
+	where
+		F: Fn(&Task) -> Duration + Send + Sync + 'static,
+	{
+		self.backoff_strategies.insert(name.to_string(), Arc::new(strategy));
+		self
+	}
+
 	pub fn configure_queue(mut self, config: QueueConfig) -> Self {
 		self.worker_queues.insert(config.name.clone(), config);
 		self
@@ -86,17 +106,22 @@ where
 
 		let mut worker_handles = Vec::new();
 
+		// Tells apart the workers of pools running on several processes in the attempts they record
+		let pool_id = uuid::Uuid::new_v4().simple().to_string()[..8].to_string();
+
 		// Spawn all individual workers per queue
 		for (queue_name, queue_config) in &self.worker_queues {
 			for idx in 0..queue_config.num_workers {
+				let worker_name = format!("worker-{queue_name}-{idx}@{pool_id}");
 				let mut worker: Worker<AppData, S> = Worker::new(
+					worker_name.clone(),
 					self.task_store.clone(),
 					queue_config.to_owned(),
 					self.task_registry.clone(),
+					self.backoff_strategies.clone(),
 					self.application_data_fn.clone(),
 					Some(rx.clone()),
 				);
-				let worker_name = format!("worker-{queue_name}-{idx}");
 				// grabs the join handle for every worker for graceful shutdown
 				let join_handle = tokio::spawn(async move {
 					match worker.run_tasks().await {
@@ -106,6 +131,10 @@ where
 				});
 				worker_handles.push(join_handle);
 			}
+
+			if let Some(janitor) = Janitor::new(self.task_store.clone(), queue_config, rx.clone()) {
+				worker_handles.push(tokio::spawn(janitor.run()));
+			}
 		}
 
 		Ok(tokio::spawn(async move {
@@ -146,7 +175,8 @@ where
 ///     .num_workers(5)
 ///     .retention_mode(RetentionMode::KeepAll)
 ///     .execution_timeout(Duration::from_secs(60))
-///     .pull_interval(Duration::from_secs(1));
+///     .pull_interval(Duration::from_secs(1))
+///     .cancellation_grace_period(Duration::from_secs(5));
 /// ```
 /// Example of queue configuration with default options:
 /// ```
@@ -161,7 +191,13 @@ pub struct QueueConfig {
 	pub(crate) num_workers: u32,
 	pub(crate) retention_mode: RetentionMode,
 	pub(crate) execution_timeout: Option<Duration>,
+	pub(crate) lease_duration: Option<Duration>,
 	pub(crate) pull_interval: Duration,
+	pub(crate) cancellation_poll_interval: Duration,
+	pub(crate) cancellation_grace_period: Duration,
+	pub(crate) janitor_interval: Duration,
+	pub(crate) janitor_batch_size: u32,
+	pub(crate) dead_letter_queue: bool,
 }
 
 impl QueueConfig {
@@ -172,7 +208,13 @@ impl QueueConfig {
 			num_workers: 1,
 			retention_mode: RetentionMode::default(),
 			execution_timeout: None,
+			lease_duration: None,
 			pull_interval: Duration::from_secs(1),
+			cancellation_poll_interval: Duration::from_secs(1),
+			cancellation_grace_period: Duration::from_secs(10),
+			janitor_interval: Duration::from_secs(60),
+			janitor_batch_size: 500,
+			dead_letter_queue: false,
 		}
 	}
 
@@ -192,14 +234,27 @@ impl QueueConfig {
 
 	/// Set the execution timeout for this queue.
 	///
-	/// This is the maximum time a task can run before it is considered failed and ready to retry.
-	/// If this is not set, the task may run indefinitely.
+	/// This is the maximum time a task without a lease can run before it is considered failed and
+	/// ready to retry. Tasks claimed with a lease are only retried once their lease expires, see
+	/// [`QueueConfig::lease_duration`]. If this is not set, a task without a lease may run
+	/// indefinitely.
 	#[must_use]
 	pub const fn execution_timeout(mut self, execution_timeout: Duration) -> Self {
 		self.execution_timeout = Some(execution_timeout);
 		self
 	}
 
+	/// Set the lease duration for this queue.
+	///
+	/// Workers hold a lease on the tasks they execute and renew it while the task runs. A task whose
+	/// lease expired, because the process executing it crashed, is claimed again by another worker.
+	/// Not set by default, tasks are then only claimed again after the execution timeout.
+	#[must_use]
+	pub const fn lease_duration(mut self, lease_duration: Duration) -> Self {
+		self.lease_duration = Some(lease_duration);
+		self
+	}
+
 	/// Set the pull interval for this queue.
 	///
 	/// This is the interval at which the queue will be checking for new tasks by calling
@@ -209,6 +264,55 @@ impl QueueConfig {
 		self.pull_interval = pull_interval;
 		self
 	}
+
+	/// Set the cancellation poll interval for this queue.
+	///
+	/// This is the interval at which a running task is checked for a cancellation request.
+	#[must_use]
+	pub const fn cancellation_poll_interval(mut self, cancellation_poll_interval: Duration) -> Self {
+		self.cancellation_poll_interval = cancellation_poll_interval;
+		self
+	}
+
+	/// Set the cancellation grace period for this queue.
+	///
+	/// This is the time a cancelled task is given to stop by itself before it is aborted.
+	#[must_use]
+	pub const fn cancellation_grace_period(mut self, cancellation_grace_period: Duration) -> Self {
+		self.cancellation_grace_period = cancellation_grace_period;
+		self
+	}
+
+	/// Set the janitor interval for this queue.
+	///
+	/// With [`RetentionMode::KeepFor`], this is the interval at which the finished tasks whose
+	/// retention period is over are removed. Defaults to 1 minute.
+	#[must_use]
+	pub const fn janitor_interval(mut self, janitor_interval: Duration) -> Self {
+		self.janitor_interval = janitor_interval;
+		self
+	}
+
+	/// Set the janitor batch size for this queue.
+	///
+	/// Expired tasks are removed by batches of at most this many tasks, so removing a large
+	/// backlog does not hold the store for long. Defaults to 500.
+	#[must_use]
+	pub const fn janitor_batch_size(mut self, janitor_batch_size: u32) -> Self {
+		self.janitor_batch_size = if janitor_batch_size == 0 { 1 } else { janitor_batch_size };
+		self
+	}
+
+	/// Set whether tasks that exhausted their retries are moved to the dead-letter queue.
+	///
+	/// Dead tasks are kept apart from the tasks of the queue until they are requeued or purged,
+	/// see [`crate::TaskStore::requeue_dead_task`]. When disabled, they are finalized as failed
+	/// according to the retention mode of the queue instead. Disabled by default.
+	#[must_use]
+	pub const fn dead_letter_queue(mut self, dead_letter_queue: bool) -> Self {
+		self.dead_letter_queue = dead_letter_queue;
+		self
+	}
 }
 
 impl<S> From<S> for QueueConfig
@@ -223,13 +327,13 @@ where
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use crate::store::test_store::MemoryTaskStore;
+	use crate::sqlite_task::{CurrentTask, TaskState};
+	use crate::store::MemoryTaskStore;
+	#[cfg(feature = "async_postgres")]
 	use crate::store::PgTaskStore;
-	use crate::task::CurrentTask;
-	use crate::BackgroundTaskExt;
+	use crate::{BackgroundTaskExt, Clock, ErrorClass, ManualClock, TaskErrorKind};
 	use async_trait::async_trait;
-	use diesel_async::pooled_connection::{bb8::Pool, AsyncDieselConnectionManager};
-	use diesel_async::AsyncPgConnection;
+	use chrono::SubsecRound;
 	use futures::FutureExt;
 	use std::sync::atomic::{AtomicBool, Ordering};
 	use tokio::sync::Mutex;
@@ -531,6 +635,7 @@ mod tests {
 			}
 		}
 
+		crate::install_panic_hook();
 		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
 
 		let mut task_store = memory_store();
@@ -545,13 +650,633 @@ mod tests {
 			.unwrap();
 
 		// Enqueue a task that will panic
-		BrokenTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		let id = BrokenTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		let raw_task = task_store.get_task(id).await.unwrap().unwrap();
+		let error: crate::TaskError = serde_json::from_value(raw_task.error_info.0.unwrap()).unwrap();
+		assert_eq!((error.kind, error.message.as_str()), (crate::TaskErrorKind::Panic, "Task panicked with: Oh no!"));
+		assert_eq!(error.attempt, raw_task.attempt);
+		assert!(error.failed_at.is_some());
+		assert!(
+			error.location.map_or(false, |location| location.starts_with("src/worker_pool.rs:")),
+			"the panic location was not kept"
+		);
+	}
+
+	#[tokio::test]
+	async fn task_exceeding_its_timeout_is_dead_lettered() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct HangingTask;
+
+		#[async_trait]
+		impl BackgroundTask for HangingTask {
+			const TASK_NAME: &'static str = "hanging_task";
+			const MAX_RETRIES: i32 = 0;
+			const TIMEOUT: Duration = Duration::from_millis(50);
+			type AppData = ();
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+				futures::future::pending::<()>().await;
+				Ok(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<HangingTask>()
+			.configure_queue(QueueConfig::new("default").pull_interval(Duration::from_millis(10)).dead_letter_queue(true))
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let id = HangingTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let dead_task = loop {
+			if let Some(dead_task) = task_store.get_dead_task(id).await.unwrap() {
+				break dead_task;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		};
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		assert!(task_store.get_task(id).await.unwrap().is_none());
+		assert_eq!(dead_task.error_history.last().unwrap().error, "Task timed out after 50ms");
+		assert_eq!(dead_task.error().map(|error| error.kind), Some(TaskErrorKind::Timeout));
+	}
+
+	#[tokio::test]
+	async fn lease_of_long_running_task_is_renewed() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct LongTask;
+
+		#[async_trait]
+		impl BackgroundTask for LongTask {
+			const TASK_NAME: &'static str = "long_task";
+			type AppData = Arc<tokio::sync::Notify>;
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, started: Self::AppData) -> Result<(), ()> {
+				started.notify_one();
+				tokio::time::sleep(Duration::from_millis(1000)).await;
+				Ok(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+		let started = Arc::new(tokio::sync::Notify::new());
+		let lease_duration = Duration::from_millis(300);
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), {
+			let started = started.clone();
+			move || started.clone()
+		})
+		.register_task_type::<LongTask>()
+		.configure_queue(
+			QueueConfig::new("default")
+				.retention_mode(RetentionMode::KeepAll)
+				.pull_interval(Duration::from_millis(10))
+				.lease_duration(lease_duration),
+		)
+		.start(async move {
+			should_stop.await.unwrap();
+		})
+		.await
+		.unwrap();
+
+		let id = LongTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		started.notified().await;
+
+		// The task outlives its initial lease, another worker must never be able to claim it
+		let task_names = vec![LongTask::TASK_NAME.to_string()];
+		for _ in 0..8 {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			let stolen = task_store.pull_next_task("default", None, Some(lease_duration), &task_names).await.unwrap();
+			assert!(stolen.is_none(), "Task with a live lease was claimed again");
+		}
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		let task = task_store.get_task(id).await.unwrap().unwrap();
+		assert_eq!(task.state(), TaskState::Done);
+	}
+
+	#[tokio::test]
+	async fn running_task_stops_once_cancelled() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct CancellableTask;
+
+		#[async_trait]
+		impl BackgroundTask for CancellableTask {
+			const TASK_NAME: &'static str = "cancellable_task";
+			type AppData = Arc<tokio::sync::Notify>;
+			type Error = ();
+
+			async fn run(&self, task: CurrentTask, started: Self::AppData) -> Result<(), ()> {
+				started.notify_one();
+				task.cancellation_token().cancelled().await;
+				Ok(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+		let started = Arc::new(tokio::sync::Notify::new());
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), {
+			let started = started.clone();
+			move || started.clone()
+		})
+		.register_task_type::<CancellableTask>()
+		.configure_queue(
+			QueueConfig::new("default")
+				.retention_mode(RetentionMode::KeepAll)
+				.pull_interval(Duration::from_millis(10))
+				.cancellation_poll_interval(Duration::from_millis(10)),
+		)
+		.start(async move {
+			should_stop.await.unwrap();
+		})
+		.await
+		.unwrap();
+
+		let id = CancellableTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		started.notified().await;
+
+		let task = task_store.cancel_task(id).await.unwrap().unwrap();
+		assert_eq!(task.state(), TaskState::Running);
+
+		// The worker pool waits for the task to stop
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		let task = task_store.get_task(id).await.unwrap().unwrap();
+		assert_eq!(task.state(), TaskState::Cancelled);
+	}
+
+	#[tokio::test]
+	async fn cancelled_task_is_aborted_after_its_grace_period() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct StubbornTask;
+
+		#[async_trait]
+		impl BackgroundTask for StubbornTask {
+			const TASK_NAME: &'static str = "stubborn_task";
+			type AppData = Arc<tokio::sync::Notify>;
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, started: Self::AppData) -> Result<(), ()> {
+				started.notify_one();
+				futures::future::pending::<()>().await;
+				Ok(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+		let started = Arc::new(tokio::sync::Notify::new());
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), {
+			let started = started.clone();
+			move || started.clone()
+		})
+		.register_task_type::<StubbornTask>()
+		.configure_queue(
+			QueueConfig::new("default")
+				.pull_interval(Duration::from_millis(10))
+				.cancellation_poll_interval(Duration::from_millis(10))
+				.cancellation_grace_period(Duration::from_millis(50)),
+		)
+		.start(async move {
+			should_stop.await.unwrap();
+		})
+		.await
+		.unwrap();
+
+		let id = StubbornTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		started.notified().await;
+		task_store.cancel_task(id).await.unwrap().unwrap();
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		let task = task_store.get_task(id).await.unwrap().unwrap();
+		assert_eq!(task.state(), TaskState::Cancelled);
+		assert_eq!(task.retries, 0);
+	}
+
+	#[tokio::test]
+	async fn janitor_prunes_tasks_once_their_retention_is_over() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct OutcomeTask {
+			fail: bool,
+		}
+
+		#[async_trait]
+		impl BackgroundTask for OutcomeTask {
+			const TASK_NAME: &'static str = "outcome_task";
+			const MAX_RETRIES: i32 = 0;
+			type AppData = ();
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+				if self.fail {
+					Err(())
+				} else {
+					Ok(())
+				}
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<OutcomeTask>()
+			.configure_queue(
+				QueueConfig::new("default")
+					.retention_mode(RetentionMode::KeepFor {
+						done: Duration::ZERO,
+						failed: Duration::from_secs(3600),
+					})
+					.pull_interval(Duration::from_millis(10))
+					.janitor_interval(Duration::from_millis(10))
+					.dead_letter_queue(false),
+			)
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let done = OutcomeTask { fail: false }.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		let failed = OutcomeTask { fail: true }.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		for _ in 0..100 {
+			let done_pruned = task_store.get_task(done).await.unwrap().is_none();
+			let failed_finished = task_store.get_task(failed).await.unwrap().map_or(false, |task| task.done_at.0.is_some());
+			if done_pruned && failed_finished {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		assert!(task_store.get_task(done).await.unwrap().is_none(), "Done task was kept past its retention");
+		let failed_task = task_store.get_task(failed).await.unwrap().unwrap();
+		assert!(matches!(failed_task.state(), TaskState::Failed(_)));
+	}
+
+	#[tokio::test]
+	async fn task_exhausting_its_retries_is_dead_lettered_and_can_be_requeued() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct FlakyTask;
+
+		#[async_trait]
+		impl BackgroundTask for FlakyTask {
+			const TASK_NAME: &'static str = "flaky_task";
+			const MAX_RETRIES: i32 = 1;
+			const BACKOFF_MODE: crate::BackoffMode = crate::BackoffMode::NoBackoff;
+			type AppData = Arc<AtomicBool>;
+			type Error = String;
+
+			async fn run(&self, task: CurrentTask, healthy: Self::AppData) -> Result<(), String> {
+				if healthy.load(Ordering::SeqCst) {
+					Ok(())
+				} else {
+					Err(format!("attempt with {} retries failed", task.retry_count()))
+				}
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let mut task_store = memory_store();
+		let healthy = Arc::new(AtomicBool::new(false));
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), {
+			let healthy = healthy.clone();
+			move || healthy.clone()
+		})
+		.register_task_type::<FlakyTask>()
+		.configure_queue(QueueConfig::new("default").pull_interval(Duration::from_millis(10)).dead_letter_queue(true))
+		.start(async move {
+			should_stop.await.unwrap();
+		})
+		.await
+		.unwrap();
+
+		let id = FlakyTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let dead_task = loop {
+			if let Some(dead_task) = task_store.get_dead_task(id).await.unwrap() {
+				break dead_task;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		};
+		assert!(task_store.get_task(id).await.unwrap().is_none());
+		assert_eq!(dead_task.retries, 1);
+		let errors = dead_task.error_history.0.iter().map(|failed| failed.error.as_str()).collect::<Vec<_>>();
+		assert_eq!(
+			errors,
+			[
+				"Task execution failed: \"attempt with 0 retries failed\"",
+				"Task execution failed: \"attempt with 1 retries failed\""
+			]
+		);
+
+		let attempts = task_store.task_attempts(id).await.unwrap();
+		assert_eq!(attempts.len(), 2);
+		for (attempt, failed) in attempts.iter().zip(&dead_task.error_history.0) {
+			assert_eq!(
+				(attempt.attempt, attempt.outcome, attempt.error.as_deref()),
+				(failed.attempt, crate::AttemptOutcome::Failed, Some(failed.error.as_str()))
+			);
+			assert!(attempt.worker_id.starts_with("worker-default-0@"), "unexpected worker id {}", attempt.worker_id);
+		}
+
+		healthy.store(true, Ordering::SeqCst);
+		let requeued = task_store.requeue_dead_task(id).await.unwrap().unwrap();
+		assert_eq!(requeued.retries, 0);
+		assert!(task_store.get_dead_task(id).await.unwrap().is_none());
+
+		for _ in 0..100 {
+			if task_store.get_task(id).await.unwrap().is_none() {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		assert!(task_store.task_attempts(id).await.unwrap().is_empty(), "the attempts of a removed task were kept");
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		assert!(task_store.get_task(id).await.unwrap().is_none(), "Requeued task was not executed");
+		assert!(task_store.get_dead_task(id).await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn failed_task_is_retried_once_the_clock_passes_its_backoff() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct FailsOnceTask;
+
+		#[async_trait]
+		impl BackgroundTask for FailsOnceTask {
+			const TASK_NAME: &'static str = "fails_once_task";
+			const MAX_RETRIES: i32 = 1;
+			const BACKOFF_MODE: crate::BackoffMode = crate::BackoffMode::ExponentialBackoff;
+			type AppData = ();
+			type Error = String;
+
+			async fn run(&self, task: CurrentTask, _context: Self::AppData) -> Result<(), String> {
+				if task.retry_count() == 0 {
+					Err("first attempt failed".to_string())
+				} else {
+					Ok(())
+				}
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let clock = ManualClock::default();
+		let mut task_store = memory_store().with_clock(clock.clone());
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<FailsOnceTask>()
+			.configure_queue(QueueConfig::new("default").retention_mode(RetentionMode::KeepAll).pull_interval(Duration::from_millis(10)))
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let id = FailsOnceTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let failed_at = loop {
+			let task = task_store.get_task(id).await.unwrap().unwrap();
+			if task.retries == 1 {
+				break serde_json::from_value::<crate::TaskError>(task.error_info.0.unwrap()).unwrap().failed_at;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		};
+		assert_eq!(failed_at, Some(clock.now().trunc_subsecs(3)), "the failure was not timed by the clock of the store");
+
+		// the retry waits for its backoff of 2 seconds on the clock, however long the test waits
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(task_store.get_task(id).await.unwrap().unwrap().state(), TaskState::Ready);
+
+		clock.advance(Duration::from_secs(2));
+		for _ in 0..100 {
+			if task_store.get_task(id).await.unwrap().unwrap().state() == TaskState::Done {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		assert_eq!(task_store.get_task(id).await.unwrap().unwrap().state(), TaskState::Done);
+		let attempts = task_store.task_attempts(id).await.unwrap();
+		assert_eq!(attempts.len(), 2);
+		assert_eq!(attempts[1].finished_at.0 - attempts[0].finished_at.0, chrono::Duration::seconds(2));
+	}
+
+	#[tokio::test]
+	async fn failed_task_waits_for_its_custom_backoff() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct RateLimitedTask;
+
+		#[async_trait]
+		impl BackgroundTask for RateLimitedTask {
+			const TASK_NAME: &'static str = "rate_limited_task";
+			const BACKOFF_MODE: crate::BackoffMode = crate::BackoffMode::Custom {
+				name: std::borrow::Cow::Borrowed("hourly"),
+			};
+			type AppData = ();
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+				Err(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let clock = ManualClock::default();
+		let mut task_store = memory_store().with_clock(clock.clone());
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<RateLimitedTask>()
+			.register_backoff("hourly", |task: &Task| Duration::from_secs(3600 * (task.retries.unsigned_abs() + 1)))
+			.configure_queue(QueueConfig::new("default").pull_interval(Duration::from_millis(10)))
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let id = RateLimitedTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let task = loop {
+			let task = task_store.get_task(id).await.unwrap().unwrap();
+			if task.retries == 1 {
+				break task;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		};
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		assert_eq!(task.scheduled_at.0 - clock.now().trunc_subsecs(3), chrono::Duration::hours(1));
+	}
+
+	#[tokio::test]
+	async fn permanent_errors_are_not_retried() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct ImportTask {
+			rows: u32,
+		}
+
+		#[async_trait]
+		impl BackgroundTask for ImportTask {
+			const TASK_NAME: &'static str = "import_task";
+			const BACKOFF_MODE: crate::BackoffMode = crate::BackoffMode::NoBackoff;
+			type AppData = ();
+			type Error = String;
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), String> {
+				Err(format!("{} rows is too many", self.rows))
+			}
+
+			fn classify_error(&self, _error: &String) -> ErrorClass {
+				ErrorClass::Permanent
+			}
+		}
+
+		/// An older version of the task, whose payload the current version cannot read.
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct LegacyImportTask {
+			rows: String,
+		}
+
+		#[async_trait]
+		impl BackgroundTask for LegacyImportTask {
+			const TASK_NAME: &'static str = "import_task";
+			type AppData = ();
+			type Error = ();
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), ()> {
+				Ok(())
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let mut task_store = memory_store();
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<ImportTask>()
+			.configure_queue(QueueConfig::new("default").pull_interval(Duration::from_millis(10)).dead_letter_queue(true))
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let failing = ImportTask { rows: 1000 }.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+		let unreadable = LegacyImportTask { rows: "many".to_string() }.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let mut dead_tasks = Vec::new();
+		for id in [failing, unreadable] {
+			let dead_task = loop {
+				if let Some(dead_task) = task_store.get_dead_task(id).await.unwrap() {
+					break dead_task;
+				}
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			};
+			dead_tasks.push(dead_task);
+		}
+
+		notify_stop_worker_pool.send(()).unwrap();
+		worker_pool_finished.await.unwrap();
+
+		for dead_task in &dead_tasks {
+			assert_eq!((dead_task.retries, dead_task.error_history.0.len()), (0, 1), "task {} was retried", dead_task.id);
+		}
+		assert_eq!(dead_tasks[0].error_history.0[0].error, "Task execution failed: \"1000 rows is too many\"");
+		assert!(dead_tasks[1].error_history.0[0].error.starts_with("Task deserialization failed"));
+	}
+
+	#[tokio::test]
+	async fn retry_after_error_overrides_the_backoff() {
+		#[derive(Clone, serde::Serialize, serde::Deserialize)]
+		struct ThrottledTask;
+
+		#[async_trait]
+		impl BackgroundTask for ThrottledTask {
+			const TASK_NAME: &'static str = "throttled_task";
+			const BACKOFF_MODE: crate::BackoffMode = crate::BackoffMode::NoBackoff;
+			type AppData = ();
+			type Error = Duration;
+
+			async fn run(&self, _task: CurrentTask, _context: Self::AppData) -> Result<(), Duration> {
+				Err(Duration::from_secs(600))
+			}
+
+			fn classify_error(&self, retry_in: &Duration) -> ErrorClass {
+				ErrorClass::RetryAfter(*retry_in)
+			}
+		}
+
+		let (notify_stop_worker_pool, should_stop) = tokio::sync::oneshot::channel();
+
+		let clock = ManualClock::default();
+		let mut task_store = memory_store().with_clock(clock.clone());
+
+		let worker_pool_finished = WorkerPool::new(task_store.clone(), || ())
+			.register_task_type::<ThrottledTask>()
+			.configure_queue(QueueConfig::new("default").pull_interval(Duration::from_millis(10)))
+			.start(async move {
+				should_stop.await.unwrap();
+			})
+			.await
+			.unwrap();
+
+		let id = ThrottledTask.enqueue::<MemoryTaskStore>(&mut task_store).await.unwrap();
+
+		let task = loop {
+			let task = task_store.get_task(id).await.unwrap().unwrap();
+			if task.retries == 1 {
+				break task;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		};
 
 		notify_stop_worker_pool.send(()).unwrap();
 		worker_pool_finished.await.unwrap();
 
-		let raw_task = task_store.tasks.lock().await.first_entry().unwrap().remove();
-		assert_eq!(serde_json::to_string(&raw_task.error_info.unwrap()).unwrap(), "{\"error\":\"Task panicked with: Oh no!\"}");
+		assert_eq!(task.scheduled_at.0 - clock.now().trunc_subsecs(3), chrono::Duration::minutes(10));
 	}
 
 	/// This test will make sure that the worker pool will only stop after all workers are done.
@@ -657,6 +1382,7 @@ mod tests {
 		MemoryTaskStore::default()
 	}
 
+	#[cfg(feature = "async_postgres")]
 	#[tokio::test]
 	#[ignore]
 	async fn test_worker_pool_with_pg_store() {
@@ -672,10 +1398,10 @@ mod tests {
 		join_handle.await.unwrap();
 	}
 
+	#[cfg(feature = "async_postgres")]
 	async fn pg_task_store() -> Result<PgTaskStore, String> {
 		let url = option_env!("DATABASE_URL").ok_or_else(|| "DATABASE_URL not set".to_string())?;
-		let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new(url);
-		let pool = Pool::builder().max_size(1).min_idle(Some(1)).build(manager).await.unwrap();
+		let pool = sqlx::postgres::PgPoolOptions::new().max_connections(1).connect(url).await.map_err(|e| e.to_string())?;
 
 		Ok(PgTaskStore::new(pool))
 	}